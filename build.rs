@@ -1,4 +1,7 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::configure().compile(&["proto/registry.proto"], &["."])?;
+    tonic_build::configure().compile(
+        &["proto/registry.proto", "proto/error_details.proto"],
+        &["."],
+    )?;
     Ok(())
 }
@@ -0,0 +1,207 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::registry::{
+    ChannelStrip, EntityKind, Looper, MixerInput, MixerOutput, OutputStage, Plugin, Scene,
+};
+
+/// Number of changes kept so a watcher that reconnects can catch up.
+const CHANGE_HISTORY_CAPACITY: usize = 1024;
+
+/// Number of changes buffered for a live watcher before it counts as lagging.
+const WATCHER_BUFFER: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeType {
+    Created,
+    Updated,
+    Deleted,
+}
+
+/// The state of an entity after a change, or its last state if it was deleted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Entity {
+    Input(MixerInput),
+    Output(MixerOutput),
+    Plugin(Plugin),
+    ChannelStrip(ChannelStrip),
+    Looper(Looper),
+    OutputStage(OutputStage),
+    Scene(Scene),
+}
+
+impl Entity {
+    pub fn kind(&self) -> EntityKind {
+        match self {
+            Entity::Input(_) => EntityKind::Input,
+            Entity::Output(_) => EntityKind::Output,
+            Entity::Plugin(_) => EntityKind::Plugin,
+            Entity::ChannelStrip(_) => EntityKind::ChannelStrip,
+            Entity::Looper(_) => EntityKind::Looper,
+            Entity::OutputStage(_) => EntityKind::OutputStage,
+            Entity::Scene(_) => EntityKind::Scene,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        match self {
+            Entity::Input(input) => input.id,
+            Entity::Output(output) => output.id,
+            Entity::Plugin(plugin) => plugin.id,
+            Entity::ChannelStrip(channel_strip) => channel_strip.id,
+            Entity::Looper(looper) => looper.id,
+            Entity::OutputStage(output_stage) => output_stage.id,
+            Entity::Scene(scene) => scene.id,
+        }
+    }
+
+    pub fn revision(&self) -> u64 {
+        match self {
+            Entity::Input(input) => input.revision,
+            Entity::Output(output) => output.revision,
+            Entity::Plugin(plugin) => plugin.revision,
+            Entity::ChannelStrip(channel_strip) => channel_strip.revision,
+            Entity::Looper(looper) => looper.revision,
+            Entity::OutputStage(output_stage) => output_stage.revision,
+            Entity::Scene(scene) => scene.revision,
+        }
+    }
+
+    pub fn set_revision(&mut self, revision: u64) {
+        match self {
+            Entity::Input(input) => input.revision = revision,
+            Entity::Output(output) => output.revision = revision,
+            Entity::Plugin(plugin) => plugin.revision = revision,
+            Entity::ChannelStrip(channel_strip) => channel_strip.revision = revision,
+            Entity::Looper(looper) => looper.revision = revision,
+            Entity::OutputStage(output_stage) => output_stage.revision = revision,
+            Entity::Scene(scene) => scene.revision = revision,
+        }
+    }
+}
+
+/// What a mutation did to one entity, with enough state to undo it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Change {
+    Created(Entity),
+    Updated { before: Entity, after: Entity },
+    Deleted(Entity),
+}
+
+impl Change {
+    pub fn change_type(&self) -> ChangeType {
+        match self {
+            Change::Created(_) => ChangeType::Created,
+            Change::Updated { .. } => ChangeType::Updated,
+            Change::Deleted(_) => ChangeType::Deleted,
+        }
+    }
+
+    /// The entity after the change, or before it for a deletion.
+    pub fn entity(&self) -> &Entity {
+        match self {
+            Change::Created(entity) | Change::Deleted(entity) => entity,
+            Change::Updated { after, .. } => after,
+        }
+    }
+
+    /// The change that takes the entity back to its previous state.
+    pub fn inverse(&self) -> Change {
+        match self {
+            Change::Created(entity) => Change::Deleted(entity.clone()),
+            Change::Updated { before, after } => Change::Updated {
+                before: after.clone(),
+                after: before.clone(),
+            },
+            Change::Deleted(entity) => Change::Created(entity.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegistryChange {
+    pub resume_token: ResumeToken,
+    pub change_type: ChangeType,
+    pub entity: Entity,
+}
+
+/// Identifies a change by the journal entry it was written in and its
+/// position within the entry. The journal outlives the server, so a token
+/// stays valid across restarts for as long as its entry is in the journal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResumeToken {
+    sequence: u64,
+    index: usize,
+}
+
+impl std::fmt::Display for ResumeToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.sequence, self.index)
+    }
+}
+
+impl std::str::FromStr for ResumeToken {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        let (sequence, index) = token.split_once('.').unwrap_or((token, ""));
+        Ok(ResumeToken {
+            sequence: sequence.parse()?,
+            index: index.parse()?,
+        })
+    }
+}
+
+/// Recent changes plus a channel that fans new ones out to watchers.
+#[derive(Debug)]
+pub struct ChangeLog {
+    history: VecDeque<RegistryChange>,
+    sender: broadcast::Sender<RegistryChange>,
+}
+
+impl Default for ChangeLog {
+    fn default() -> Self {
+        ChangeLog {
+            history: VecDeque::new(),
+            sender: broadcast::channel(WATCHER_BUFFER).0,
+        }
+    }
+}
+
+impl ChangeLog {
+    /// Records the changes of the journal entry with `sequence`.
+    pub fn record(&mut self, sequence: u64, changes: &[Change]) {
+        for (index, change) in changes.iter().enumerate() {
+            let change = RegistryChange {
+                resume_token: ResumeToken { sequence, index },
+                change_type: change.change_type(),
+                entity: change.entity().clone(),
+            };
+            if self.history.len() == CHANGE_HISTORY_CAPACITY {
+                self.history.pop_front();
+            }
+            self.history.push_back(change.clone());
+            // Nobody watching isn't an error.
+            let _ = self.sender.send(change);
+        }
+    }
+
+    /// The changes after `resume_token` followed by a receiver for new ones.
+    /// `None` if the change the token identifies is no longer, or never was,
+    /// in the history.
+    pub fn subscribe(
+        &self,
+        resume_token: Option<ResumeToken>,
+    ) -> Option<(Vec<RegistryChange>, broadcast::Receiver<RegistryChange>)> {
+        let missed = match resume_token {
+            None => Vec::new(),
+            Some(token) => {
+                let position = self.history.iter().position(|c| c.resume_token == token)?;
+                self.history.iter().skip(position + 1).cloned().collect()
+            }
+        };
+        Some((missed, self.sender.subscribe()))
+    }
+}
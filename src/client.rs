@@ -1,11 +1,24 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use pmx::{
-    input::PmxInputType, pmx_registry_client::PmxRegistryClient, ByIdRequest, EmptyRequest,
-    UpdateInputNameRequest, UpdateInputPortAssignmentsRequest,
+    input::{PmxInput, PmxInputType},
+    output::{PmxOutput, PmxOutputType},
+    pmx_registry_client::PmxRegistryClient,
+    AddInputRequest, AddOutputRequest, ByIdRequest, EmptyRequest, ListChannelStripsRequest,
+    ListInputsRequest, ListLoopersRequest, ListOutputStagesRequest, ListOutputsRequest,
+    ListPluginsRequest, MoveInputsToGroupRequest, PmxListOrder, RenameGroupRequest,
+    SaveSceneRequest, SceneByNameRequest, UpdateInputGroupRequest, UpdateInputNameRequest,
+    UpdateInputPortAssignmentsRequest, UpdateOutputNameRequest, UpdateOutputPortAssignmentsRequest,
+    UpdateOutputTypeRequest, WatchRegistryRequest,
 };
 use std::result::Result;
+use template::{RegistryTemplate, TemplateInput, TemplateOutput, TemplateOutputType};
+use tokio::io::AsyncWriteExt;
 use tonic::Request;
 
+#[cfg(test)]
+mod client_tests;
+mod template;
+
 #[derive(Parser)]
 #[command(version, about, long_about=None)]
 struct Arguments {
@@ -15,7 +28,16 @@ struct Arguments {
 
 #[derive(Subcommand)]
 enum Commands {
-    ListInputs {},
+    ListInputs {
+        #[arg(short, long)]
+        group_channel_strip_name: Option<String>,
+        #[arg(short, long, value_enum)]
+        port_state: Option<PortState>,
+        #[arg(short, long)]
+        name_contains: Option<String>,
+        #[command(flatten)]
+        page: PageArguments,
+    },
     GetInput {
         #[arg(short, long)]
         id: u32,
@@ -25,12 +47,24 @@ enum Commands {
         id: u32,
         #[arg(short, long)]
         name: String,
+        #[arg(short, long)]
+        expected_revision: Option<u64>,
+    },
+    UpdateInputGroup {
+        #[arg(short, long)]
+        id: u32,
+        #[arg(short, long)]
+        group_channel_strip_name: String,
+        #[arg(short, long)]
+        expected_revision: Option<u64>,
     },
     AssignMonoPort {
         #[arg(short, long)]
         id: u32,
         #[arg(short, long)]
         path: String,
+        #[arg(short, long)]
+        expected_revision: Option<u64>,
     },
     AssignStereoPort {
         #[arg(short, long)]
@@ -39,16 +73,208 @@ enum Commands {
         left_path: String,
         #[arg(short, long)]
         right_path: String,
+        #[arg(short, long)]
+        expected_revision: Option<u64>,
     },
     RemovePort {
         #[arg(short, long)]
         id: u32,
+        #[arg(short, long)]
+        expected_revision: Option<u64>,
+    },
+    AddInput {
+        #[arg(short, long)]
+        name: String,
+        #[arg(short, long)]
+        group_channel_strip_name: String,
+    },
+    RemoveInput {
+        #[arg(short, long)]
+        id: u32,
+    },
+    ListPlugins {
+        #[arg(short = 'u', long)]
+        plugin_uri: Option<String>,
+        #[command(flatten)]
+        page: PageArguments,
+    },
+    ListChannelStrips {
+        #[command(flatten)]
+        page: PageArguments,
+    },
+    ListLoopers {
+        #[command(flatten)]
+        page: PageArguments,
+    },
+    ListOutputs {
+        #[arg(short, long, value_enum)]
+        output_type: Option<OutputType>,
+        #[command(flatten)]
+        page: PageArguments,
+    },
+    GetOutput {
+        #[arg(short, long)]
+        id: u32,
+    },
+    AddOutput {
+        #[arg(short, long)]
+        name: String,
+        #[arg(short, long, value_enum)]
+        output_type: OutputType,
+    },
+    UpdateOutputName {
+        #[arg(short, long)]
+        id: u32,
+        #[arg(short, long)]
+        name: String,
+        #[arg(short, long)]
+        expected_revision: Option<u64>,
+    },
+    UpdateOutputType {
+        #[arg(short, long)]
+        id: u32,
+        #[arg(short, long, value_enum)]
+        output_type: OutputType,
+        #[arg(short, long)]
+        expected_revision: Option<u64>,
+    },
+    RemoveOutput {
+        #[arg(short, long)]
+        id: u32,
+    },
+    AssignOutputMonoPort {
+        #[arg(short, long)]
+        id: u32,
+        #[arg(short, long)]
+        path: String,
+        #[arg(short, long)]
+        expected_revision: Option<u64>,
+    },
+    AssignOutputStereoPort {
+        #[arg(short, long)]
+        id: u32,
+        #[arg(short, long)]
+        left_path: String,
+        #[arg(short, long)]
+        right_path: String,
+        #[arg(short, long)]
+        expected_revision: Option<u64>,
+    },
+    RemoveOutputPort {
+        #[arg(short, long)]
+        id: u32,
+        #[arg(short, long)]
+        expected_revision: Option<u64>,
     },
-    ListPlugins {},
-    ListChannelStrips {},
-    ListLoopers {},
-    ListOutputs {},
-    ListOutputStages {},
+    ListOutputStages {
+        #[command(flatten)]
+        page: PageArguments,
+    },
+    Watch {
+        #[arg(short, long)]
+        resume_token: Option<String>,
+    },
+    SaveScene {
+        #[arg(short, long)]
+        name: String,
+    },
+    ListScenes {},
+    RecallScene {
+        #[arg(short, long)]
+        name: String,
+    },
+    DeleteScene {
+        #[arg(short, long)]
+        name: String,
+    },
+    DiffScene {
+        #[arg(short, long)]
+        name: String,
+    },
+    ListGroups {},
+    RenameGroup {
+        #[arg(short, long)]
+        name: String,
+        #[arg(short = 'N', long)]
+        new_name: String,
+    },
+    MoveInputsToGroup {
+        #[arg(short, long, num_args = 1.., required = true)]
+        input_ids: Vec<u32>,
+        #[arg(short, long)]
+        group: String,
+    },
+    Undo {},
+    Redo {},
+    PersistenceStatus {},
+    Init {
+        #[arg(short, long)]
+        template: String,
+        #[arg(long)]
+        from_server: bool,
+    },
+}
+
+// Ordering and paging of the list commands. Without a page size everything
+// is listed. Not a doc comment, clap would make it the help text of every
+// command it's flattened into.
+#[derive(Args)]
+struct PageArguments {
+    #[arg(long, value_enum, default_value = "id")]
+    order_by: ListOrder,
+    #[arg(long)]
+    descending: bool,
+    #[arg(long, default_value_t = 0)]
+    page_size: u32,
+    #[arg(long, default_value = "")]
+    page_token: String,
+}
+
+#[derive(Clone, ValueEnum)]
+enum ListOrder {
+    Id,
+    Name,
+}
+
+impl From<ListOrder> for PmxListOrder {
+    fn from(order: ListOrder) -> Self {
+        match order {
+            ListOrder::Id => PmxListOrder::ById,
+            ListOrder::Name => PmxListOrder::ByName,
+        }
+    }
+}
+
+#[derive(Clone, ValueEnum)]
+enum PortState {
+    None,
+    Mono,
+    Stereo,
+}
+
+impl From<PortState> for PmxInputType {
+    fn from(port_state: PortState) -> Self {
+        match port_state {
+            PortState::None => PmxInputType::None,
+            PortState::Mono => PmxInputType::MonoInput,
+            PortState::Stereo => PmxInputType::StereoInput,
+        }
+    }
+}
+
+#[derive(Clone, ValueEnum)]
+enum OutputType {
+    Main,
+    Cue,
+}
+
+impl From<OutputType> for PmxOutputType {
+    fn from(output_type: OutputType) -> Self {
+        match output_type {
+            OutputType::Main => PmxOutputType::Main,
+            OutputType::Cue => PmxOutputType::Cue,
+        }
+    }
 }
 
 pub mod pmx {
@@ -77,6 +303,47 @@ pub mod pmx {
     pub mod output_stage {
         tonic::include_proto!("pmx.output_stage");
     }
+
+    pub mod scene {
+        tonic::include_proto!("pmx.scene");
+    }
+
+    pub mod group {
+        tonic::include_proto!("pmx.group");
+    }
+}
+
+/// Prints the warnings the server attached to a request that succeeded.
+fn print_warnings<T>(response: &tonic::Response<T>) {
+    for warning in response.metadata().get_all_bin("x-pmx-warning-bin") {
+        if let Ok(warning) = warning.to_bytes() {
+            eprintln!("warning: {}", String::from_utf8_lossy(&warning));
+        }
+    }
+}
+
+/// A template seeding the inputs and outputs the server has now, without
+/// their ids and port assignments.
+fn template_from_registry(inputs: Vec<PmxInput>, outputs: Vec<PmxOutput>) -> RegistryTemplate {
+    RegistryTemplate {
+        inputs: inputs
+            .into_iter()
+            .map(|i| TemplateInput {
+                name: i.name,
+                group_channel_strip_name: i.group_channel_strip_name,
+            })
+            .collect(),
+        outputs: outputs
+            .into_iter()
+            .map(|o| TemplateOutput {
+                output_type: match o.output_type() {
+                    PmxOutputType::Cue => TemplateOutputType::Cue,
+                    PmxOutputType::Main => TemplateOutputType::Main,
+                },
+                name: o.name,
+            })
+            .collect(),
+    }
 }
 
 #[tokio::main]
@@ -85,21 +352,44 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     if let Some(command) = cli_arguments.command {
         match command {
-            Commands::ListOutputStages {} => {
+            Commands::ListOutputStages { page } => {
                 let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
-                let request = Request::new(EmptyRequest {});
+                let request = Request::new(ListOutputStagesRequest {
+                    order_by: PmxListOrder::from(page.order_by) as i32,
+                    descending: page.descending,
+                    page_size: page.page_size,
+                    page_token: page.page_token,
+                });
                 let response = client.list_output_stages(request).await?;
                 println!("{response:#?}");
             }
-            Commands::ListChannelStrips {} => {
+            Commands::ListChannelStrips { page } => {
                 let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
-                let request = Request::new(EmptyRequest {});
+                let request = Request::new(ListChannelStripsRequest {
+                    order_by: PmxListOrder::from(page.order_by) as i32,
+                    descending: page.descending,
+                    page_size: page.page_size,
+                    page_token: page.page_token,
+                });
                 let response = client.list_channel_strips(request).await?;
                 println!("{response:#?}");
             }
-            Commands::ListInputs {} => {
+            Commands::ListInputs {
+                group_channel_strip_name,
+                port_state,
+                name_contains,
+                page,
+            } => {
                 let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
-                let request = Request::new(EmptyRequest {});
+                let request = Request::new(ListInputsRequest {
+                    group_channel_strip_name,
+                    port_state: port_state.map(|state| PmxInputType::from(state) as i32),
+                    name_contains,
+                    order_by: PmxListOrder::from(page.order_by) as i32,
+                    descending: page.descending,
+                    page_size: page.page_size,
+                    page_token: page.page_token,
+                });
                 let response = client.list_inputs(request).await?;
                 println!("{response:#?}");
             }
@@ -109,30 +399,79 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 let response = client.get_input(request).await?;
                 println!("{response:#?}");
             }
-            Commands::UpdateInputName { name, id } => {
+            Commands::UpdateInputName {
+                name,
+                id,
+                expected_revision,
+            } => {
                 let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
-                let request = Request::new(UpdateInputNameRequest { name, id });
+                let request = Request::new(UpdateInputNameRequest {
+                    name,
+                    id,
+                    expected_revision,
+                });
                 let response = client.update_input_name(request).await?;
                 println!("{response:#?}");
             }
-            Commands::RemovePort { id } => {
+            Commands::UpdateInputGroup {
+                id,
+                group_channel_strip_name,
+                expected_revision,
+            } => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let request = Request::new(UpdateInputGroupRequest {
+                    id,
+                    group_channel_strip_name,
+                    expected_revision,
+                });
+                let response = client.update_input_group(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::RemovePort {
+                id,
+                expected_revision,
+            } => {
                 let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
                 let request = Request::new(UpdateInputPortAssignmentsRequest {
                     id,
                     input_type: PmxInputType::None as i32,
                     left_port_path: None,
                     right_port_path: None,
+                    expected_revision,
                 });
                 let response = client.update_input_port_assignments(request).await?;
                 println!("{response:#?}");
             }
-            Commands::AssignMonoPort { id, path } => {
+            Commands::AddInput {
+                name,
+                group_channel_strip_name,
+            } => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let request = Request::new(AddInputRequest {
+                    name,
+                    group_channel_strip_name,
+                });
+                let response = client.add_input(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::RemoveInput { id } => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let request = Request::new(ByIdRequest { id });
+                let response = client.remove_input(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::AssignMonoPort {
+                id,
+                path,
+                expected_revision,
+            } => {
                 let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
                 let request = Request::new(UpdateInputPortAssignmentsRequest {
                     id,
                     input_type: PmxInputType::MonoInput as i32,
                     left_port_path: Some(path),
                     right_port_path: None,
+                    expected_revision,
                 });
                 let response = client.update_input_port_assignments(request).await?;
                 println!("{response:#?}");
@@ -141,6 +480,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 id,
                 left_path,
                 right_path,
+                expected_revision,
             } => {
                 let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
                 let request = Request::new(UpdateInputPortAssignmentsRequest {
@@ -148,28 +488,244 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     input_type: PmxInputType::StereoInput as i32,
                     left_port_path: Some(left_path),
                     right_port_path: Some(right_path),
+                    expected_revision,
                 });
                 let response = client.update_input_port_assignments(request).await?;
+                print_warnings(&response);
                 println!("{response:#?}");
             }
-            Commands::ListPlugins {} => {
+            Commands::ListPlugins { plugin_uri, page } => {
                 let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
-                let request = Request::new(EmptyRequest {});
+                let request = Request::new(ListPluginsRequest {
+                    plugin_uri,
+                    order_by: PmxListOrder::from(page.order_by) as i32,
+                    descending: page.descending,
+                    page_size: page.page_size,
+                    page_token: page.page_token,
+                });
                 let response = client.list_plugins(request).await?;
                 println!("{response:#?}");
             }
-            Commands::ListLoopers {} => {
+            Commands::ListLoopers { page } => {
                 let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
-                let request = Request::new(EmptyRequest {});
+                let request = Request::new(ListLoopersRequest {
+                    order_by: PmxListOrder::from(page.order_by) as i32,
+                    descending: page.descending,
+                    page_size: page.page_size,
+                    page_token: page.page_token,
+                });
                 let response = client.list_loopers(request).await?;
                 println!("{response:#?}");
             }
-            Commands::ListOutputs {} => {
+            Commands::ListOutputs { output_type, page } => {
                 let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
-                let request = Request::new(EmptyRequest {});
+                let request = Request::new(ListOutputsRequest {
+                    output_type: output_type
+                        .map(|output_type| PmxOutputType::from(output_type) as i32),
+                    order_by: PmxListOrder::from(page.order_by) as i32,
+                    descending: page.descending,
+                    page_size: page.page_size,
+                    page_token: page.page_token,
+                });
                 let response = client.list_outputs(request).await?;
                 println!("{response:#?}");
             }
+            Commands::GetOutput { id } => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let request = Request::new(ByIdRequest { id });
+                let response = client.get_output(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::AddOutput { name, output_type } => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let request = Request::new(AddOutputRequest {
+                    name,
+                    output_type: PmxOutputType::from(output_type) as i32,
+                });
+                let response = client.add_output(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::UpdateOutputName {
+                id,
+                name,
+                expected_revision,
+            } => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let request = Request::new(UpdateOutputNameRequest {
+                    id,
+                    name,
+                    expected_revision,
+                });
+                let response = client.update_output_name(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::UpdateOutputType {
+                id,
+                output_type,
+                expected_revision,
+            } => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let request = Request::new(UpdateOutputTypeRequest {
+                    id,
+                    output_type: PmxOutputType::from(output_type) as i32,
+                    expected_revision,
+                });
+                let response = client.update_output_type(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::RemoveOutput { id } => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let request = Request::new(ByIdRequest { id });
+                let response = client.remove_output(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::AssignOutputMonoPort {
+                id,
+                path,
+                expected_revision,
+            } => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let request = Request::new(UpdateOutputPortAssignmentsRequest {
+                    id,
+                    left_port_path: Some(path),
+                    right_port_path: None,
+                    expected_revision,
+                });
+                let response = client.update_output_port_assignments(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::AssignOutputStereoPort {
+                id,
+                left_path,
+                right_path,
+                expected_revision,
+            } => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let request = Request::new(UpdateOutputPortAssignmentsRequest {
+                    id,
+                    left_port_path: Some(left_path),
+                    right_port_path: Some(right_path),
+                    expected_revision,
+                });
+                let response = client.update_output_port_assignments(request).await?;
+                print_warnings(&response);
+                println!("{response:#?}");
+            }
+            Commands::RemoveOutputPort {
+                id,
+                expected_revision,
+            } => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let request = Request::new(UpdateOutputPortAssignmentsRequest {
+                    id,
+                    left_port_path: None,
+                    right_port_path: None,
+                    expected_revision,
+                });
+                let response = client.update_output_port_assignments(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::Watch { resume_token } => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let request = Request::new(WatchRegistryRequest { resume_token });
+                let mut changes = client.watch_registry(request).await?.into_inner();
+                while let Some(change) = changes.message().await? {
+                    println!("{change:#?}");
+                }
+            }
+            Commands::SaveScene { name } => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let request = Request::new(SaveSceneRequest { name });
+                let response = client.save_scene(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::ListScenes {} => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let response = client.list_scenes(Request::new(EmptyRequest {})).await?;
+                println!("{response:#?}");
+            }
+            Commands::RecallScene { name } => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let request = Request::new(SceneByNameRequest { name });
+                let response = client.recall_scene(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::DeleteScene { name } => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let request = Request::new(SceneByNameRequest { name });
+                let response = client.delete_scene(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::DiffScene { name } => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let request = Request::new(SceneByNameRequest { name });
+                let response = client.diff_scene(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::ListGroups {} => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let request = Request::new(EmptyRequest {});
+                let response = client.list_groups(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::RenameGroup { name, new_name } => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let request = Request::new(RenameGroupRequest { name, new_name });
+                let response = client.rename_group(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::MoveInputsToGroup { input_ids, group } => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let request = Request::new(MoveInputsToGroupRequest { input_ids, group });
+                let response = client.move_inputs_to_group(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::Undo {} => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let response = client.undo(Request::new(EmptyRequest {})).await?;
+                println!("{response:#?}");
+            }
+            Commands::Redo {} => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let response = client.redo(Request::new(EmptyRequest {})).await?;
+                println!("{response:#?}");
+            }
+            Commands::PersistenceStatus {} => {
+                let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                let response = client
+                    .get_persistence_status(Request::new(EmptyRequest {}))
+                    .await?;
+                println!("{response:#?}");
+            }
+            Commands::Init {
+                template,
+                from_server,
+            } => {
+                let registry_template = if from_server {
+                    let mut client = PmxRegistryClient::connect("http://127.0.0.1:50001").await?;
+                    let inputs = client
+                        .list_inputs(Request::new(ListInputsRequest::default()))
+                        .await?
+                        .into_inner()
+                        .inputs;
+                    let outputs = client
+                        .list_outputs(Request::new(ListOutputsRequest::default()))
+                        .await?
+                        .into_inner()
+                        .outputs;
+                    template_from_registry(inputs, outputs)
+                } else {
+                    RegistryTemplate::builtin()
+                };
+                let data = serde_json::to_string_pretty(&registry_template)?;
+                let mut file = tokio::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&template)
+                    .await?;
+                file.write_all(data.as_bytes()).await?;
+                println!("Wrote template to {template}");
+            }
         }
     }
 
@@ -0,0 +1,54 @@
+//! Covers what the client builds locally instead of asking the server for.
+
+use crate::pmx::input::{PmxInput, PmxInputType};
+use crate::pmx::output::{PmxOutput, PmxOutputType};
+use crate::template::TemplateOutputType;
+use crate::template_from_registry;
+
+#[test]
+fn template_from_the_server_keeps_names_groups_and_output_types_in_order() {
+    let input = |id, name: &str, group: &str| PmxInput {
+        id,
+        name: String::from(name),
+        input_type: PmxInputType::MonoInput as i32,
+        left_port_path: Some(String::from("synth:out")),
+        group_channel_strip_name: String::from(group),
+        ..PmxInput::default()
+    };
+    let output = |id, name: &str, output_type: PmxOutputType| PmxOutput {
+        id,
+        name: String::from(name),
+        output_type: output_type as i32,
+        ..PmxOutput::default()
+    };
+
+    let template = template_from_registry(
+        vec![input(7, "SE02", "Bass"), input(3, "DSMPL", "Drums")],
+        vec![
+            output(2, "Booth", PmxOutputType::Cue),
+            output(5, "Main", PmxOutputType::Main),
+        ],
+    );
+
+    let inputs: Vec<_> = template
+        .inputs
+        .iter()
+        .map(|input| (input.name.as_str(), input.group_channel_strip_name.as_str()))
+        .collect();
+    assert_eq!(inputs, [("SE02", "Bass"), ("DSMPL", "Drums")]);
+    let outputs: Vec<_> = template
+        .outputs
+        .iter()
+        .map(|output| (output.name.as_str(), &output.output_type))
+        .collect();
+    assert!(
+        matches!(
+            outputs[..],
+            [
+                ("Booth", TemplateOutputType::Cue),
+                ("Main", TemplateOutputType::Main)
+            ]
+        ),
+        "{outputs:?}"
+    );
+}
@@ -1,111 +1,285 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
 use tokio::{fs::File, io::AsyncReadExt};
 
-use crate::registry::{MixerInput, MixerOutput, MixerOutputType, PipewirePorts};
-
-pub async fn read_outputs_file(path: &str) -> Vec<MixerOutput> {
-    match File::open(&path).await {
-        Err(_why) => {
-            vec![
-                MixerOutput {
-                    name: String::from("Main"),
-                    pipewire_ports: PipewirePorts::None {},
-                    id: 1,
-                    output_type: MixerOutputType::Main,
-                },
-                MixerOutput {
-                    name: String::from("Cue"),
-                    pipewire_ports: PipewirePorts::None {},
-                    id: 2,
-                    output_type: MixerOutputType::Cue,
-                },
-                MixerOutput {
-                    name: String::from("Main 2"),
-                    pipewire_ports: PipewirePorts::None {},
-                    id: 3,
-                    output_type: MixerOutputType::Main,
-                },
-            ]
+use crate::file_writer::{backup_path, BACKUP_GENERATIONS};
+use crate::registry::{MixerInput, MixerOutput, MixerOutputType, NextIds, PipewirePorts};
+use crate::snapshot::{self, MigrationError, RegistrySnapshot, CURRENT_SCHEMA_VERSION};
+use crate::template::{RegistryTemplate, TemplateOutputType};
+
+/// Paths of the files written before the registry switched to a single
+/// snapshot document. They are only read when migrating a version 0 install.
+pub struct LegacyDataFiles<'a> {
+    pub outputs_file: &'a str,
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    NotFound {
+        path: String,
+    },
+    PermissionDenied {
+        path: String,
+    },
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    Parse {
+        path: String,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    Migration {
+        path: String,
+        source: MigrationError,
+    },
+}
+
+impl ReadError {
+    fn from_io(path: &str, source: std::io::Error) -> Self {
+        let path = String::from(path);
+        match source.kind() {
+            ErrorKind::NotFound => ReadError::NotFound { path },
+            ErrorKind::PermissionDenied => ReadError::PermissionDenied { path },
+            _ => ReadError::Io { path, source },
         }
-        Ok(mut file) => {
-            let mut raw_string = String::new();
+    }
 
-            if let Err(why) = file.read_to_string(&mut raw_string).await {
-                panic!("couldn't read {}", why);
-            }
+    pub fn path(&self) -> &str {
+        match self {
+            ReadError::NotFound { path }
+            | ReadError::PermissionDenied { path }
+            | ReadError::Io { path, .. }
+            | ReadError::Parse { path, .. }
+            | ReadError::Migration { path, .. } => path,
+        }
+    }
+}
 
-            serde_json::from_str(&raw_string).unwrap()
+impl std::fmt::Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadError::NotFound { path } => write!(f, "{path} doesn't exist"),
+            ReadError::PermissionDenied { path } => write!(f, "permission denied reading {path}"),
+            ReadError::Io { path, source } => write!(f, "couldn't read {path}: {source}"),
+            ReadError::Parse {
+                path,
+                line,
+                column,
+                message,
+            } => write!(
+                f,
+                "couldn't parse {path} at line {line}, column {column}: {message}"
+            ),
+            ReadError::Migration { path, source } => write!(f, "couldn't load {path}: {source}"),
         }
     }
 }
 
-pub async fn read_inputs_file(path: &str) -> Vec<MixerInput> {
-    match File::open(&path).await {
-        Err(_why) => {
-            vec![
-                MixerInput {
-                    name: String::from("DSMPL"),
-                    pipewire_ports: crate::registry::PipewirePorts::None,
-                    id: 1,
-                    group_channel_strip_name: String::from("Drums"),
-                },
-                MixerInput {
-                    name: String::from("DFire"),
-                    pipewire_ports: crate::registry::PipewirePorts::None,
-                    id: 2,
-                    group_channel_strip_name: String::from("Drums"),
-                },
-                MixerInput {
-                    name: String::from("DEuro"),
-                    pipewire_ports: crate::registry::PipewirePorts::None,
-                    id: 3,
-                    group_channel_strip_name: String::from("Drums"),
-                },
-                MixerInput {
-                    name: String::from("Prophet rev2"),
-                    pipewire_ports: crate::registry::PipewirePorts::None,
-                    id: 4,
-                    group_channel_strip_name: String::from("Melody"),
-                },
-                MixerInput {
-                    name: String::from("SE02"),
-                    pipewire_ports: crate::registry::PipewirePorts::None,
-                    id: 5,
-                    group_channel_strip_name: String::from("Bass"),
-                },
-                MixerInput {
-                    name: String::from("Torso S4"),
-                    pipewire_ports: crate::registry::PipewirePorts::None,
-                    id: 6,
-                    group_channel_strip_name: String::from("Atmos"),
-                },
-                MixerInput {
-                    name: String::from("opsix"),
-                    pipewire_ports: crate::registry::PipewirePorts::None,
-                    id: 7,
-                    group_channel_strip_name: String::from("Drums"),
-                },
-                MixerInput {
-                    name: String::from("System 1m"),
-                    pipewire_ports: crate::registry::PipewirePorts::None,
-                    id: 8,
-                    group_channel_strip_name: String::from("Drums"),
-                },
-                MixerInput {
-                    name: String::from("Cobalt 8m"),
-                    pipewire_ports: crate::registry::PipewirePorts::None,
-                    id: 9,
-                    group_channel_strip_name: String::from("Drums"),
-                },
-            ]
+impl std::error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReadError::Io { source, .. } => Some(source),
+            ReadError::Migration { source, .. } => Some(source),
+            _ => None,
         }
-        Ok(mut file) => {
-            let mut raw_string = String::new();
+    }
+}
+
+/// Reads the registry snapshot, upgrading older files to the current schema.
+/// A snapshot path holding a bare `Vec` of inputs is the legacy layout and is
+/// combined with the legacy outputs file. Missing files are a
+/// fresh install and are seeded from `template`.
+pub async fn read_snapshot_file(
+    path: &str,
+    legacy: LegacyDataFiles<'_>,
+    template: &RegistryTemplate,
+) -> Result<RegistrySnapshot, ReadError> {
+    let (source_path, document) = match read_snapshot_document(path).await? {
+        Some((source_path, Value::Array(inputs))) => (
+            source_path,
+            read_legacy_document(Value::Array(inputs), legacy, template).await?,
+        ),
+        Some((source_path, document)) => (source_path, document),
+        None => (
+            String::from(path),
+            read_legacy_document(
+                serde_json::to_value(template_inputs(template)).unwrap(),
+                legacy,
+                template,
+            )
+            .await?,
+        ),
+    };
+
+    snapshot::migrate(document).map_err(|source| ReadError::Migration {
+        path: source_path,
+        source,
+    })
+}
 
-            if let Err(why) = file.read_to_string(&mut raw_string).await {
-                panic!("couldn't read {}", why);
+/// A template is selected either by path or by name, where a name refers to
+/// `<name>.json` in `templates_directory`.
+pub fn resolve_template_path(name_or_path: &str, templates_directory: &Path) -> PathBuf {
+    let path = Path::new(name_or_path);
+    if path.components().count() > 1 || path.extension().is_some() {
+        path.to_path_buf()
+    } else {
+        templates_directory.join(format!("{name_or_path}.json"))
+    }
+}
+
+pub async fn read_template_file(path: &Path) -> Result<RegistryTemplate, ReadError> {
+    read_json_file(&path.to_string_lossy()).await
+}
+
+pub fn snapshot_from_template(template: &RegistryTemplate) -> RegistrySnapshot {
+    let inputs = template_inputs(template);
+    let outputs = template_outputs(template);
+    RegistrySnapshot {
+        schema_version: CURRENT_SCHEMA_VERSION,
+        next_ids: NextIds {
+            input: inputs.len() as u32 + 1,
+            output: outputs.len() as u32 + 1,
+            ..NextIds::default()
+        },
+        inputs,
+        outputs,
+        plugins: Vec::new(),
+        channel_strips: Vec::new(),
+        loopers: Vec::new(),
+        output_stages: Vec::new(),
+        idempotency_tokens: Vec::new(),
+        scenes: Vec::new(),
+        journal_sequence: 0,
+    }
+}
+
+fn template_inputs(template: &RegistryTemplate) -> Vec<MixerInput> {
+    template
+        .inputs
+        .iter()
+        .zip(1..)
+        .map(|(input, id)| {
+            MixerInput::new(
+                &input.name,
+                PipewirePorts::None,
+                id,
+                &input.group_channel_strip_name,
+            )
+        })
+        .collect()
+}
+
+fn template_outputs(template: &RegistryTemplate) -> Vec<MixerOutput> {
+    template
+        .outputs
+        .iter()
+        .zip(1..)
+        .map(|(output, id)| {
+            MixerOutput::new(
+                &output.name,
+                PipewirePorts::None,
+                id,
+                match output.output_type {
+                    TemplateOutputType::Cue => MixerOutputType::Cue,
+                    TemplateOutputType::Main => MixerOutputType::Main,
+                },
+            )
+        })
+        .collect()
+}
+
+async fn read_legacy_document(
+    inputs: Value,
+    legacy: LegacyDataFiles<'_>,
+    template: &RegistryTemplate,
+) -> Result<Value, ReadError> {
+    let mut document = Map::new();
+    document.insert(String::from("inputs"), inputs);
+    document.insert(
+        String::from("outputs"),
+        match read_optional_json_file(legacy.outputs_file).await? {
+            Some(outputs) => outputs,
+            None => serde_json::to_value(template_outputs(template)).unwrap(),
+        },
+    );
+    Ok(Value::Object(document))
+}
+
+/// Reads the newest snapshot that parses, falling back to the backup
+/// generations when the data file is truncated or corrupt. Returns the error
+/// of the newest file when no generation can be read.
+async fn read_snapshot_document(path: &str) -> Result<Option<(String, Value)>, ReadError> {
+    let mut first_error = None;
+    for candidate in std::iter::once(String::from(path))
+        .chain((1..=BACKUP_GENERATIONS).map(|generation| backup_path(path, generation)))
+    {
+        match read_optional_json_file(&candidate).await {
+            Ok(None) => continue,
+            Ok(Some(document)) => {
+                if let Some(why) = &first_error {
+                    log::warn!("{why}, using backup {candidate}");
+                }
+                return Ok(Some((candidate, document)));
+            }
+            Err(why) => {
+                first_error.get_or_insert(why);
             }
+        }
+    }
+
+    match first_error {
+        Some(why) => Err(why),
+        None => Ok(None),
+    }
+}
 
-            serde_json::from_str(&raw_string).unwrap()
+/// The lowest journal sequence among the readable generations of the
+/// snapshot at `path`. Startup may fall back to any of them, so the journal
+/// has to keep the entries after it. `None` if no generation can be read.
+pub async fn earliest_journal_sequence(path: &str) -> Option<u64> {
+    let mut earliest = None;
+    for candidate in std::iter::once(String::from(path))
+        .chain((1..=BACKUP_GENERATIONS).map(|generation| backup_path(path, generation)))
+    {
+        if let Ok(Some(document)) = read_optional_json_file(&candidate).await {
+            let sequence = snapshot::journal_sequence(&document);
+            earliest = Some(earliest.map_or(sequence, |earliest: u64| earliest.min(sequence)));
         }
     }
+    earliest
+}
+
+async fn read_optional_json_file(path: &str) -> Result<Option<Value>, ReadError> {
+    match read_json_file(path).await {
+        Ok(document) => Ok(Some(document)),
+        Err(ReadError::NotFound { .. }) => Ok(None),
+        Err(why) => Err(why),
+    }
+}
+
+async fn read_json_file<T: DeserializeOwned>(path: &str) -> Result<T, ReadError> {
+    let mut file = File::open(&path)
+        .await
+        .map_err(|why| ReadError::from_io(path, why))?;
+    let mut raw_string = String::new();
+    file.read_to_string(&mut raw_string)
+        .await
+        .map_err(|why| ReadError::from_io(path, why))?;
+    serde_json::from_str(&raw_string).map_err(|why| {
+        let position = format!(" at line {} column {}", why.line(), why.column());
+        let message = why.to_string();
+        ReadError::Parse {
+            path: String::from(path),
+            line: why.line(),
+            column: why.column(),
+            message: String::from(message.strip_suffix(&position).unwrap_or(&message)),
+        }
+    })
 }
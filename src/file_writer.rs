@@ -1,31 +1,62 @@
+use std::path::Path;
+
 use tokio::{fs::File, io::AsyncWriteExt};
 
-use crate::registry::{MixerInput, MixerOutput};
-
-pub async fn run_input_file_writer(
-    mut receiver: tokio::sync::mpsc::UnboundedReceiver<Vec<MixerInput>>,
-    path: &String,
-) {
-    loop {
-        let data = receiver.recv().await;
-        if receiver.is_empty() {
-            let data = serde_json::to_string_pretty(&data.unwrap()).unwrap();
-            let mut file = File::create(path).await.unwrap();
-            file.write_all(data.as_bytes()).await.unwrap();
+use crate::snapshot::RegistrySnapshot;
+
+/// Number of previous snapshots kept next to the data file as `<path>.1`
+/// (newest) to `<path>.N` (oldest).
+pub const BACKUP_GENERATIONS: usize = 3;
+
+pub fn backup_path(path: &str, generation: usize) -> String {
+    format!("{path}.{generation}")
+}
+
+fn temporary_path(path: &str) -> String {
+    format!("{path}.tmp")
+}
+
+/// Writes the snapshot next to `path`, syncs it to disk and renames it over
+/// `path`, so a crash at any point leaves either the old or the new file.
+pub async fn write_snapshot_file(path: &str, snapshot: &RegistrySnapshot) -> std::io::Result<()> {
+    let data = serde_json::to_string_pretty(snapshot)?;
+    let temporary_path = temporary_path(path);
+    let mut file = File::create(&temporary_path).await?;
+    file.write_all(data.as_bytes()).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    rotate_backups(path).await?;
+    tokio::fs::rename(&temporary_path, path).await?;
+    sync_parent_directory(path).await
+}
+
+/// Shifts every backup one generation down and keeps the current file as the
+/// newest backup. The current file stays in place until it is replaced.
+async fn rotate_backups(path: &str) -> std::io::Result<()> {
+    if !tokio::fs::try_exists(path).await? {
+        return Ok(());
+    }
+
+    for generation in (1..BACKUP_GENERATIONS).rev() {
+        let from = backup_path(path, generation);
+        if tokio::fs::try_exists(&from).await? {
+            tokio::fs::rename(&from, backup_path(path, generation + 1)).await?;
         }
     }
+
+    let newest_backup = backup_path(path, 1);
+    if tokio::fs::hard_link(path, &newest_backup).await.is_err() {
+        tokio::fs::copy(path, &newest_backup).await?;
+    }
+    Ok(())
 }
 
-pub async fn run_output_file_writer(
-    mut receiver: tokio::sync::mpsc::UnboundedReceiver<Vec<MixerOutput>>,
-    path: &String,
-) {
-    loop {
-        let data = receiver.recv().await;
-        if receiver.is_empty() {
-            let data = serde_json::to_string_pretty(&data.unwrap()).unwrap();
-            let mut file = File::create(path).await.unwrap();
-            file.write_all(data.as_bytes()).await.unwrap();
+async fn sync_parent_directory(path: &str) -> std::io::Result<()> {
+    match Path::new(path).parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => {
+            File::open(directory).await?.sync_all().await
         }
+        _ => Ok(()),
     }
 }
@@ -0,0 +1,344 @@
+//! Append-only record of every mutation, one JSON object per line next to the
+//! data file. An entry is synced before its mutation is acknowledged, so after
+//! a crash the registry is the last snapshot plus the entries after it. Once
+//! the snapshot and all its backups cover enough entries they are dropped from
+//! the file; the ones left double as an audit trail of who changed what. The
+//! file is only touched on the blocking thread pool, so a slow disk doesn't
+//! stall the runtime.
+
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::changes::Change;
+use crate::registry::IdempotencyToken;
+
+pub fn journal_path(data_file: &str) -> String {
+    format!("{data_file}.journal")
+}
+
+/// The RPC a mutation came from and who called it.
+#[derive(Debug, Clone)]
+pub struct Operation {
+    pub name: String,
+    pub caller: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub sequence: u64,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub caller: String,
+    pub operation: String,
+    pub changes: Vec<Change>,
+    /// Tokens of the register calls that made the changes.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub idempotency_tokens: Vec<IdempotencyToken>,
+}
+
+#[derive(Debug)]
+pub enum JournalError {
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    /// The oldest entry comes after the one the snapshot ends with, so the
+    /// changes in between are lost.
+    Gap {
+        path: String,
+        snapshot_sequence: u64,
+        first_sequence: u64,
+    },
+    /// A line other than an incomplete last one doesn't parse or doesn't
+    /// follow the entry before it, so acknowledged entries may come after it.
+    Corrupt {
+        path: String,
+        line: usize,
+        reason: String,
+    },
+}
+
+impl JournalError {
+    pub fn path(&self) -> &str {
+        match self {
+            JournalError::Io { path, .. }
+            | JournalError::Gap { path, .. }
+            | JournalError::Corrupt { path, .. } => path,
+        }
+    }
+}
+
+impl std::fmt::Display for JournalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JournalError::Io { path, source } => write!(f, "couldn't read {path}: {source}"),
+            JournalError::Gap {
+                path,
+                snapshot_sequence,
+                first_sequence,
+            } => write!(
+                f,
+                "{path} starts at entry {first_sequence} but the snapshot ends at entry {snapshot_sequence}"
+            ),
+            JournalError::Corrupt { path, line, reason } => {
+                write!(f, "{path} is corrupt at line {line}: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for JournalError {}
+
+/// Shared by the registry, which appends, and the snapshot writer, which
+/// compacts.
+#[derive(Debug, Clone)]
+pub struct Journal {
+    file: Arc<Mutex<JournalFile>>,
+}
+
+#[derive(Debug)]
+struct JournalFile {
+    path: String,
+    /// Opened on the first append, so a read-only registry never creates or
+    /// truncates the file.
+    file: Option<File>,
+    /// Length of the complete entries. Anything after it, a line torn by a
+    /// crash, is cut off before the next append.
+    valid_length: u64,
+    first_sequence: Option<u64>,
+    next_sequence: u64,
+}
+
+impl Journal {
+    /// Reads the journal at `path` and returns its entries, including the
+    /// ones the snapshot ending at `snapshot_sequence` already covers. An
+    /// incomplete last line is what a crash during an append leaves behind
+    /// and is skipped; any other line that doesn't parse or doesn't follow
+    /// the previous entry is an error.
+    pub fn open(
+        path: &str,
+        snapshot_sequence: u64,
+    ) -> Result<(Journal, Vec<JournalEntry>), JournalError> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(why) if why.kind() == ErrorKind::NotFound => String::new(),
+            Err(source) => {
+                return Err(JournalError::Io {
+                    path: String::from(path),
+                    source,
+                })
+            }
+        };
+
+        let mut valid_length = 0;
+        let mut entries: Vec<JournalEntry> = Vec::new();
+        for (number, line) in contents.split_inclusive('\n').enumerate() {
+            if !line.ends_with('\n') {
+                log::warn!("ignoring the incomplete last line of {path}");
+                break;
+            }
+            let corrupt = |reason: String| JournalError::Corrupt {
+                path: String::from(path),
+                line: number + 1,
+                reason,
+            };
+            let entry = serde_json::from_str::<JournalEntry>(line)
+                .map_err(|why| corrupt(why.to_string()))?;
+            if let Some(previous) = entries.last() {
+                if entry.sequence != previous.sequence + 1 {
+                    return Err(corrupt(format!(
+                        "entry {} follows entry {}",
+                        entry.sequence, previous.sequence
+                    )));
+                }
+            }
+            valid_length += line.len() as u64;
+            entries.push(entry);
+        }
+
+        let first_sequence = entries.first().map(|entry| entry.sequence);
+        if let Some(first_sequence) = first_sequence {
+            if first_sequence > snapshot_sequence + 1 {
+                return Err(JournalError::Gap {
+                    path: String::from(path),
+                    snapshot_sequence,
+                    first_sequence,
+                });
+            }
+        }
+        let next_sequence = entries.last().map_or(snapshot_sequence, |entry| {
+            entry.sequence.max(snapshot_sequence)
+        }) + 1;
+        Ok((
+            Journal::new(JournalFile {
+                path: String::from(path),
+                file: None,
+                valid_length,
+                first_sequence,
+                next_sequence,
+            }),
+            entries,
+        ))
+    }
+
+    fn new(file: JournalFile) -> Journal {
+        Journal {
+            file: Arc::new(Mutex::new(file)),
+        }
+    }
+
+    /// A journal that isn't read, for a registry that starts read-only and so
+    /// never appends to it.
+    pub fn unread(path: &str) -> Journal {
+        Journal::new(JournalFile {
+            path: String::from(path),
+            file: None,
+            valid_length: 0,
+            first_sequence: None,
+            next_sequence: 1,
+        })
+    }
+
+    /// Appends and syncs one entry for the changes of `operation`, returning
+    /// its sequence number.
+    pub async fn append(
+        &self,
+        operation: &Operation,
+        changes: &[Change],
+        idempotency_tokens: &[IdempotencyToken],
+    ) -> std::io::Result<u64> {
+        let operation = operation.clone();
+        let changes = changes.to_vec();
+        let idempotency_tokens = idempotency_tokens.to_vec();
+        self.with_file(move |file| file.append(&operation, changes, idempotency_tokens))
+            .await
+    }
+
+    /// Drops the entries a snapshot ending at `sequence` covers, once there
+    /// are at least `threshold` of them.
+    pub async fn compact(&self, sequence: u64, threshold: u64) -> std::io::Result<()> {
+        self.with_file(move |file| {
+            // An empty journal has nothing to drop, and its file may not
+            // exist yet.
+            if file.first_sequence.is_none() {
+                return Ok(());
+            }
+            if file.covered_entries(sequence) >= threshold {
+                file.compact(sequence)
+            } else {
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    async fn with_file<T: Send + 'static>(
+        &self,
+        action: impl FnOnce(&mut JournalFile) -> std::io::Result<T> + Send + 'static,
+    ) -> std::io::Result<T> {
+        let file = self.file.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut file = file
+                .lock()
+                .map_err(|_| std::io::Error::other("an earlier journal write panicked"))?;
+            action(&mut file)
+        })
+        .await
+        .map_err(std::io::Error::other)?
+    }
+}
+
+impl JournalFile {
+    fn append(
+        &mut self,
+        operation: &Operation,
+        changes: Vec<Change>,
+        idempotency_tokens: Vec<IdempotencyToken>,
+    ) -> std::io::Result<u64> {
+        let entry = JournalEntry {
+            sequence: self.next_sequence,
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            caller: operation.caller.clone(),
+            operation: operation.name.clone(),
+            changes,
+            idempotency_tokens,
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+
+        let result = self.write_line(&line);
+        if result.is_err() {
+            // Reopening truncates whatever part of the line made it out.
+            self.file = None;
+        }
+        result?;
+        self.valid_length += line.len() as u64;
+        self.first_sequence.get_or_insert(entry.sequence);
+        self.next_sequence += 1;
+        Ok(entry.sequence)
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(false)
+                    .open(&self.path)?;
+                file.set_len(self.valid_length)?;
+                self.file.insert(file)
+            }
+        };
+        file.seek(SeekFrom::Start(self.valid_length))?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()
+    }
+
+    /// Number of entries a snapshot ending at `sequence` makes redundant.
+    fn covered_entries(&self, sequence: u64) -> u64 {
+        self.first_sequence
+            .map_or(0, |first| (sequence + 1).saturating_sub(first))
+    }
+
+    /// Drops the entries up to and including `sequence`. The rest is written
+    /// to a new file that replaces the journal, so a crash leaves one or the
+    /// other.
+    fn compact(&mut self, sequence: u64) -> std::io::Result<()> {
+        let contents = std::fs::read_to_string(&self.path)?;
+        let mut kept = String::new();
+        let mut first_sequence = None;
+        for line in contents[..self.valid_length as usize].split_inclusive('\n') {
+            let entry: JournalEntry = serde_json::from_str(line)?;
+            if entry.sequence > sequence {
+                first_sequence.get_or_insert(entry.sequence);
+                kept.push_str(line);
+            }
+        }
+
+        let temporary_path = format!("{}.tmp", self.path);
+        let mut file = File::create(&temporary_path)?;
+        file.write_all(kept.as_bytes())?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&temporary_path, &self.path)?;
+        if let Some(directory) = Path::new(&self.path).parent() {
+            if !directory.as_os_str().is_empty() {
+                File::open(directory)?.sync_all()?;
+            }
+        }
+
+        self.file = None;
+        self.valid_length = kept.len() as u64;
+        self.first_sequence = first_sequence;
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+//! Writes the registry to disk in the background. Mutations only mark the
+//! registry dirty; the writer waits until no change arrived for the debounce
+//! interval, or until the maximum delay passed since the first of them, then
+//! takes one snapshot for however many changes happened in between. Memory
+//! use doesn't depend on how fast changes arrive. Every mutation is already in
+//! the journal by then, so the snapshot only bounds how much of the journal
+//! has to be replayed. After a write the entries covered by the snapshot and
+//! by every backup of it are compacted away; startup may fall back to a
+//! backup and has to replay from there.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{oneshot, watch, RwLock};
+use tokio::time::Instant;
+
+use crate::file_reader::earliest_journal_sequence;
+use crate::file_writer::write_snapshot_file;
+use crate::registry::Registry;
+
+/// Outcome of the most recent write, shared with the registry so clients can
+/// ask whether their changes reached the disk.
+#[derive(Debug, Clone, Default)]
+struct WriteStatus {
+    written_generation: u64,
+    failed_attempts: u64,
+    last_error: Option<String>,
+}
+
+/// The registry's end: marks state dirty and reads back write failures.
+#[derive(Debug)]
+pub struct PersistenceHandle {
+    dirty: watch::Sender<u64>,
+    status: watch::Receiver<WriteStatus>,
+}
+
+/// What `PersistenceHandle::status` reports.
+#[derive(Debug, Clone)]
+pub struct PersistenceStatus {
+    pub write_pending: bool,
+    pub failed_attempts: u64,
+    pub last_error: Option<String>,
+}
+
+/// The writer's end, driven by `run`.
+#[derive(Debug)]
+pub struct SnapshotWriter {
+    dirty: watch::Receiver<u64>,
+    status: watch::Sender<WriteStatus>,
+    path: String,
+    debounce: Duration,
+    /// Upper bound on the wait, so a steady stream of changes is still written.
+    max_delay: Duration,
+    /// Journal entries a snapshot has to cover before they are dropped.
+    compact_after: u64,
+}
+
+pub fn channel(
+    path: &str,
+    debounce: Duration,
+    max_delay: Duration,
+    compact_after: u64,
+) -> (PersistenceHandle, SnapshotWriter) {
+    let (dirty_sender, dirty_receiver) = watch::channel(0);
+    let (status_sender, status_receiver) = watch::channel(WriteStatus::default());
+    (
+        PersistenceHandle {
+            dirty: dirty_sender,
+            status: status_receiver,
+        },
+        SnapshotWriter {
+            dirty: dirty_receiver,
+            status: status_sender,
+            path: String::from(path),
+            debounce,
+            max_delay,
+            compact_after,
+        },
+    )
+}
+
+impl PersistenceHandle {
+    /// Schedules a write. The change is already journaled, so a writer that
+    /// stopped or keeps failing doesn't lose it; `status` tells clients.
+    pub fn mark_dirty(&self) {
+        if self.dirty.is_closed() {
+            log::warn!("the snapshot writer has stopped, changes are only journaled");
+        }
+        self.dirty.send_modify(|generation| *generation += 1);
+    }
+
+    pub fn status(&self) -> PersistenceStatus {
+        let status = self.status.borrow();
+        PersistenceStatus {
+            write_pending: *self.dirty.borrow() != status.written_generation,
+            failed_attempts: status.failed_attempts,
+            last_error: status.last_error.clone(),
+        }
+    }
+}
+
+impl SnapshotWriter {
+    fn pending(&self) -> bool {
+        *self.dirty.borrow() != self.status.borrow().written_generation
+    }
+
+    /// Writes whenever the registry was marked dirty and settled, retrying
+    /// failed writes every debounce interval. Once `shutdown` fires it writes
+    /// whatever is still pending and returns the error of that final write, if
+    /// any.
+    pub async fn run(
+        mut self,
+        registry: Arc<RwLock<Registry>>,
+        mut shutdown: oneshot::Receiver<()>,
+    ) -> Result<(), String> {
+        loop {
+            if !self.pending() {
+                tokio::select! {
+                    changed = self.dirty.changed() => {
+                        if changed.is_err() {
+                            break;
+                        }
+                    }
+                    _ = &mut shutdown => break,
+                }
+            }
+            let deadline = Instant::now() + self.max_delay;
+            let mut closed = false;
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(self.debounce) => break,
+                    _ = tokio::time::sleep_until(deadline) => break,
+                    // Every further change restarts the debounce interval.
+                    changed = self.dirty.changed(), if !closed => closed = changed.is_err(),
+                    _ = &mut shutdown => return self.flush(&registry).await,
+                }
+            }
+            let _ = self.write(&registry).await;
+        }
+        self.flush(&registry).await
+    }
+
+    /// Writes now if the registry changed since the last write.
+    pub async fn flush(&mut self, registry: &RwLock<Registry>) -> Result<(), String> {
+        if self.pending() {
+            self.write(registry).await
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn write(&mut self, registry: &RwLock<Registry>) -> Result<(), String> {
+        // Mutations mark the registry dirty while holding the write lock, so
+        // the generation read under the read lock matches the snapshot.
+        let (generation, snapshot, journal) = {
+            let registry = registry.read().await;
+            let generation = *self.dirty.borrow_and_update();
+            if registry.is_read_only() {
+                // Whatever a read-only registry serves must not replace the
+                // data file it couldn't read.
+                self.status
+                    .send_modify(|status| status.written_generation = generation);
+                return Err(String::from("the registry is read-only"));
+            }
+            (generation, registry.snapshot(), registry.journal())
+        };
+        let result = write_snapshot_file(&self.path, &snapshot)
+            .await
+            .map_err(|why| format!("couldn't write {}: {why}", self.path));
+        if result.is_ok() {
+            let covered = earliest_journal_sequence(&self.path)
+                .await
+                .map_or(snapshot.journal_sequence, |earliest| {
+                    earliest.min(snapshot.journal_sequence)
+                });
+            if let Err(why) = journal.compact(covered, self.compact_after).await {
+                log::warn!("couldn't compact the journal: {why}");
+            }
+        }
+        self.status.send_modify(|status| match &result {
+            Ok(()) => {
+                status.written_generation = generation;
+                status.failed_attempts = 0;
+                status.last_error = None;
+            }
+            Err(why) => {
+                status.failed_attempts += 1;
+                log::error!("{why} ({} failed attempts)", status.failed_attempts);
+                status.last_error = Some(why.clone());
+            }
+        });
+        result
+    }
+}
@@ -0,0 +1,94 @@
+//! PipeWire names a port `node:port`, like `alsa_input.usb-mixer:capture_FL`.
+//! Paths are normalized before they're stored, so the same port is always
+//! spelled the same way: whitespace is trimmed and collapsed, and a channel
+//! position suffix is upper-cased, so `capture_fl` becomes `capture_FL`. Any
+//! other suffix is part of the port's name and is kept as it is.
+
+use crate::registry::PipewirePorts;
+
+/// Channel positions PipeWire ends port names with.
+const POSITIONS: &[&str] = &["FL", "FR", "FC", "LFE", "RL", "RR", "SL", "SR", "MONO"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortPathError {
+    Empty,
+    ControlCharacter,
+    MissingSeparator,
+    EmptyNode,
+    EmptyPort,
+    EmptySegment,
+}
+
+impl std::fmt::Display for PortPathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PortPathError::Empty => "must not be empty",
+            PortPathError::ControlCharacter => "must not contain control characters",
+            PortPathError::MissingSeparator => "must be a node and a port separated by ':'",
+            PortPathError::EmptyNode => "has no node before the ':'",
+            PortPathError::EmptyPort => "has no port after the ':'",
+            PortPathError::EmptySegment => "has nothing between two ':'s or after the last",
+        })
+    }
+}
+
+impl std::error::Error for PortPathError {}
+
+/// The path in its stored form. Ports may contain ':' themselves, so only the
+/// first one separates the node from the port, but none of the parts a ':'
+/// separates may be empty.
+pub fn normalize(path: &str) -> Result<String, PortPathError> {
+    let path = path.trim();
+    if path.is_empty() {
+        return Err(PortPathError::Empty);
+    }
+    if path.chars().any(char::is_control) {
+        return Err(PortPathError::ControlCharacter);
+    }
+    let (node, port) = path
+        .split_once(':')
+        .ok_or(PortPathError::MissingSeparator)?;
+    let node = collapse_whitespace(node);
+    if node.is_empty() {
+        return Err(PortPathError::EmptyNode);
+    }
+    let port = collapse_whitespace(port);
+    if port.is_empty() {
+        return Err(PortPathError::EmptyPort);
+    }
+    if port.split(':').any(|segment| segment.trim().is_empty()) {
+        return Err(PortPathError::EmptySegment);
+    }
+    Ok(format!("{node}:{}", with_position(port)))
+}
+
+/// Assigning the same port to both sides is allowed but most likely a
+/// mistake, so the caller is warned.
+pub fn warning(ports: &PipewirePorts) -> Option<String> {
+    match ports {
+        PipewirePorts::Stereo(left, right) if left == right => {
+            Some(format!("both the left and the right port are {left}"))
+        }
+        _ => None,
+    }
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn with_position(port: String) -> String {
+    let Some((name, suffix)) = port.rsplit_once('_') else {
+        return port;
+    };
+    if name.is_empty() {
+        return port;
+    }
+    let position = POSITIONS
+        .iter()
+        .find(|position| position.eq_ignore_ascii_case(suffix));
+    match position {
+        Some(position) => format!("{name}_{position}"),
+        None => port,
+    }
+}
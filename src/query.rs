@@ -0,0 +1,218 @@
+//! Filtering, ordering and paging for the list RPCs. A page token holds the
+//! sort key of the last entity on its page, so the next page starts right
+//! after that entity even if others were added or removed in between.
+
+use crate::registry::{
+    ChannelStrip, Looper, MixerInput, MixerOutput, MixerOutputType, OutputStage, PipewirePorts,
+    Plugin,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    Id,
+    Name,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortState {
+    None,
+    Mono,
+    Stereo,
+}
+
+impl PortState {
+    fn of(ports: &PipewirePorts) -> Self {
+        match ports {
+            PipewirePorts::None => PortState::None,
+            PipewirePorts::Mono(_) => PortState::Mono,
+            PipewirePorts::Stereo(_, _) => PortState::Stereo,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InputFilter {
+    pub group_channel_strip_name: Option<String>,
+    pub port_state: Option<PortState>,
+    pub name_contains: Option<String>,
+}
+
+impl InputFilter {
+    pub fn matches(&self, input: &MixerInput) -> bool {
+        self.group_channel_strip_name
+            .as_ref()
+            .is_none_or(|name| input.group_channel_strip_name == *name)
+            && self
+                .port_state
+                .is_none_or(|state| PortState::of(&input.pipewire_ports) == state)
+            && self
+                .name_contains
+                .as_ref()
+                .is_none_or(|part| input.name.contains(part.as_str()))
+    }
+}
+
+#[derive(Debug)]
+pub struct OutputFilter {
+    pub output_type: Option<MixerOutputType>,
+}
+
+impl OutputFilter {
+    pub fn matches(&self, output: &MixerOutput) -> bool {
+        self.output_type
+            .as_ref()
+            .is_none_or(|output_type| output.output_type == *output_type)
+    }
+}
+
+#[derive(Debug)]
+pub struct PluginFilter {
+    pub plugin_uri: Option<String>,
+}
+
+impl PluginFilter {
+    pub fn matches(&self, plugin: &Plugin) -> bool {
+        self.plugin_uri
+            .as_ref()
+            .is_none_or(|uri| plugin.plugin_uri == *uri)
+    }
+}
+
+/// An entity the list RPCs can order by id or by name.
+pub trait Listed {
+    fn id(&self) -> u32;
+    fn name(&self) -> &str;
+}
+
+impl Listed for MixerInput {
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Listed for MixerOutput {
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Listed for Plugin {
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Listed for ChannelStrip {
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Listed for Looper {
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Listed for OutputStage {
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// The id and name of the last entity on a page. When ordering by name, ids
+/// break ties between entities with the same name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageToken {
+    id: u32,
+    name: String,
+}
+
+impl std::fmt::Display for PageToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.id, self.name)
+    }
+}
+
+impl std::str::FromStr for PageToken {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        let (id, name) = token.split_once('.').unwrap_or((token, ""));
+        Ok(PageToken {
+            id: id.parse()?,
+            name: String::from(name),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Page {
+    pub order: Order,
+    pub descending: bool,
+    /// 0 means everything.
+    pub size: usize,
+    pub after: Option<PageToken>,
+}
+
+impl Page {
+    /// The entities on this page in order, and the token of the next page if
+    /// there are more.
+    pub fn of<'a, T: Listed>(
+        &self,
+        entities: impl Iterator<Item = &'a T>,
+    ) -> (Vec<&'a T>, Option<PageToken>) {
+        // How an entity sorts relative to the one with `id` and `name`.
+        let ordering = |entity: &T, id: u32, name: &str| {
+            let ordering = match self.order {
+                Order::Id => entity.id().cmp(&id),
+                Order::Name => (entity.name(), entity.id()).cmp(&(name, id)),
+            };
+            if self.descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        };
+        let mut entities: Vec<&T> = match &self.after {
+            Some(after) => entities
+                .filter(|entity| ordering(entity, after.id, &after.name).is_gt())
+                .collect(),
+            None => entities.collect(),
+        };
+        entities.sort_by(|a, b| ordering(a, b.id(), b.name()));
+        if self.size == 0 || entities.len() <= self.size {
+            return (entities, None);
+        }
+        entities.truncate(self.size);
+        let next = entities.last().map(|last| PageToken {
+            id: last.id(),
+            name: String::from(last.name()),
+        });
+        (entities, next)
+    }
+}
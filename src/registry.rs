@@ -1,4 +1,9 @@
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::changes::{Change, ChangeLog, Entity, RegistryChange, ResumeToken};
+use crate::journal::{Journal, JournalEntry, Operation};
+use crate::persistence::{PersistenceHandle, PersistenceStatus};
 
 use crate::pmx::{
     channel_strip::{PmxChannelStrip, PmxChannelStripType},
@@ -6,13 +11,18 @@ use crate::pmx::{
     output_stage::PmxOutputStage,
     plugin::PmxPlugin,
 };
+use crate::snapshot::{RegistrySnapshot, CURRENT_SCHEMA_VERSION};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct MixerInput {
     pub name: String,
     pub pipewire_ports: PipewirePorts,
     pub id: u32,
     pub group_channel_strip_name: String,
+    /// Starts at 0 and is bumped by every update, so a writer can tell the
+    /// entity changed since it read it. Every entity kind has one.
+    #[serde(default)]
+    pub revision: u64,
 }
 
 impl MixerInput {
@@ -27,22 +37,25 @@ impl MixerInput {
             name: String::from(name),
             pipewire_ports,
             group_channel_strip_name: String::from(group_channel_strip_name),
+            revision: 0,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum MixerOutputType {
     Cue,
     Main,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct MixerOutput {
     pub name: String,
     pub pipewire_ports: PipewirePorts,
     pub id: u32,
     pub output_type: MixerOutputType,
+    #[serde(default)]
+    pub revision: u64,
 }
 
 impl MixerOutput {
@@ -57,32 +70,35 @@ impl MixerOutput {
             name: String::from(name),
             pipewire_ports,
             output_type,
+            revision: 0,
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum PipewirePorts {
     None,
     Mono(String),
     Stereo(String, String),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum PluginType {
     Lv2,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Plugin {
     pub id: u32,
     pub mod_host_id: u32,
     pub name: String,
     pub plugin_uri: String,
     pub plugin_type: PluginType,
+    #[serde(default)]
+    pub revision: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ChannelStripType {
     Basic {
         saturator_plugin_id: u32,
@@ -99,92 +115,303 @@ pub enum ChannelStripType {
     },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ChannelStrip {
     pub id: u32,
     pub name: String,
     pub channel_strip_type: ChannelStripType,
+    #[serde(default)]
+    pub revision: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct OutputStage {
     pub id: u32,
     pub name: String,
     pub left_channel_strip_id: u32,
     pub right_channel_strip_id: u32,
     pub cross_fader_plugin_id: u32,
+    #[serde(default)]
+    pub revision: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Looper {
     pub id: u32,
     pub name: String,
     pub loop_number: u32,
+    #[serde(default)]
+    pub revision: u64,
 }
 
-#[derive(Debug)]
-pub struct Registry {
-    inputs: Vec<MixerInput>,
-    outputs: Vec<MixerOutput>,
-    inputs_sender: tokio::sync::mpsc::UnboundedSender<Vec<MixerInput>>,
-    outputs_sender: tokio::sync::mpsc::UnboundedSender<Vec<MixerOutput>>,
-    plugins: Vec<Plugin>,
-    channel_strips: Vec<ChannelStrip>,
-    loopers: Vec<Looper>,
-    output_stages: Vec<OutputStage>,
+/// An input's wiring when its scene was saved.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SceneInput {
+    pub id: u32,
+    pub name: String,
+    pub pipewire_ports: PipewirePorts,
+    pub group_channel_strip_name: String,
 }
 
-impl Registry {
-    pub fn new(
-        inputs: Vec<MixerInput>,
-        outputs: Vec<MixerOutput>,
-        inputs_sender: tokio::sync::mpsc::UnboundedSender<Vec<MixerInput>>,
-        outputs_sender: tokio::sync::mpsc::UnboundedSender<Vec<MixerOutput>>,
-    ) -> Self {
-        Registry {
-            inputs,
-            outputs,
-            inputs_sender,
-            outputs_sender,
-            plugins: Vec::new(),
-            channel_strips: Vec::new(),
-            loopers: Vec::new(),
-            output_stages: Vec::new(),
+/// An output's wiring when its scene was saved.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SceneOutput {
+    pub id: u32,
+    pub name: String,
+    pub pipewire_ports: PipewirePorts,
+}
+
+/// A named copy of the names, ports and groups of every input and output,
+/// so a setup like rehearsal or recording can be switched to in one step.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scene {
+    pub id: u32,
+    pub name: String,
+    pub inputs: Vec<SceneInput>,
+    pub outputs: Vec<SceneOutput>,
+    #[serde(default)]
+    pub revision: u64,
+}
+
+/// Inputs and outputs a recalled scene couldn't apply to, because they
+/// were removed after it was saved, and groups that no longer exist.
+#[derive(Debug, Default)]
+pub struct SceneRecall {
+    pub missing_input_ids: Vec<u32>,
+    pub missing_output_ids: Vec<u32>,
+    pub missing_groups: Vec<String>,
+}
+
+/// A field whose current value differs from the one in a scene. `current` is
+/// `None` when the field is unset or the entity no longer exists, in which
+/// case `field` is `id`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SceneDifference {
+    pub kind: EntityKind,
+    pub id: u32,
+    pub field: &'static str,
+    pub current: Option<String>,
+    pub scene: Option<String>,
+}
+
+/// The inputs sharing a `group_channel_strip_name`, and the channel strip
+/// registered under that name if there is one. Groups aren't stored; one
+/// exists as long as an input names it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Group {
+    pub name: String,
+    pub channel_strip_id: Option<u32>,
+    pub input_ids: Vec<u32>,
+}
+
+impl PipewirePorts {
+    /// The left and right port, with a mono port on the left.
+    pub fn paths(&self) -> (Option<&str>, Option<&str>) {
+        match self {
+            PipewirePorts::None => (None, None),
+            PipewirePorts::Mono(left) => (Some(left), None),
+            PipewirePorts::Stereo(left, right) => (Some(left), Some(right)),
         }
     }
+}
 
-    pub fn register_output_stage(&mut self, output_stage: PmxOutputStage) {
-        self.output_stages.push(OutputStage {
-            id: output_stage.id,
-            name: output_stage.name,
-            left_channel_strip_id: output_stage.left_channel_strip_id,
-            right_channel_strip_id: output_stage.right_channel_strip_id,
-            cross_fader_plugin_id: output_stage.cross_fader_plugin_id,
-        });
+/// Appends a difference for every field that doesn't match.
+fn diff_fields(
+    differences: &mut Vec<SceneDifference>,
+    kind: EntityKind,
+    id: u32,
+    fields: &[(&'static str, Option<&str>, Option<&str>)],
+) {
+    for (field, current, scene) in fields {
+        if current != scene {
+            differences.push(SceneDifference {
+                kind,
+                id,
+                field,
+                current: current.map(String::from),
+                scene: scene.map(String::from),
+            });
+        }
     }
+}
 
-    pub fn get_all_output_stages(&self) -> &[OutputStage] {
-        &self.output_stages
+/// One mutation of a batch, already validated.
+#[derive(Debug, Clone)]
+pub enum Mutation {
+    AddInput {
+        name: String,
+        group_channel_strip_name: String,
+    },
+    UpdateInputName {
+        id: u32,
+        name: String,
+        expected_revision: Option<u64>,
+    },
+    UpdateInputPorts {
+        id: u32,
+        ports: PipewirePorts,
+        expected_revision: Option<u64>,
+    },
+    UpdateInputGroup {
+        id: u32,
+        group_channel_strip_name: String,
+        expected_revision: Option<u64>,
+    },
+    RemoveInput {
+        id: u32,
+    },
+    AddOutput {
+        name: String,
+        output_type: MixerOutputType,
+    },
+    UpdateOutputName {
+        id: u32,
+        name: String,
+        expected_revision: Option<u64>,
+    },
+    UpdateOutputType {
+        id: u32,
+        output_type: MixerOutputType,
+        expected_revision: Option<u64>,
+    },
+    UpdateOutputPorts {
+        id: u32,
+        ports: PipewirePorts,
+        expected_revision: Option<u64>,
+    },
+    RemoveOutput {
+        id: u32,
+    },
+    RegisterPlugin {
+        plugin: PmxPlugin,
+        idempotency_token: Option<String>,
+    },
+    UpdatePlugin {
+        plugin: PmxPlugin,
+        expected_revision: Option<u64>,
+    },
+    UnregisterPlugin {
+        id: u32,
+        cascade: bool,
+    },
+    RegisterChannelStrip {
+        channel_strip: PmxChannelStrip,
+        idempotency_token: Option<String>,
+    },
+    UpdateChannelStrip {
+        channel_strip: PmxChannelStrip,
+        expected_revision: Option<u64>,
+    },
+    UnregisterChannelStrip {
+        id: u32,
+        cascade: bool,
+    },
+    RegisterLooper {
+        looper: PmxLooper,
+        idempotency_token: Option<String>,
+    },
+    UpdateLooper {
+        looper: PmxLooper,
+        expected_revision: Option<u64>,
+    },
+    UnregisterLooper {
+        id: u32,
+    },
+    RegisterOutputStage {
+        output_stage: PmxOutputStage,
+        idempotency_token: Option<String>,
+    },
+    UpdateOutputStage {
+        output_stage: PmxOutputStage,
+        expected_revision: Option<u64>,
+    },
+    UnregisterOutputStage {
+        id: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntityKind {
+    Input,
+    Output,
+    Plugin,
+    ChannelStrip,
+    Looper,
+    OutputStage,
+    Scene,
+}
+
+impl std::fmt::Display for EntityKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            EntityKind::Input => "input",
+            EntityKind::Output => "output",
+            EntityKind::Plugin => "plugin",
+            EntityKind::ChannelStrip => "channel strip",
+            EntityKind::Looper => "looper",
+            EntityKind::OutputStage => "output stage",
+            EntityKind::Scene => "scene",
+        })
     }
+}
 
-    pub fn register_looper(&mut self, looper: PmxLooper) {
-        self.loopers.push(Looper {
-            id: looper.id,
-            name: looper.name,
-            loop_number: looper.loop_number,
-        });
+impl ChannelStripType {
+    pub fn plugin_ids(&self) -> Vec<u32> {
+        match *self {
+            ChannelStripType::Basic {
+                saturator_plugin_id,
+                compressor_plugin_id,
+                equalizer_plugin_id,
+                gain_plugin_id,
+            } => vec![
+                saturator_plugin_id,
+                compressor_plugin_id,
+                equalizer_plugin_id,
+                gain_plugin_id,
+            ],
+            ChannelStripType::CrossFaded {
+                cross_fader_plugin_id,
+                saturator_plugin_id,
+                compressor_plugin_id,
+                equalizer_plugin_id,
+                gain_plugin_id,
+            } => vec![
+                cross_fader_plugin_id,
+                saturator_plugin_id,
+                compressor_plugin_id,
+                equalizer_plugin_id,
+                gain_plugin_id,
+            ],
+        }
     }
+}
 
-    pub fn get_all_loopers(&self) -> &Vec<Looper> {
-        &self.loopers
+impl OutputStage {
+    pub fn uses_channel_strip(&self, channel_strip_id: u32) -> bool {
+        self.left_channel_strip_id == channel_strip_id
+            || self.right_channel_strip_id == channel_strip_id
     }
+}
 
-    pub fn get_looper_by_id(&self, id: u32) -> Option<&Looper> {
-        self.loopers.iter().find(|c| c.id == id)
+impl From<PmxPlugin> for Plugin {
+    fn from(plugin: PmxPlugin) -> Self {
+        Plugin {
+            id: plugin.id,
+            mod_host_id: plugin.mod_host_id,
+            name: plugin.name,
+            plugin_uri: plugin.plugin_uri,
+            plugin_type: PluginType::Lv2,
+            revision: 0,
+        }
     }
+}
 
-    pub fn register_channel_strip(&mut self, channel_strip: PmxChannelStrip) {
-        self.channel_strips.push(ChannelStrip {
+impl TryFrom<PmxChannelStrip> for ChannelStrip {
+    type Error = RegistryError;
+
+    fn try_from(channel_strip: PmxChannelStrip) -> Result<Self, RegistryError> {
+        Ok(ChannelStrip {
             id: channel_strip.id,
             name: channel_strip.name.clone(),
             channel_strip_type: match channel_strip.channel_strip_type() {
@@ -195,139 +422,2007 @@ impl Registry {
                     gain_plugin_id: channel_strip.gain_plugin_id,
                 },
                 PmxChannelStripType::CrossFaded => ChannelStripType::CrossFaded {
-                    cross_fader_plugin_id: channel_strip.cross_fader_plugin_id.unwrap(),
+                    cross_fader_plugin_id: channel_strip.cross_fader_plugin_id.ok_or_else(
+                        || RegistryError::MissingCrossFader {
+                            name: channel_strip.name.clone(),
+                        },
+                    )?,
                     saturator_plugin_id: channel_strip.saturator_plugin_id,
                     compressor_plugin_id: channel_strip.compressor_plugin_id,
                     equalizer_plugin_id: channel_strip.equalizer_plugin_id,
                     gain_plugin_id: channel_strip.gain_plugin_id,
                 },
             },
-        });
+            revision: 0,
+        })
     }
+}
 
-    pub fn get_channel_strip_by_id(&self, id: u32) -> Option<&ChannelStrip> {
-        self.channel_strips.iter().find(|c| c.id == id)
+impl From<PmxOutputStage> for OutputStage {
+    fn from(output_stage: PmxOutputStage) -> Self {
+        OutputStage {
+            id: output_stage.id,
+            name: output_stage.name,
+            left_channel_strip_id: output_stage.left_channel_strip_id,
+            right_channel_strip_id: output_stage.right_channel_strip_id,
+            cross_fader_plugin_id: output_stage.cross_fader_plugin_id,
+            revision: 0,
+        }
     }
+}
 
-    pub fn register_plugin(&mut self, plugin: PmxPlugin) {
-        self.plugins.push(Plugin {
-            id: plugin.id,
-            mod_host_id: plugin.mod_host_id,
-            name: plugin.name,
-            plugin_uri: plugin.plugin_uri,
-            plugin_type: PluginType::Lv2,
-        });
+impl From<PmxLooper> for Looper {
+    fn from(looper: PmxLooper) -> Self {
+        Looper {
+            id: looper.id,
+            name: looper.name,
+            loop_number: looper.loop_number,
+            revision: 0,
+        }
     }
+}
+
+/// The id handed out to the next entity of each kind. Ids only ever grow, so
+/// an id isn't reused after its entity is removed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NextIds {
+    pub input: u32,
+    pub output: u32,
+    pub plugin: u32,
+    pub channel_strip: u32,
+    pub looper: u32,
+    pub output_stage: u32,
+    pub scene: u32,
+}
 
-    pub fn get_plugin_by_id(&self, id: u32) -> Option<&Plugin> {
-        self.plugins.iter().find(|p| p.id == id)
+impl Default for NextIds {
+    fn default() -> Self {
+        NextIds {
+            input: 1,
+            output: 1,
+            plugin: 1,
+            channel_strip: 1,
+            looper: 1,
+            output_stage: 1,
+            scene: 1,
+        }
     }
+}
 
-    pub fn get_all_plugins(&self) -> &Vec<Plugin> {
-        &self.plugins
+/// Number of idempotency tokens remembered, so that a retried register call
+/// returns the entity created by the first attempt.
+const IDEMPOTENCY_TOKEN_CAPACITY: usize = 1024;
+
+/// The entity a register call with `token` created. Kept in the snapshot and
+/// the journal, so a retry still finds it after a restart.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdempotencyToken {
+    pub kind: EntityKind,
+    pub token: String,
+    pub id: u32,
+}
+
+#[derive(Debug, Default)]
+struct IdempotencyTokens {
+    tokens: std::collections::VecDeque<IdempotencyToken>,
+}
+
+impl IdempotencyTokens {
+    fn get(&self, kind: EntityKind, token: &str) -> Option<u32> {
+        self.tokens
+            .iter()
+            .find(|t| t.kind == kind && t.token == token)
+            .map(|t| t.id)
     }
 
-    pub fn get_all_outputs(&self) -> &[MixerOutput] {
-        &self.outputs
+    /// Replaces what the token referred to before, which is only remembered
+    /// again once the entity it created is gone.
+    fn remember(&mut self, token: IdempotencyToken) {
+        self.tokens
+            .retain(|t| t.kind != token.kind || t.token != token.token);
+        if self.tokens.len() == IDEMPOTENCY_TOKEN_CAPACITY {
+            self.tokens.pop_front();
+        }
+        self.tokens.push_back(token);
     }
+}
+
+/// Number of operations that can be undone.
+const HISTORY_CAPACITY: usize = 100;
 
-    pub fn output_by_id(&self, id: u32) -> Option<&MixerOutput> {
-        self.outputs.iter().find(|o| o.id == id)
+/// The changes of one operation, kept so it can be undone or redone.
+#[derive(Debug)]
+struct HistoryStep {
+    operation: String,
+    changes: Vec<Change>,
+}
+
+#[derive(Debug, Default)]
+struct History {
+    undo: std::collections::VecDeque<HistoryStep>,
+    redo: Vec<HistoryStep>,
+}
+
+impl History {
+    /// A new operation makes whatever was undone before it unreachable.
+    fn push(&mut self, step: HistoryStep) {
+        if self.undo.len() == HISTORY_CAPACITY {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(step);
+        self.redo.clear();
     }
+}
 
-    pub fn update_output_ports(
-        &mut self,
-        id: u32,
-        ports: PipewirePorts,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        if let Some(output) = self
-            .outputs
-            .clone()
-            .iter()
-            .enumerate()
-            .find(|(_index, output)| output.id == id)
-        {
-            self.outputs[output.0].pipewire_ports = ports;
-            self.outputs_sender.send(self.outputs.clone()).unwrap();
-            Ok(())
-        } else {
-            Err(std::boxed::Box::new(NotFoundError {}))
+/// Which way `Registry::step_history` moves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryDirection {
+    Undo,
+    Redo,
+}
+
+#[derive(Debug)]
+pub struct Registry {
+    inputs: Vec<MixerInput>,
+    outputs: Vec<MixerOutput>,
+    persistence: PersistenceHandle,
+    plugins: Vec<Plugin>,
+    channel_strips: Vec<ChannelStrip>,
+    loopers: Vec<Looper>,
+    output_stages: Vec<OutputStage>,
+    scenes: Vec<Scene>,
+    next_ids: NextIds,
+    idempotency_tokens: IdempotencyTokens,
+    changes: ChangeLog,
+    /// Changes made by the operation in progress, see `apply`.
+    uncommitted: Vec<Change>,
+    /// Tokens used by the operation in progress. They are journaled with its
+    /// changes and only remembered once it is committed.
+    uncommitted_tokens: Vec<IdempotencyToken>,
+    journal: Journal,
+    history: History,
+    /// Sequence of the last journal entry reflected in the registry.
+    journal_sequence: u64,
+    read_only: bool,
+}
+
+impl Registry {
+    pub fn new(
+        snapshot: RegistrySnapshot,
+        persistence: PersistenceHandle,
+        journal: Journal,
+        read_only: bool,
+    ) -> Self {
+        Registry {
+            inputs: snapshot.inputs,
+            outputs: snapshot.outputs,
+            persistence,
+            plugins: snapshot.plugins,
+            channel_strips: snapshot.channel_strips,
+            loopers: snapshot.loopers,
+            output_stages: snapshot.output_stages,
+            scenes: snapshot.scenes,
+            next_ids: snapshot.next_ids,
+            idempotency_tokens: snapshot.idempotency_tokens.into_iter().fold(
+                IdempotencyTokens::default(),
+                |mut tokens, token| {
+                    tokens.remember(token);
+                    tokens
+                },
+            ),
+            changes: ChangeLog::default(),
+            uncommitted: Vec::new(),
+            uncommitted_tokens: Vec::new(),
+            journal,
+            history: History::default(),
+            journal_sequence: snapshot.journal_sequence,
+            read_only,
         }
     }
 
-    pub fn get_all_inputs(&self) -> &[MixerInput] {
-        &self.inputs
+    /// Applies the journal entries written after the snapshot the registry
+    /// was created from. Every entry goes into the change log, so watchers
+    /// can resume from the changes before the restart.
+    pub fn replay(&mut self, entries: Vec<JournalEntry>) {
+        let mut replayed = false;
+        for entry in entries {
+            if entry.sequence > self.journal_sequence {
+                for change in &entry.changes {
+                    self.apply_change(change);
+                }
+                for token in entry.idempotency_tokens {
+                    self.idempotency_tokens.remember(token);
+                }
+                self.journal_sequence = entry.sequence;
+                replayed = true;
+            }
+            self.changes.record(entry.sequence, &entry.changes);
+        }
+        // A fresh snapshot lets the replayed entries be compacted away.
+        if replayed && !self.read_only {
+            self.persist();
+        }
     }
 
-    pub fn input_by_id(&self, id: u32) -> Option<&MixerInput> {
-        self.inputs.iter().find(|i| i.id == id)
+    /// Runs `mutation` as one operation. Its changes and idempotency tokens
+    /// are journaled, broadcast to watchers and persisted together, or
+    /// dropped together if the mutation fails or can't be journaled.
+    pub async fn apply<T>(
+        &mut self,
+        operation: &Operation,
+        mutation: impl FnOnce(&mut Self) -> Result<T, RegistryError>,
+    ) -> Result<T, RegistryError> {
+        self.ensure_writable()?;
+        let result = mutation(self);
+        let changes = std::mem::take(&mut self.uncommitted);
+        let tokens = std::mem::take(&mut self.uncommitted_tokens);
+        let value = match result {
+            Ok(value) => value,
+            Err(why) => {
+                self.revert(&changes);
+                return Err(why);
+            }
+        };
+        // A register call that matched an existing entity by its natural key
+        // changes nothing but still has a token to keep.
+        if changes.is_empty() && tokens.is_empty() {
+            return Ok(value);
+        }
+        self.commit(operation, &changes, &tokens).await?;
+        for token in tokens {
+            self.idempotency_tokens.remember(token);
+        }
+        if !changes.is_empty() {
+            self.history.push(HistoryStep {
+                operation: operation.name.clone(),
+                changes,
+            });
+        }
+        self.persist();
+        Ok(value)
     }
 
-    pub fn update_input_name(
+    /// Reverts the last operation, or makes the last reverted one again. The
+    /// step is journaled, broadcast and persisted like any other operation.
+    /// Returns the name of the operation stepped over.
+    pub async fn step_history(
         &mut self,
-        id: u32,
-        name: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        if let Some(input) = self
-            .inputs
-            .clone()
-            .iter()
-            .enumerate()
-            .find(|(_index, input)| input.id == id)
-        {
-            self.inputs[input.0].name = String::from(name);
-            self.inputs_sender.send(self.inputs.clone()).unwrap();
-            Ok(())
-        } else {
-            Err(std::boxed::Box::new(NotFoundError {}))
+        operation: &Operation,
+        direction: HistoryDirection,
+    ) -> Result<String, RegistryError> {
+        self.ensure_writable()?;
+        let step = match direction {
+            HistoryDirection::Undo => self.history.undo.pop_back(),
+            HistoryDirection::Redo => self.history.redo.pop(),
+        }
+        .ok_or(RegistryError::HistoryEmpty { direction })?;
+        let steps: Vec<Change> = match direction {
+            HistoryDirection::Undo => step.changes.iter().rev().map(Change::inverse).collect(),
+            HistoryDirection::Redo => step.changes.clone(),
+        };
+        // Later changes may depend on earlier ones, so each is applied before
+        // the next is restamped. On any failure the applied ones are reverted
+        // and the step goes back where it came from.
+        let mut changes = Vec::new();
+        let mut restamped = Ok(());
+        for change in steps {
+            match self.restamp(change) {
+                Ok(change) => {
+                    self.apply_change(&change);
+                    changes.push(change);
+                }
+                Err(why) => {
+                    self.revert(&changes);
+                    restamped = Err(why);
+                    break;
+                }
+            }
         }
+        let committed = match restamped {
+            Ok(()) => self.commit(operation, &changes, &[]).await,
+            Err(why) => Err(why),
+        };
+        if let Err(why) = committed {
+            match direction {
+                HistoryDirection::Undo => self.history.undo.push_back(step),
+                HistoryDirection::Redo => self.history.redo.push(step),
+            }
+            return Err(why);
+        }
+        let name = step.operation.clone();
+        match direction {
+            HistoryDirection::Undo => self.history.redo.push(step),
+            HistoryDirection::Redo => self.history.undo.push_back(step),
+        }
+        self.persist();
+        Ok(name)
     }
 
-    pub fn update_input_ports(
+    /// A change from the history against the current state of its entity.
+    /// Revisions only move forward, so stepping back to an earlier state still
+    /// counts as an update.
+    fn restamp(&self, change: Change) -> Result<Change, RegistryError> {
+        Ok(match change {
+            Change::Updated { mut after, .. } => {
+                let before = self.entity(after.kind(), after.id())?;
+                after.set_revision(before.revision() + 1);
+                Change::Updated { before, after }
+            }
+            Change::Deleted(entity) => Change::Deleted(self.entity(entity.kind(), entity.id())?),
+            created => created,
+        })
+    }
+
+    /// Number of operations that can be undone and redone.
+    pub fn history_depth(&self) -> (usize, usize) {
+        (self.history.undo.len(), self.history.redo.len())
+    }
+
+    /// Journals changes that were already made and tells watchers about them.
+    /// Changes that can't be journaled are reverted.
+    async fn commit(
         &mut self,
-        id: u32,
-        ports: PipewirePorts,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        if let Some(input) = self
-            .inputs
-            .clone()
-            .iter()
-            .enumerate()
-            .find(|(_index, input)| input.id == id)
-        {
-            self.inputs[input.0].pipewire_ports = ports;
-            self.inputs_sender.send(self.inputs.clone()).unwrap();
-            Ok(())
-        } else {
-            Err(std::boxed::Box::new(NotFoundError {}))
+        operation: &Operation,
+        changes: &[Change],
+        tokens: &[IdempotencyToken],
+    ) -> Result<(), RegistryError> {
+        match self.journal.append(operation, changes, tokens).await {
+            Ok(sequence) => {
+                self.journal_sequence = sequence;
+                self.changes.record(sequence, changes);
+                Ok(())
+            }
+            Err(why) => {
+                self.revert(changes);
+                Err(RegistryError::PersistenceFailed {
+                    reason: why.to_string(),
+                })
+            }
         }
     }
 
-    pub fn get_all_channel_strips(&self) -> &Vec<ChannelStrip> {
-        &self.channel_strips
+    /// The journal the registry appends to, for the snapshot writer to
+    /// compact.
+    pub fn journal(&self) -> Journal {
+        self.journal.clone()
     }
-}
 
-#[derive(Debug)]
-struct NotFoundError {}
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
 
-impl std::fmt::Display for NotFoundError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str("couldn't find input")?;
-        Ok(())
+    /// Adds a change to the operation in progress. An updated entity gets the
+    /// revision after the one it had before.
+    fn record(&mut self, before: Option<Entity>, after: Option<Entity>) {
+        let change = match (before, after) {
+            (None, Some(after)) => Change::Created(after),
+            (Some(before), Some(mut after)) => {
+                after.set_revision(before.revision() + 1);
+                self.put(after.clone());
+                Change::Updated { before, after }
+            }
+            (Some(before), None) => Change::Deleted(before),
+            (None, None) => return,
+        };
+        self.uncommitted.push(change);
     }
-}
 
-impl std::error::Error for NotFoundError {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
+    fn revert(&mut self, changes: &[Change]) {
+        for change in changes.iter().rev() {
+            self.apply_change(&change.inverse());
+        }
+    }
+
+    /// Brings the entity to its state after `change`, without recording it.
+    fn apply_change(&mut self, change: &Change) {
+        match change {
+            Change::Created(entity) | Change::Updated { after: entity, .. } => {
+                self.put(entity.clone())
+            }
+            Change::Deleted(entity) => self.take(entity.kind(), entity.id()),
+        }
+    }
+
+    fn put(&mut self, entity: Entity) {
+        let next_ids = &mut self.next_ids;
+        match entity {
+            Entity::Input(input) => {
+                next_ids.input = next_ids.input.max(input.id.saturating_add(1));
+                upsert(&mut self.inputs, input, |i| i.id);
+            }
+            Entity::Output(output) => {
+                next_ids.output = next_ids.output.max(output.id.saturating_add(1));
+                upsert(&mut self.outputs, output, |o| o.id);
+            }
+            Entity::Plugin(plugin) => {
+                next_ids.plugin = next_ids.plugin.max(plugin.id.saturating_add(1));
+                upsert(&mut self.plugins, plugin, |p| p.id);
+            }
+            Entity::ChannelStrip(channel_strip) => {
+                next_ids.channel_strip = next_ids
+                    .channel_strip
+                    .max(channel_strip.id.saturating_add(1));
+                upsert(&mut self.channel_strips, channel_strip, |c| c.id);
+            }
+            Entity::Looper(looper) => {
+                next_ids.looper = next_ids.looper.max(looper.id.saturating_add(1));
+                upsert(&mut self.loopers, looper, |l| l.id);
+            }
+            Entity::OutputStage(output_stage) => {
+                next_ids.output_stage =
+                    next_ids.output_stage.max(output_stage.id.saturating_add(1));
+                upsert(&mut self.output_stages, output_stage, |o| o.id);
+            }
+            Entity::Scene(scene) => {
+                next_ids.scene = next_ids.scene.max(scene.id.saturating_add(1));
+                upsert(&mut self.scenes, scene, |s| s.id);
+            }
+        }
+    }
+
+    fn take(&mut self, kind: EntityKind, id: u32) {
+        match kind {
+            EntityKind::Input => self.inputs.retain(|i| i.id != id),
+            EntityKind::Output => self.outputs.retain(|o| o.id != id),
+            EntityKind::Plugin => self.plugins.retain(|p| p.id != id),
+            EntityKind::ChannelStrip => self.channel_strips.retain(|c| c.id != id),
+            EntityKind::Looper => self.loopers.retain(|l| l.id != id),
+            EntityKind::OutputStage => self.output_stages.retain(|o| o.id != id),
+            EntityKind::Scene => self.scenes.retain(|s| s.id != id),
+        }
+    }
+
+    fn ensure_writable(&self) -> Result<(), RegistryError> {
+        if self.read_only {
+            Err(RegistryError::ReadOnly)
+        } else {
+            Ok(())
+        }
     }
 
-    fn description(&self) -> &str {
-        "description() is deprecated; use Display"
+    fn contains(&self, kind: EntityKind, id: u32) -> bool {
+        match kind {
+            EntityKind::Input => self.input_by_id(id).is_ok(),
+            EntityKind::Output => self.output_by_id(id).is_ok(),
+            EntityKind::Plugin => self.get_plugin_by_id(id).is_ok(),
+            EntityKind::ChannelStrip => self.get_channel_strip_by_id(id).is_ok(),
+            EntityKind::Looper => self.get_looper_by_id(id).is_ok(),
+            EntityKind::OutputStage => self.get_output_stage_by_id(id).is_ok(),
+            EntityKind::Scene => self.scenes.iter().any(|s| s.id == id),
+        }
     }
 
-    fn cause(&self) -> Option<&dyn std::error::Error> {
-        self.source()
+    fn entity(&self, kind: EntityKind, id: u32) -> Result<Entity, RegistryError> {
+        Ok(match kind {
+            EntityKind::Input => Entity::Input(self.input_by_id(id)?.clone()),
+            EntityKind::Output => Entity::Output(self.output_by_id(id)?.clone()),
+            EntityKind::Plugin => Entity::Plugin(self.get_plugin_by_id(id)?.clone()),
+            EntityKind::ChannelStrip => {
+                Entity::ChannelStrip(self.get_channel_strip_by_id(id)?.clone())
+            }
+            EntityKind::Looper => Entity::Looper(self.get_looper_by_id(id)?.clone()),
+            EntityKind::OutputStage => {
+                Entity::OutputStage(self.get_output_stage_by_id(id)?.clone())
+            }
+            EntityKind::Scene => Entity::Scene(
+                self.scenes
+                    .iter()
+                    .find(|s| s.id == id)
+                    .ok_or(RegistryError::NotFound { kind, id })?
+                    .clone(),
+            ),
+        })
+    }
+
+    /// Fails unless the entity is at `expected_revision`, if one is given.
+    fn check_revision(
+        &self,
+        kind: EntityKind,
+        id: u32,
+        expected_revision: Option<u64>,
+    ) -> Result<(), RegistryError> {
+        let Some(expected) = expected_revision else {
+            return Ok(());
+        };
+        let actual = self.entity(kind, id)?.revision();
+        if actual == expected {
+            Ok(())
+        } else {
+            Err(RegistryError::RevisionMismatch {
+                kind,
+                id,
+                expected,
+                actual,
+            })
+        }
+    }
+
+    /// The entity created by an earlier call with the same token, if it still
+    /// exists. Earlier calls include those of the operation in progress.
+    fn replayed_id(&self, kind: EntityKind, idempotency_token: Option<&str>) -> Option<u32> {
+        let token = idempotency_token?;
+        self.uncommitted_tokens
+            .iter()
+            .rev()
+            .find(|t| t.kind == kind && t.token == token)
+            .map(|t| t.id)
+            .or_else(|| self.idempotency_tokens.get(kind, token))
+            .filter(|id| self.contains(kind, *id))
+    }
+
+    /// Adds the token to the operation in progress unless it already refers
+    /// to `id`.
+    fn remember_token(&mut self, kind: EntityKind, idempotency_token: Option<&str>, id: u32) {
+        let Some(token) = idempotency_token else {
+            return;
+        };
+        if self.replayed_id(kind, Some(token)) == Some(id) {
+            return;
+        }
+        self.uncommitted_tokens.push(IdempotencyToken {
+            kind,
+            token: String::from(token),
+            id,
+        });
+    }
+
+    pub fn snapshot(&self) -> RegistrySnapshot {
+        RegistrySnapshot {
+            schema_version: CURRENT_SCHEMA_VERSION,
+            inputs: self.inputs.clone(),
+            outputs: self.outputs.clone(),
+            plugins: self.plugins.clone(),
+            channel_strips: self.channel_strips.clone(),
+            loopers: self.loopers.clone(),
+            output_stages: self.output_stages.clone(),
+            scenes: self.scenes.clone(),
+            next_ids: self.next_ids.clone(),
+            idempotency_tokens: self.idempotency_tokens.tokens.iter().cloned().collect(),
+            journal_sequence: self.journal_sequence,
+        }
+    }
+
+    /// The changes since `resume_token` and a receiver for the ones after
+    /// them. Without a token only new changes are received.
+    pub fn watch(
+        &self,
+        resume_token: Option<ResumeToken>,
+    ) -> Result<(Vec<RegistryChange>, broadcast::Receiver<RegistryChange>), RegistryError> {
+        self.changes
+            .subscribe(resume_token)
+            .ok_or(RegistryError::ResumeTokenExpired)
+    }
+
+    fn persist(&self) {
+        self.persistence.mark_dirty();
+    }
+
+    pub fn persistence_status(&self) -> PersistenceStatus {
+        self.persistence.status()
+    }
+
+    /// Output stages are identified by name. Registering the same stage again
+    /// returns its id; a different stage under a taken name is a conflict.
+    pub fn register_output_stage(
+        &mut self,
+        output_stage: PmxOutputStage,
+        idempotency_token: Option<&str>,
+    ) -> Result<u32, RegistryError> {
+        if let Some(id) = self.replayed_id(EntityKind::OutputStage, idempotency_token) {
+            return Ok(id);
+        }
+
+        let output_stage = OutputStage::from(output_stage);
+        if let Some(existing) = self
+            .output_stages
+            .iter()
+            .find(|o| o.name == output_stage.name)
+        {
+            let id = existing.id;
+            check_supplied_id(EntityKind::OutputStage, output_stage.id, Some(id))?;
+            if *existing
+                != (OutputStage {
+                    id,
+                    revision: existing.revision,
+                    ..output_stage
+                })
+            {
+                return Err(RegistryError::AlreadyExists {
+                    kind: EntityKind::OutputStage,
+                    id,
+                });
+            }
+            self.remember_token(EntityKind::OutputStage, idempotency_token, id);
+            return Ok(id);
+        }
+
+        check_supplied_id(EntityKind::OutputStage, output_stage.id, None)?;
+        self.validate_output_stage_references(&output_stage)?;
+        let id = take_id(&mut self.next_ids.output_stage, EntityKind::OutputStage)?;
+        let output_stage = OutputStage { id, ..output_stage };
+        self.output_stages.push(output_stage.clone());
+        self.record(None, Some(Entity::OutputStage(output_stage)));
+        self.remember_token(EntityKind::OutputStage, idempotency_token, id);
+        Ok(id)
+    }
+
+    pub fn get_all_output_stages(&self) -> &[OutputStage] {
+        &self.output_stages
+    }
+
+    pub fn get_output_stage_by_id(&self, id: u32) -> Result<&OutputStage, RegistryError> {
+        self.output_stages
+            .iter()
+            .find(|o| o.id == id)
+            .ok_or(RegistryError::NotFound {
+                kind: EntityKind::OutputStage,
+                id,
+            })
+    }
+
+    pub fn update_output_stage(
+        &mut self,
+        output_stage: PmxOutputStage,
+        expected_revision: Option<u64>,
+    ) -> Result<(), RegistryError> {
+        self.check_revision(EntityKind::OutputStage, output_stage.id, expected_revision)?;
+        let output_stage = OutputStage::from(output_stage);
+        if let Some(conflicting) = self
+            .output_stages
+            .iter()
+            .find(|o| o.id != output_stage.id && o.name == output_stage.name)
+        {
+            return Err(RegistryError::AlreadyExists {
+                kind: EntityKind::OutputStage,
+                id: conflicting.id,
+            });
+        }
+        self.validate_output_stage_references(&output_stage)?;
+        if let Some(existing) = self
+            .output_stages
+            .iter_mut()
+            .find(|o| o.id == output_stage.id)
+        {
+            let before = std::mem::replace(existing, output_stage.clone());
+            self.record(
+                Some(Entity::OutputStage(before)),
+                Some(Entity::OutputStage(output_stage)),
+            );
+            Ok(())
+        } else {
+            Err(RegistryError::NotFound {
+                kind: EntityKind::OutputStage,
+                id: output_stage.id,
+            })
+        }
+    }
+
+    pub fn unregister_output_stage(&mut self, id: u32) -> Result<OutputStage, RegistryError> {
+        if let Some(index) = self.output_stages.iter().position(|o| o.id == id) {
+            let output_stage = self.output_stages.remove(index);
+            self.record(Some(Entity::OutputStage(output_stage.clone())), None);
+            Ok(output_stage)
+        } else {
+            Err(RegistryError::NotFound {
+                kind: EntityKind::OutputStage,
+                id,
+            })
+        }
+    }
+
+    /// Loopers are identified by their loop number, so registering a loop
+    /// number twice returns the existing looper.
+    pub fn register_looper(
+        &mut self,
+        looper: PmxLooper,
+        idempotency_token: Option<&str>,
+    ) -> Result<u32, RegistryError> {
+        if let Some(id) = self.replayed_id(EntityKind::Looper, idempotency_token) {
+            return Ok(id);
+        }
+
+        let looper = Looper::from(looper);
+        if let Some(existing) = self
+            .loopers
+            .iter()
+            .find(|l| l.loop_number == looper.loop_number)
+        {
+            let id = existing.id;
+            check_supplied_id(EntityKind::Looper, looper.id, Some(id))?;
+            if *existing
+                != (Looper {
+                    id,
+                    revision: existing.revision,
+                    ..looper
+                })
+            {
+                return Err(RegistryError::AlreadyExists {
+                    kind: EntityKind::Looper,
+                    id,
+                });
+            }
+            self.remember_token(EntityKind::Looper, idempotency_token, id);
+            return Ok(id);
+        }
+
+        check_supplied_id(EntityKind::Looper, looper.id, None)?;
+        let id = take_id(&mut self.next_ids.looper, EntityKind::Looper)?;
+        let looper = Looper { id, ..looper };
+        self.loopers.push(looper.clone());
+        self.record(None, Some(Entity::Looper(looper)));
+        self.remember_token(EntityKind::Looper, idempotency_token, id);
+        Ok(id)
+    }
+
+    pub fn get_all_loopers(&self) -> &Vec<Looper> {
+        &self.loopers
+    }
+
+    pub fn get_looper_by_id(&self, id: u32) -> Result<&Looper, RegistryError> {
+        self.loopers
+            .iter()
+            .find(|c| c.id == id)
+            .ok_or(RegistryError::NotFound {
+                kind: EntityKind::Looper,
+                id,
+            })
+    }
+
+    pub fn update_looper(
+        &mut self,
+        looper: PmxLooper,
+        expected_revision: Option<u64>,
+    ) -> Result<(), RegistryError> {
+        self.check_revision(EntityKind::Looper, looper.id, expected_revision)?;
+        let looper = Looper::from(looper);
+        if let Some(conflicting) = self
+            .loopers
+            .iter()
+            .find(|l| l.id != looper.id && l.loop_number == looper.loop_number)
+        {
+            return Err(RegistryError::AlreadyExists {
+                kind: EntityKind::Looper,
+                id: conflicting.id,
+            });
+        }
+        if let Some(existing) = self.loopers.iter_mut().find(|l| l.id == looper.id) {
+            let before = std::mem::replace(existing, looper.clone());
+            self.record(Some(Entity::Looper(before)), Some(Entity::Looper(looper)));
+            Ok(())
+        } else {
+            Err(RegistryError::NotFound {
+                kind: EntityKind::Looper,
+                id: looper.id,
+            })
+        }
+    }
+
+    pub fn unregister_looper(&mut self, id: u32) -> Result<Looper, RegistryError> {
+        if let Some(index) = self.loopers.iter().position(|l| l.id == id) {
+            let looper = self.loopers.remove(index);
+            self.record(Some(Entity::Looper(looper.clone())), None);
+            Ok(looper)
+        } else {
+            Err(RegistryError::NotFound {
+                kind: EntityKind::Looper,
+                id,
+            })
+        }
+    }
+
+    /// Channel strips are identified by name. Registering the same strip again
+    /// returns its id; different plugins under a taken name are a conflict.
+    pub fn register_channel_strip(
+        &mut self,
+        channel_strip: PmxChannelStrip,
+        idempotency_token: Option<&str>,
+    ) -> Result<u32, RegistryError> {
+        if let Some(id) = self.replayed_id(EntityKind::ChannelStrip, idempotency_token) {
+            return Ok(id);
+        }
+
+        let channel_strip = ChannelStrip::try_from(channel_strip)?;
+        if let Some(existing) = self
+            .channel_strips
+            .iter()
+            .find(|c| c.name == channel_strip.name)
+        {
+            let id = existing.id;
+            check_supplied_id(EntityKind::ChannelStrip, channel_strip.id, Some(id))?;
+            if *existing
+                != (ChannelStrip {
+                    id,
+                    revision: existing.revision,
+                    ..channel_strip
+                })
+            {
+                return Err(RegistryError::AlreadyExists {
+                    kind: EntityKind::ChannelStrip,
+                    id,
+                });
+            }
+            self.remember_token(EntityKind::ChannelStrip, idempotency_token, id);
+            return Ok(id);
+        }
+
+        check_supplied_id(EntityKind::ChannelStrip, channel_strip.id, None)?;
+        self.validate_channel_strip_references(&channel_strip)?;
+        let id = take_id(&mut self.next_ids.channel_strip, EntityKind::ChannelStrip)?;
+        let channel_strip = ChannelStrip {
+            id,
+            ..channel_strip
+        };
+        self.channel_strips.push(channel_strip.clone());
+        self.record(None, Some(Entity::ChannelStrip(channel_strip)));
+        self.remember_token(EntityKind::ChannelStrip, idempotency_token, id);
+        Ok(id)
+    }
+
+    pub fn get_channel_strip_by_id(&self, id: u32) -> Result<&ChannelStrip, RegistryError> {
+        self.channel_strips
+            .iter()
+            .find(|c| c.id == id)
+            .ok_or(RegistryError::NotFound {
+                kind: EntityKind::ChannelStrip,
+                id,
+            })
+    }
+
+    pub fn update_channel_strip(
+        &mut self,
+        channel_strip: PmxChannelStrip,
+        expected_revision: Option<u64>,
+    ) -> Result<(), RegistryError> {
+        self.check_revision(
+            EntityKind::ChannelStrip,
+            channel_strip.id,
+            expected_revision,
+        )?;
+        let channel_strip = ChannelStrip::try_from(channel_strip)?;
+        if let Some(conflicting) = self
+            .channel_strips
+            .iter()
+            .find(|c| c.id != channel_strip.id && c.name == channel_strip.name)
+        {
+            return Err(RegistryError::AlreadyExists {
+                kind: EntityKind::ChannelStrip,
+                id: conflicting.id,
+            });
+        }
+        self.validate_channel_strip_references(&channel_strip)?;
+        // The inputs grouped under the strip follow it to its new name, which
+        // mustn't already be the name of another group.
+        let previous_name = self.get_channel_strip_by_id(channel_strip.id)?.name.clone();
+        let renamed = previous_name != channel_strip.name;
+        if renamed
+            && self
+                .inputs
+                .iter()
+                .any(|i| i.group_channel_strip_name == channel_strip.name)
+        {
+            return Err(RegistryError::GroupExists {
+                name: channel_strip.name,
+            });
+        }
+        if let Some(existing) = self
+            .channel_strips
+            .iter_mut()
+            .find(|c| c.id == channel_strip.id)
+        {
+            let before = std::mem::replace(existing, channel_strip.clone());
+            let new_name = channel_strip.name.clone();
+            self.record(
+                Some(Entity::ChannelStrip(before)),
+                Some(Entity::ChannelStrip(channel_strip)),
+            );
+            if renamed {
+                let grouped: Vec<u32> = self
+                    .inputs
+                    .iter()
+                    .filter(|i| i.group_channel_strip_name == previous_name)
+                    .map(|i| i.id)
+                    .collect();
+                for id in grouped {
+                    self.set_input_group(id, &new_name)?;
+                }
+                self.rename_group_in_scenes(&previous_name, &new_name);
+            }
+            Ok(())
+        } else {
+            Err(RegistryError::NotFound {
+                kind: EntityKind::ChannelStrip,
+                id: channel_strip.id,
+            })
+        }
+    }
+
+    /// A channel strip used by an output stage is only removed together with
+    /// those stages when `cascade` is set.
+    pub fn unregister_channel_strip(
+        &mut self,
+        id: u32,
+        cascade: bool,
+    ) -> Result<ChannelStrip, RegistryError> {
+        let Some(index) = self.channel_strips.iter().position(|c| c.id == id) else {
+            return Err(RegistryError::NotFound {
+                kind: EntityKind::ChannelStrip,
+                id,
+            });
+        };
+        if !cascade {
+            if let Some(output_stage) = self.output_stages.iter().find(|o| o.uses_channel_strip(id))
+            {
+                return Err(RegistryError::InUse {
+                    kind: EntityKind::ChannelStrip,
+                    id,
+                    used_by_kind: EntityKind::OutputStage,
+                    used_by_id: output_stage.id,
+                });
+            }
+        }
+        self.remove_output_stages(|o| o.uses_channel_strip(id));
+        let channel_strip = self.channel_strips.remove(index);
+        self.record(Some(Entity::ChannelStrip(channel_strip.clone())), None);
+        Ok(channel_strip)
+    }
+
+    /// Plugins are identified by mod-host id and plugin URI. Re-registering
+    /// one returns its id; a different name for the same key is a conflict.
+    pub fn register_plugin(
+        &mut self,
+        plugin: PmxPlugin,
+        idempotency_token: Option<&str>,
+    ) -> Result<u32, RegistryError> {
+        if let Some(id) = self.replayed_id(EntityKind::Plugin, idempotency_token) {
+            return Ok(id);
+        }
+
+        let plugin = Plugin::from(plugin);
+        if let Some(existing) = self
+            .plugins
+            .iter()
+            .find(|p| p.mod_host_id == plugin.mod_host_id && p.plugin_uri == plugin.plugin_uri)
+        {
+            let id = existing.id;
+            check_supplied_id(EntityKind::Plugin, plugin.id, Some(id))?;
+            if *existing
+                != (Plugin {
+                    id,
+                    revision: existing.revision,
+                    ..plugin
+                })
+            {
+                return Err(RegistryError::AlreadyExists {
+                    kind: EntityKind::Plugin,
+                    id,
+                });
+            }
+            self.remember_token(EntityKind::Plugin, idempotency_token, id);
+            return Ok(id);
+        }
+
+        check_supplied_id(EntityKind::Plugin, plugin.id, None)?;
+        let id = take_id(&mut self.next_ids.plugin, EntityKind::Plugin)?;
+        let plugin = Plugin { id, ..plugin };
+        self.plugins.push(plugin.clone());
+        self.record(None, Some(Entity::Plugin(plugin)));
+        self.remember_token(EntityKind::Plugin, idempotency_token, id);
+        Ok(id)
+    }
+
+    pub fn get_plugin_by_id(&self, id: u32) -> Result<&Plugin, RegistryError> {
+        self.plugins
+            .iter()
+            .find(|p| p.id == id)
+            .ok_or(RegistryError::NotFound {
+                kind: EntityKind::Plugin,
+                id,
+            })
+    }
+
+    pub fn get_all_plugins(&self) -> &Vec<Plugin> {
+        &self.plugins
+    }
+
+    pub fn update_plugin(
+        &mut self,
+        plugin: PmxPlugin,
+        expected_revision: Option<u64>,
+    ) -> Result<(), RegistryError> {
+        self.check_revision(EntityKind::Plugin, plugin.id, expected_revision)?;
+        let plugin = Plugin::from(plugin);
+        if let Some(conflicting) = self.plugins.iter().find(|p| {
+            p.id != plugin.id
+                && p.mod_host_id == plugin.mod_host_id
+                && p.plugin_uri == plugin.plugin_uri
+        }) {
+            return Err(RegistryError::AlreadyExists {
+                kind: EntityKind::Plugin,
+                id: conflicting.id,
+            });
+        }
+        if let Some(existing) = self.plugins.iter_mut().find(|p| p.id == plugin.id) {
+            let before = std::mem::replace(existing, plugin.clone());
+            self.record(Some(Entity::Plugin(before)), Some(Entity::Plugin(plugin)));
+            Ok(())
+        } else {
+            Err(RegistryError::NotFound {
+                kind: EntityKind::Plugin,
+                id: plugin.id,
+            })
+        }
+    }
+
+    /// A plugin used by a channel strip or an output stage is only removed
+    /// together with everything that depends on it when `cascade` is set.
+    pub fn unregister_plugin(&mut self, id: u32, cascade: bool) -> Result<Plugin, RegistryError> {
+        let Some(index) = self.plugins.iter().position(|p| p.id == id) else {
+            return Err(RegistryError::NotFound {
+                kind: EntityKind::Plugin,
+                id,
+            });
+        };
+        let dependent_channel_strips: Vec<u32> = self
+            .channel_strips
+            .iter()
+            .filter(|c| c.channel_strip_type.plugin_ids().contains(&id))
+            .map(|c| c.id)
+            .collect();
+        if !cascade {
+            let used_by = dependent_channel_strips
+                .first()
+                .map(|strip_id| (EntityKind::ChannelStrip, *strip_id))
+                .or_else(|| {
+                    self.output_stages
+                        .iter()
+                        .find(|o| o.cross_fader_plugin_id == id)
+                        .map(|o| (EntityKind::OutputStage, o.id))
+                });
+            if let Some((used_by_kind, used_by_id)) = used_by {
+                return Err(RegistryError::InUse {
+                    kind: EntityKind::Plugin,
+                    id,
+                    used_by_kind,
+                    used_by_id,
+                });
+            }
+        }
+        self.remove_output_stages(|o| {
+            o.cross_fader_plugin_id == id
+                || dependent_channel_strips
+                    .iter()
+                    .any(|strip_id| o.uses_channel_strip(*strip_id))
+        });
+        let (removed, kept) = std::mem::take(&mut self.channel_strips)
+            .into_iter()
+            .partition(|c| dependent_channel_strips.contains(&c.id));
+        self.channel_strips = kept;
+        for channel_strip in removed {
+            self.record(Some(Entity::ChannelStrip(channel_strip)), None);
+        }
+        let plugin = self.plugins.remove(index);
+        self.record(Some(Entity::Plugin(plugin.clone())), None);
+        Ok(plugin)
+    }
+
+    /// Removes the output stages matching `predicate`, as part of a cascade.
+    fn remove_output_stages(&mut self, predicate: impl Fn(&OutputStage) -> bool) {
+        let (removed, kept) = std::mem::take(&mut self.output_stages)
+            .into_iter()
+            .partition(predicate);
+        self.output_stages = kept;
+        for output_stage in removed {
+            self.record(Some(Entity::OutputStage(output_stage)), None);
+        }
+    }
+
+    fn validate_channel_strip_references(
+        &self,
+        channel_strip: &ChannelStrip,
+    ) -> Result<(), RegistryError> {
+        match channel_strip
+            .channel_strip_type
+            .plugin_ids()
+            .into_iter()
+            .find(|id| self.get_plugin_by_id(*id).is_err())
+        {
+            Some(id) => Err(RegistryError::InvalidReference {
+                kind: EntityKind::Plugin,
+                id,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn validate_output_stage_references(
+        &self,
+        output_stage: &OutputStage,
+    ) -> Result<(), RegistryError> {
+        for id in [
+            output_stage.left_channel_strip_id,
+            output_stage.right_channel_strip_id,
+        ] {
+            if self.get_channel_strip_by_id(id).is_err() {
+                return Err(RegistryError::InvalidReference {
+                    kind: EntityKind::ChannelStrip,
+                    id,
+                });
+            }
+        }
+        if self
+            .get_plugin_by_id(output_stage.cross_fader_plugin_id)
+            .is_err()
+        {
+            return Err(RegistryError::InvalidReference {
+                kind: EntityKind::Plugin,
+                id: output_stage.cross_fader_plugin_id,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn get_all_outputs(&self) -> &[MixerOutput] {
+        &self.outputs
+    }
+
+    pub fn output_by_id(&self, id: u32) -> Result<&MixerOutput, RegistryError> {
+        self.outputs
+            .iter()
+            .find(|o| o.id == id)
+            .ok_or(RegistryError::NotFound {
+                kind: EntityKind::Output,
+                id,
+            })
+    }
+
+    pub fn add_output(
+        &mut self,
+        name: &str,
+        output_type: MixerOutputType,
+    ) -> Result<u32, RegistryError> {
+        let id = take_id(&mut self.next_ids.output, EntityKind::Output)?;
+        let output = MixerOutput::new(name, PipewirePorts::None, id, output_type);
+        self.outputs.push(output.clone());
+        self.record(None, Some(Entity::Output(output)));
+        Ok(id)
+    }
+
+    pub fn remove_output(&mut self, id: u32) -> Result<MixerOutput, RegistryError> {
+        if let Some(index) = self.outputs.iter().position(|output| output.id == id) {
+            let output = self.outputs.remove(index);
+            self.record(Some(Entity::Output(output.clone())), None);
+            Ok(output)
+        } else {
+            Err(RegistryError::NotFound {
+                kind: EntityKind::Output,
+                id,
+            })
+        }
+    }
+
+    pub fn update_output_name(
+        &mut self,
+        id: u32,
+        name: &str,
+        expected_revision: Option<u64>,
+    ) -> Result<(), RegistryError> {
+        self.check_revision(EntityKind::Output, id, expected_revision)?;
+        if let Some(output) = self.outputs.iter_mut().find(|output| output.id == id) {
+            let before = output.clone();
+            output.name = String::from(name);
+            let after = output.clone();
+            self.record(Some(Entity::Output(before)), Some(Entity::Output(after)));
+            Ok(())
+        } else {
+            Err(RegistryError::NotFound {
+                kind: EntityKind::Output,
+                id,
+            })
+        }
+    }
+
+    pub fn update_output_type(
+        &mut self,
+        id: u32,
+        output_type: MixerOutputType,
+        expected_revision: Option<u64>,
+    ) -> Result<(), RegistryError> {
+        self.check_revision(EntityKind::Output, id, expected_revision)?;
+        if let Some(output) = self.outputs.iter_mut().find(|output| output.id == id) {
+            let before = output.clone();
+            output.output_type = output_type;
+            let after = output.clone();
+            self.record(Some(Entity::Output(before)), Some(Entity::Output(after)));
+            Ok(())
+        } else {
+            Err(RegistryError::NotFound {
+                kind: EntityKind::Output,
+                id,
+            })
+        }
+    }
+
+    pub fn update_output_ports(
+        &mut self,
+        id: u32,
+        ports: PipewirePorts,
+        expected_revision: Option<u64>,
+    ) -> Result<(), RegistryError> {
+        self.check_revision(EntityKind::Output, id, expected_revision)?;
+        if let Some(output) = self
+            .outputs
+            .clone()
+            .iter()
+            .enumerate()
+            .find(|(_index, output)| output.id == id)
+        {
+            self.outputs[output.0].pipewire_ports = ports;
+            self.record(
+                Some(Entity::Output(output.1.clone())),
+                Some(Entity::Output(self.outputs[output.0].clone())),
+            );
+            Ok(())
+        } else {
+            Err(RegistryError::NotFound {
+                kind: EntityKind::Output,
+                id,
+            })
+        }
+    }
+
+    pub fn get_all_inputs(&self) -> &[MixerInput] {
+        &self.inputs
+    }
+
+    pub fn input_by_id(&self, id: u32) -> Result<&MixerInput, RegistryError> {
+        self.inputs
+            .iter()
+            .find(|i| i.id == id)
+            .ok_or(RegistryError::NotFound {
+                kind: EntityKind::Input,
+                id,
+            })
+    }
+
+    /// The input joins `group_channel_strip_name`, which has to be an
+    /// existing group or the name of a channel strip.
+    pub fn add_input(
+        &mut self,
+        name: &str,
+        group_channel_strip_name: &str,
+    ) -> Result<u32, RegistryError> {
+        self.check_group_exists(group_channel_strip_name)?;
+        let id = take_id(&mut self.next_ids.input, EntityKind::Input)?;
+        let input = MixerInput::new(name, PipewirePorts::None, id, group_channel_strip_name);
+        self.inputs.push(input.clone());
+        self.record(None, Some(Entity::Input(input)));
+        Ok(id)
+    }
+
+    pub fn remove_input(&mut self, id: u32) -> Result<MixerInput, RegistryError> {
+        if let Some(index) = self.inputs.iter().position(|input| input.id == id) {
+            let input = self.inputs.remove(index);
+            self.record(Some(Entity::Input(input.clone())), None);
+            Ok(input)
+        } else {
+            Err(RegistryError::NotFound {
+                kind: EntityKind::Input,
+                id,
+            })
+        }
+    }
+
+    pub fn update_input_name(
+        &mut self,
+        id: u32,
+        name: &str,
+        expected_revision: Option<u64>,
+    ) -> Result<(), RegistryError> {
+        self.check_revision(EntityKind::Input, id, expected_revision)?;
+        if let Some(input) = self
+            .inputs
+            .clone()
+            .iter()
+            .enumerate()
+            .find(|(_index, input)| input.id == id)
+        {
+            self.inputs[input.0].name = String::from(name);
+            self.record(
+                Some(Entity::Input(input.1.clone())),
+                Some(Entity::Input(self.inputs[input.0].clone())),
+            );
+            Ok(())
+        } else {
+            Err(RegistryError::NotFound {
+                kind: EntityKind::Input,
+                id,
+            })
+        }
+    }
+
+    pub fn update_input_ports(
+        &mut self,
+        id: u32,
+        ports: PipewirePorts,
+        expected_revision: Option<u64>,
+    ) -> Result<(), RegistryError> {
+        self.check_revision(EntityKind::Input, id, expected_revision)?;
+        if let Some(input) = self
+            .inputs
+            .clone()
+            .iter()
+            .enumerate()
+            .find(|(_index, input)| input.id == id)
+        {
+            self.inputs[input.0].pipewire_ports = ports;
+            self.record(
+                Some(Entity::Input(input.1.clone())),
+                Some(Entity::Input(self.inputs[input.0].clone())),
+            );
+            Ok(())
+        } else {
+            Err(RegistryError::NotFound {
+                kind: EntityKind::Input,
+                id,
+            })
+        }
+    }
+
+    pub fn get_all_channel_strips(&self) -> &Vec<ChannelStrip> {
+        &self.channel_strips
+    }
+
+    /// Applies the mutations in order and returns the id each one created,
+    /// changed or removed. Stops at the first failure, reporting its index;
+    /// run it through `apply` so the ones before it are reverted.
+    pub fn apply_batch(&mut self, mutations: Vec<Mutation>) -> Result<Vec<u32>, RegistryError> {
+        mutations
+            .into_iter()
+            .enumerate()
+            .map(|(index, mutation)| {
+                self.apply_mutation(mutation)
+                    .map_err(|source| RegistryError::BatchFailed {
+                        index,
+                        source: Box::new(source),
+                    })
+            })
+            .collect()
+    }
+
+    fn apply_mutation(&mut self, mutation: Mutation) -> Result<u32, RegistryError> {
+        match mutation {
+            Mutation::AddInput {
+                name,
+                group_channel_strip_name,
+            } => self.add_input(&name, &group_channel_strip_name),
+            Mutation::UpdateInputName {
+                id,
+                name,
+                expected_revision,
+            } => self
+                .update_input_name(id, &name, expected_revision)
+                .map(|()| id),
+            Mutation::UpdateInputPorts {
+                id,
+                ports,
+                expected_revision,
+            } => self
+                .update_input_ports(id, ports, expected_revision)
+                .map(|()| id),
+            Mutation::UpdateInputGroup {
+                id,
+                group_channel_strip_name,
+                expected_revision,
+            } => self
+                .update_input_group(id, &group_channel_strip_name, expected_revision)
+                .map(|()| id),
+            Mutation::RemoveInput { id } => self.remove_input(id).map(|_| id),
+            Mutation::AddOutput { name, output_type } => self.add_output(&name, output_type),
+            Mutation::UpdateOutputName {
+                id,
+                name,
+                expected_revision,
+            } => self
+                .update_output_name(id, &name, expected_revision)
+                .map(|()| id),
+            Mutation::UpdateOutputType {
+                id,
+                output_type,
+                expected_revision,
+            } => self
+                .update_output_type(id, output_type, expected_revision)
+                .map(|()| id),
+            Mutation::UpdateOutputPorts {
+                id,
+                ports,
+                expected_revision,
+            } => self
+                .update_output_ports(id, ports, expected_revision)
+                .map(|()| id),
+            Mutation::RemoveOutput { id } => self.remove_output(id).map(|_| id),
+            Mutation::RegisterPlugin {
+                plugin,
+                idempotency_token,
+            } => self.register_plugin(plugin, idempotency_token.as_deref()),
+            Mutation::UpdatePlugin {
+                plugin,
+                expected_revision,
+            } => {
+                let id = plugin.id;
+                self.update_plugin(plugin, expected_revision).map(|()| id)
+            }
+            Mutation::UnregisterPlugin { id, cascade } => {
+                self.unregister_plugin(id, cascade).map(|_| id)
+            }
+            Mutation::RegisterChannelStrip {
+                channel_strip,
+                idempotency_token,
+            } => self.register_channel_strip(channel_strip, idempotency_token.as_deref()),
+            Mutation::UpdateChannelStrip {
+                channel_strip,
+                expected_revision,
+            } => {
+                let id = channel_strip.id;
+                self.update_channel_strip(channel_strip, expected_revision)
+                    .map(|()| id)
+            }
+            Mutation::UnregisterChannelStrip { id, cascade } => {
+                self.unregister_channel_strip(id, cascade).map(|_| id)
+            }
+            Mutation::RegisterLooper {
+                looper,
+                idempotency_token,
+            } => self.register_looper(looper, idempotency_token.as_deref()),
+            Mutation::UpdateLooper {
+                looper,
+                expected_revision,
+            } => {
+                let id = looper.id;
+                self.update_looper(looper, expected_revision).map(|()| id)
+            }
+            Mutation::UnregisterLooper { id } => self.unregister_looper(id).map(|_| id),
+            Mutation::RegisterOutputStage {
+                output_stage,
+                idempotency_token,
+            } => self.register_output_stage(output_stage, idempotency_token.as_deref()),
+            Mutation::UpdateOutputStage {
+                output_stage,
+                expected_revision,
+            } => {
+                let id = output_stage.id;
+                self.update_output_stage(output_stage, expected_revision)
+                    .map(|()| id)
+            }
+            Mutation::UnregisterOutputStage { id } => self.unregister_output_stage(id).map(|_| id),
+        }
+    }
+
+    pub fn get_all_scenes(&self) -> &[Scene] {
+        &self.scenes
+    }
+
+    pub fn scene_by_name(&self, name: &str) -> Result<&Scene, RegistryError> {
+        self.scenes
+            .iter()
+            .find(|s| s.name == name)
+            .ok_or_else(|| RegistryError::SceneNotFound {
+                name: String::from(name),
+            })
+    }
+
+    /// Saves the current inputs and outputs under `name`, replacing the scene
+    /// saved under it before.
+    pub fn save_scene(&mut self, name: &str) -> Result<u32, RegistryError> {
+        let existing = self.scenes.iter().position(|s| s.name == name);
+        let id = match existing {
+            Some(index) => self.scenes[index].id,
+            None => take_id(&mut self.next_ids.scene, EntityKind::Scene)?,
+        };
+        let scene = Scene {
+            id,
+            name: String::from(name),
+            inputs: self
+                .inputs
+                .iter()
+                .map(|input| SceneInput {
+                    id: input.id,
+                    name: input.name.clone(),
+                    pipewire_ports: input.pipewire_ports.clone(),
+                    group_channel_strip_name: input.group_channel_strip_name.clone(),
+                })
+                .collect(),
+            outputs: self
+                .outputs
+                .iter()
+                .map(|output| SceneOutput {
+                    id: output.id,
+                    name: output.name.clone(),
+                    pipewire_ports: output.pipewire_ports.clone(),
+                })
+                .collect(),
+            revision: existing.map_or(0, |index| self.scenes[index].revision),
+        };
+        match existing {
+            Some(index) => {
+                let before = std::mem::replace(&mut self.scenes[index], scene.clone());
+                if before != scene {
+                    self.record(Some(Entity::Scene(before)), Some(Entity::Scene(scene)));
+                }
+            }
+            None => {
+                self.scenes.push(scene.clone());
+                self.record(None, Some(Entity::Scene(scene)));
+            }
+        }
+        Ok(id)
+    }
+
+    pub fn delete_scene(&mut self, name: &str) -> Result<Scene, RegistryError> {
+        let index = self
+            .scenes
+            .iter()
+            .position(|s| s.name == name)
+            .ok_or_else(|| RegistryError::SceneNotFound {
+                name: String::from(name),
+            })?;
+        let scene = self.scenes.remove(index);
+        self.record(Some(Entity::Scene(scene.clone())), None);
+        Ok(scene)
+    }
+
+    /// Gives every input and output in the scene the name, ports and group it
+    /// had when the scene was saved. Inputs and outputs added since are left
+    /// alone, removed ones are reported. So are groups that no longer exist,
+    /// and the inputs saved in them keep their current group.
+    pub fn recall_scene(&mut self, name: &str) -> Result<SceneRecall, RegistryError> {
+        let scene = self.scene_by_name(name)?.clone();
+        let mut recall = SceneRecall::default();
+        // Missing inputs and groups are resolved against the registry as it
+        // was before the recall, since moving inputs can create or empty groups.
+        let mut recalled_inputs = Vec::new();
+        for scene_input in scene.inputs {
+            if self.input_by_id(scene_input.id).is_err() {
+                recall.missing_input_ids.push(scene_input.id);
+                continue;
+            }
+            let group = &scene_input.group_channel_strip_name;
+            let group_exists = self.check_group_exists(group).is_ok();
+            if !group_exists && !recall.missing_groups.contains(group) {
+                recall.missing_groups.push(group.clone());
+            }
+            recalled_inputs.push((scene_input, group_exists));
+        }
+        for (scene_input, group_exists) in recalled_inputs {
+            let Some(input) = self.inputs.iter_mut().find(|i| i.id == scene_input.id) else {
+                continue;
+            };
+            let after = MixerInput {
+                name: scene_input.name,
+                pipewire_ports: scene_input.pipewire_ports,
+                group_channel_strip_name: if group_exists {
+                    scene_input.group_channel_strip_name
+                } else {
+                    input.group_channel_strip_name.clone()
+                },
+                ..input.clone()
+            };
+            if *input != after {
+                let before = std::mem::replace(input, after.clone());
+                self.record(Some(Entity::Input(before)), Some(Entity::Input(after)));
+            }
+        }
+        for scene_output in scene.outputs {
+            let Some(output) = self.outputs.iter_mut().find(|o| o.id == scene_output.id) else {
+                recall.missing_output_ids.push(scene_output.id);
+                continue;
+            };
+            let after = MixerOutput {
+                name: scene_output.name,
+                pipewire_ports: scene_output.pipewire_ports,
+                ..output.clone()
+            };
+            if *output != after {
+                let before = std::mem::replace(output, after.clone());
+                self.record(Some(Entity::Output(before)), Some(Entity::Output(after)));
+            }
+        }
+        Ok(recall)
+    }
+
+    /// What recalling the scene would change.
+    pub fn diff_scene(&self, name: &str) -> Result<Vec<SceneDifference>, RegistryError> {
+        let scene = self.scene_by_name(name)?;
+        let mut differences = Vec::new();
+        for scene_input in &scene.inputs {
+            let id = scene_input.id;
+            let (scene_left, scene_right) = scene_input.pipewire_ports.paths();
+            let Ok(input) = self.input_by_id(id) else {
+                diff_fields(
+                    &mut differences,
+                    EntityKind::Input,
+                    id,
+                    &[("id", None, Some(&id.to_string()))],
+                );
+                continue;
+            };
+            let (left, right) = input.pipewire_ports.paths();
+            diff_fields(
+                &mut differences,
+                EntityKind::Input,
+                id,
+                &[
+                    ("name", Some(&input.name), Some(&scene_input.name)),
+                    ("left_port_path", left, scene_left),
+                    ("right_port_path", right, scene_right),
+                    (
+                        "group_channel_strip_name",
+                        Some(&input.group_channel_strip_name),
+                        Some(&scene_input.group_channel_strip_name),
+                    ),
+                ],
+            );
+        }
+        for scene_output in &scene.outputs {
+            let id = scene_output.id;
+            let (scene_left, scene_right) = scene_output.pipewire_ports.paths();
+            let Ok(output) = self.output_by_id(id) else {
+                diff_fields(
+                    &mut differences,
+                    EntityKind::Output,
+                    id,
+                    &[("id", None, Some(&id.to_string()))],
+                );
+                continue;
+            };
+            let (left, right) = output.pipewire_ports.paths();
+            diff_fields(
+                &mut differences,
+                EntityKind::Output,
+                id,
+                &[
+                    ("name", Some(&output.name), Some(&scene_output.name)),
+                    ("left_port_path", left, scene_left),
+                    ("right_port_path", right, scene_right),
+                ],
+            );
+        }
+        Ok(differences)
+    }
+
+    /// Every group in order of name.
+    pub fn get_all_groups(&self) -> Vec<Group> {
+        let mut groups: Vec<Group> = Vec::new();
+        for input in &self.inputs {
+            let name = &input.group_channel_strip_name;
+            match groups.iter_mut().find(|g| g.name == *name) {
+                Some(group) => group.input_ids.push(input.id),
+                None => groups.push(Group {
+                    name: name.clone(),
+                    channel_strip_id: self
+                        .channel_strips
+                        .iter()
+                        .find(|c| c.name == *name)
+                        .map(|c| c.id),
+                    input_ids: vec![input.id],
+                }),
+            }
+        }
+        groups.sort_by(|a, b| a.name.cmp(&b.name));
+        groups
+    }
+
+    pub fn group_by_name(&self, name: &str) -> Result<Group, RegistryError> {
+        self.get_all_groups()
+            .into_iter()
+            .find(|g| g.name == name)
+            .ok_or_else(|| RegistryError::GroupNotFound {
+                name: String::from(name),
+            })
+    }
+
+    /// Renames the group on each of its inputs and on the channel strip it's
+    /// linked to. A group without a channel strip gets linked to the one
+    /// registered under the new name, if any.
+    pub fn rename_group(&mut self, name: &str, new_name: &str) -> Result<(), RegistryError> {
+        let group = self.group_by_name(name)?;
+        if new_name == name {
+            return Ok(());
+        }
+        if self
+            .inputs
+            .iter()
+            .any(|i| i.group_channel_strip_name == new_name)
+        {
+            return Err(RegistryError::GroupExists {
+                name: String::from(new_name),
+            });
+        }
+        if let Some(id) = group.channel_strip_id {
+            if let Some(conflicting) = self.channel_strips.iter().find(|c| c.name == new_name) {
+                return Err(RegistryError::AlreadyExists {
+                    kind: EntityKind::ChannelStrip,
+                    id: conflicting.id,
+                });
+            }
+            let channel_strip = self.get_channel_strip_by_id(id)?;
+            let before = channel_strip.clone();
+            let after = ChannelStrip {
+                name: String::from(new_name),
+                ..before.clone()
+            };
+            self.record(
+                Some(Entity::ChannelStrip(before)),
+                Some(Entity::ChannelStrip(after)),
+            );
+        }
+        for id in group.input_ids {
+            self.set_input_group(id, new_name)?;
+        }
+        self.rename_group_in_scenes(name, new_name);
+        Ok(())
+    }
+
+    /// Saved scenes follow a group to its new name, so recalling one doesn't
+    /// bring back the old name.
+    fn rename_group_in_scenes(&mut self, name: &str, new_name: &str) {
+        for index in 0..self.scenes.len() {
+            let scene = &self.scenes[index];
+            if !scene
+                .inputs
+                .iter()
+                .any(|i| i.group_channel_strip_name == name)
+            {
+                continue;
+            }
+            let before = scene.clone();
+            let mut after = scene.clone();
+            for input in &mut after.inputs {
+                if input.group_channel_strip_name == name {
+                    input.group_channel_strip_name = String::from(new_name);
+                }
+            }
+            self.record(Some(Entity::Scene(before)), Some(Entity::Scene(after)));
+        }
+    }
+
+    /// Moves the inputs into `group`, which has to be an existing group or
+    /// the name of a channel strip.
+    pub fn move_inputs_to_group(
+        &mut self,
+        input_ids: &[u32],
+        group: &str,
+    ) -> Result<(), RegistryError> {
+        self.check_group_exists(group)?;
+        for &id in input_ids {
+            self.set_input_group(id, group)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the input into `group`, which has to be an existing group or the
+    /// name of a channel strip.
+    pub fn update_input_group(
+        &mut self,
+        id: u32,
+        group: &str,
+        expected_revision: Option<u64>,
+    ) -> Result<(), RegistryError> {
+        self.check_revision(EntityKind::Input, id, expected_revision)?;
+        self.check_group_exists(group)?;
+        self.set_input_group(id, group)
+    }
+
+    fn check_group_exists(&self, name: &str) -> Result<(), RegistryError> {
+        let exists = self
+            .inputs
+            .iter()
+            .any(|i| i.group_channel_strip_name == name)
+            || self.channel_strips.iter().any(|c| c.name == name);
+        if exists {
+            Ok(())
+        } else {
+            Err(RegistryError::InvalidGroupReference {
+                name: String::from(name),
+            })
+        }
+    }
+
+    fn set_input_group(&mut self, id: u32, group: &str) -> Result<(), RegistryError> {
+        let input = self.input_by_id(id)?;
+        if input.group_channel_strip_name == group {
+            return Ok(());
+        }
+        let before = input.clone();
+        let after = MixerInput {
+            group_channel_strip_name: String::from(group),
+            ..before.clone()
+        };
+        self.record(Some(Entity::Input(before)), Some(Entity::Input(after)));
+        Ok(())
+    }
+}
+
+/// Replaces the entity with the same id, or adds it if there is none.
+fn upsert<T>(entities: &mut Vec<T>, entity: T, id: impl Fn(&T) -> u32) {
+    match entities.iter_mut().find(|e| id(e) == id(&entity)) {
+        Some(existing) => *existing = entity,
+        None => entities.push(entity),
+    }
+}
+
+fn take_id(next_id: &mut u32, kind: EntityKind) -> Result<u32, RegistryError> {
+    let id = *next_id;
+    *next_id = id
+        .checked_add(1)
+        .ok_or(RegistryError::IdsExhausted { kind })?;
+    Ok(id)
+}
+
+/// Ids are assigned by the registry. A register call may still name the id of
+/// the entity it matches by natural key, but not any other.
+fn check_supplied_id(
+    kind: EntityKind,
+    supplied: u32,
+    matched: Option<u32>,
+) -> Result<(), RegistryError> {
+    if supplied == 0 || Some(supplied) == matched {
+        Ok(())
+    } else {
+        Err(RegistryError::UnexpectedId { kind, id: supplied })
+    }
+}
+
+/// Every way a registry operation can fail.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegistryError {
+    NotFound {
+        kind: EntityKind,
+        id: u32,
+    },
+    /// Another entity of `kind` with different attributes already holds the
+    /// natural key.
+    AlreadyExists {
+        kind: EntityKind,
+        id: u32,
+    },
+    /// A register call named an id the registry didn't assign to the entity
+    /// it matched.
+    UnexpectedId {
+        kind: EntityKind,
+        id: u32,
+    },
+    /// A cross-faded channel strip came without its cross fader plugin.
+    MissingCrossFader {
+        name: String,
+    },
+    /// The entity refers to a `kind` with `id` that isn't registered.
+    InvalidReference {
+        kind: EntityKind,
+        id: u32,
+    },
+    /// An input would join a group no input is in and no channel strip is
+    /// named after.
+    InvalidGroupReference {
+        name: String,
+    },
+    InUse {
+        kind: EntityKind,
+        id: u32,
+        used_by_kind: EntityKind,
+        used_by_id: u32,
+    },
+    ReadOnly,
+    IdsExhausted {
+        kind: EntityKind,
+    },
+    /// The changes after the resume token are no longer available.
+    ResumeTokenExpired,
+    /// The change couldn't be written to disk and was reverted.
+    PersistenceFailed {
+        reason: String,
+    },
+    SceneNotFound {
+        name: String,
+    },
+    GroupNotFound {
+        name: String,
+    },
+    /// Inputs are already in a group with that name.
+    GroupExists {
+        name: String,
+    },
+    /// The entity changed since the caller read it.
+    RevisionMismatch {
+        kind: EntityKind,
+        id: u32,
+        expected: u64,
+        actual: u64,
+    },
+    /// The mutation at `index` of a batch failed, so none were applied.
+    BatchFailed {
+        index: usize,
+        source: Box<RegistryError>,
+    },
+    /// There is nothing to undo or redo.
+    HistoryEmpty {
+        direction: HistoryDirection,
+    },
+}
+
+impl std::fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistryError::NotFound { kind, id } => write!(f, "couldn't find {kind} {id}"),
+            RegistryError::AlreadyExists { kind, id } => {
+                write!(f, "{kind} {id} already exists with different attributes")
+            }
+            RegistryError::UnexpectedId { kind, id } => {
+                write!(f, "{kind} id {id} wasn't assigned by the registry")
+            }
+            RegistryError::MissingCrossFader { name } => {
+                write!(
+                    f,
+                    "cross-faded channel strip {name} has no cross fader plugin"
+                )
+            }
+            RegistryError::InvalidReference { kind, id } => {
+                write!(f, "references missing {kind} {id}")
+            }
+            RegistryError::InvalidGroupReference { name } => write!(
+                f,
+                "no input is in group {name:?} and no channel strip has that name"
+            ),
+            RegistryError::InUse {
+                kind,
+                id,
+                used_by_kind,
+                used_by_id,
+            } => write!(
+                f,
+                "{kind} {id} is still used by {used_by_kind} {used_by_id}"
+            ),
+            RegistryError::ReadOnly => f.write_str("registry is read-only"),
+            RegistryError::IdsExhausted { kind } => write!(f, "no free {kind} id left"),
+            RegistryError::ResumeTokenExpired => {
+                f.write_str("resume token is unknown or too old to resume from")
+            }
+            RegistryError::SceneNotFound { name } => write!(f, "couldn't find scene {name:?}"),
+            RegistryError::GroupNotFound { name } => write!(
+                f,
+                "no input is in group {name:?} and no channel strip has that name"
+            ),
+            RegistryError::GroupExists { name } => write!(f, "group {name:?} already exists"),
+            RegistryError::RevisionMismatch {
+                kind,
+                id,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{kind} {id} is at revision {actual}, not the expected revision {expected}"
+            ),
+            RegistryError::BatchFailed { index, source } => write!(f, "mutation {index}: {source}"),
+            RegistryError::HistoryEmpty {
+                direction: HistoryDirection::Undo,
+            } => f.write_str("there is nothing to undo"),
+            RegistryError::HistoryEmpty {
+                direction: HistoryDirection::Redo,
+            } => f.write_str("there is nothing to redo"),
+            RegistryError::PersistenceFailed { reason } => {
+                write!(
+                    f,
+                    "the change wasn't applied, it couldn't be journaled: {reason}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RegistryError::BatchFailed { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
     }
 }
@@ -1,23 +1,48 @@
+use clap::{Parser, ValueEnum};
 use pmx::output::{PmxOutput, PmxOutputType};
-use registry::{MixerInput, MixerOutput};
+use registry::{
+    ChannelStrip, ChannelStripType, Group, Looper, MixerInput, MixerOutput, MixerOutputType,
+    OutputStage, Plugin, PluginType, Scene, SceneDifference,
+};
+use std::path::Path;
+use std::process::ExitCode;
 use std::result::Result;
-use tokio::sync::RwLock;
+use std::sync::Arc;
+use std::time::Duration;
+use template::RegistryTemplate;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{broadcast::error::RecvError, watch, RwLock};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::MetadataValue;
 use tonic::{transport::Server, Request, Response, Status};
 
 use pmx::channel_strip::{PmxChannelStrip, PmxChannelStripType};
+use pmx::group::PmxGroup;
 use pmx::input::{PmxInput, PmxInputType};
 use pmx::looper::PmxLooper;
 use pmx::output_stage::PmxOutputStage;
 use pmx::plugin::{PmxPlugin, PmxPluginType};
 use pmx::pmx_registry_server::{PmxRegistry, PmxRegistryServer};
+use pmx::scene::{PmxScene, PmxSceneInput, PmxSceneOutput};
 use pmx::{
-    ByIdRequest, EmptyRequest, ListChannelStripsReply, ListInputsReply, ListLoopersReply,
-    ListOutputStagesReply, ListOutputsReply, ListPluginsReply, RegisterChannelStripRequest,
-    RegisterLooperRequest, RegisterOutputStageRequest, RegisterPluginRequest,
-    UpdateInputNameRequest, UpdateInputPortAssignmentsRequest, UpdateOutputPortAssignmentsRequest,
+    registry_change, AddInputRequest, AddOutputRequest, ApplyBatchReply, ApplyBatchRequest,
+    ByIdRequest, DiffSceneReply, EmptyRequest, HistoryReply, ListChannelStripsReply,
+    ListChannelStripsRequest, ListGroupsReply, ListInputsReply, ListInputsRequest,
+    ListLoopersReply, ListLoopersRequest, ListOutputStagesReply, ListOutputStagesRequest,
+    ListOutputsReply, ListOutputsRequest, ListPluginsReply, ListPluginsRequest, ListScenesReply,
+    MoveInputsToGroupRequest, PersistenceStatusReply, PmxChangeType, PmxEntityKind,
+    RecallSceneReply, RegisterChannelStripRequest, RegisterLooperRequest,
+    RegisterOutputStageRequest, RegisterPluginRequest, RegistryChange, RenameGroupRequest,
+    SaveSceneRequest, SceneByNameRequest, UnregisterRequest, UpdateChannelStripRequest,
+    UpdateInputGroupRequest, UpdateInputNameRequest, UpdateInputPortAssignmentsRequest,
+    UpdateLooperRequest, UpdateOutputNameRequest, UpdateOutputPortAssignmentsRequest,
+    UpdateOutputStageRequest, UpdateOutputTypeRequest, UpdatePluginRequest, WatchRegistryRequest,
 };
 
-use crate::registry::{PipewirePorts, Registry};
+use crate::changes::{ChangeType, Entity};
+use crate::journal::{Journal, Operation};
+use crate::persistence::PersistenceHandle;
+use crate::registry::{EntityKind, HistoryDirection, Mutation, PipewirePorts, Registry};
 
 pub mod pmx {
     tonic::include_proto!("pmx");
@@ -45,31 +70,64 @@ pub mod pmx {
     pub mod output_stage {
         tonic::include_proto!("pmx.output_stage");
     }
+
+    pub mod scene {
+        tonic::include_proto!("pmx.scene");
+    }
+
+    pub mod group {
+        tonic::include_proto!("pmx.group");
+    }
+
+    pub mod error_details {
+        tonic::include_proto!("pmx.error_details");
+    }
 }
 
+mod changes;
 mod file_reader;
 mod file_writer;
+mod journal;
+mod logging;
+mod persistence;
+mod port_path;
+mod query;
 mod registry;
+mod snapshot;
+mod status;
+mod template;
+#[cfg(test)]
+mod tests;
+mod validation;
 
 #[derive(Debug)]
 pub struct PmxRegistryService {
-    registry: RwLock<Registry>,
+    registry: Arc<RwLock<Registry>>,
+    /// Flips to `true` on shutdown so open watch streams end and the server
+    /// can finish draining.
+    shutting_down: watch::Receiver<bool>,
 }
 
 impl PmxRegistryService {
-    fn new(
-        inputs: Vec<MixerInput>,
-        outputs: Vec<MixerOutput>,
-        inputs_sender: tokio::sync::mpsc::UnboundedSender<Vec<MixerInput>>,
-        outputs_sender: tokio::sync::mpsc::UnboundedSender<Vec<MixerOutput>>,
-    ) -> Self {
+    async fn step_history(
+        &self,
+        operation: Operation,
+        direction: HistoryDirection,
+    ) -> Result<Response<HistoryReply>, Status> {
+        let mut registry = self.registry.write().await;
+        let stepped_over = registry.step_history(&operation, direction).await?;
+        let (undo_depth, redo_depth) = registry.history_depth();
+        Ok(Response::new(HistoryReply {
+            operation: stepped_over,
+            undo_depth: undo_depth as u32,
+            redo_depth: redo_depth as u32,
+        }))
+    }
+
+    fn new(registry: Arc<RwLock<Registry>>, shutting_down: watch::Receiver<bool>) -> Self {
         PmxRegistryService {
-            registry: RwLock::new(Registry::new(
-                inputs,
-                outputs,
-                inputs_sender,
-                outputs_sender,
-            )),
+            registry,
+            shutting_down,
         }
     }
 }
@@ -94,421 +152,1288 @@ impl PmxInput {
                 _ => None,
             },
             group_channel_strip_name: input.group_channel_strip_name.clone(),
+            revision: input.revision,
+        }
+    }
+}
+
+impl PmxOutput {
+    fn from(output: &MixerOutput) -> Self {
+        PmxOutput {
+            id: output.id,
+            name: output.name.clone(),
+            output_type: match output.output_type {
+                MixerOutputType::Cue => PmxOutputType::Cue as i32,
+                MixerOutputType::Main => PmxOutputType::Main as i32,
+            },
+            left_port_path: match &output.pipewire_ports {
+                PipewirePorts::None => None,
+                PipewirePorts::Mono(left) => Some(left.clone()),
+                PipewirePorts::Stereo(left, _) => Some(left.clone()),
+            },
+            right_port_path: match &output.pipewire_ports {
+                PipewirePorts::None => None,
+                PipewirePorts::Mono(left) => Some(left.clone()),
+                PipewirePorts::Stereo(_, right) => Some(right.clone()),
+            },
+            revision: output.revision,
+        }
+    }
+}
+
+impl PmxPlugin {
+    fn from(plugin: &Plugin) -> Self {
+        PmxPlugin {
+            id: plugin.id,
+            mod_host_id: plugin.mod_host_id,
+            name: plugin.name.clone(),
+            plugin_uri: plugin.plugin_uri.clone(),
+            plugin_type: match plugin.plugin_type {
+                PluginType::Lv2 => PmxPluginType::Lv2 as i32,
+            },
+            revision: plugin.revision,
+        }
+    }
+}
+
+impl PmxChannelStrip {
+    fn from(channel_strip: &ChannelStrip) -> Self {
+        match channel_strip.channel_strip_type {
+            ChannelStripType::Basic {
+                saturator_plugin_id,
+                compressor_plugin_id,
+                equalizer_plugin_id,
+                gain_plugin_id,
+            } => PmxChannelStrip {
+                id: channel_strip.id,
+                name: channel_strip.name.clone(),
+                channel_strip_type: PmxChannelStripType::Basic as i32,
+                cross_fader_plugin_id: None,
+                saturator_plugin_id,
+                compressor_plugin_id,
+                equalizer_plugin_id,
+                gain_plugin_id,
+                revision: channel_strip.revision,
+            },
+            ChannelStripType::CrossFaded {
+                cross_fader_plugin_id,
+                saturator_plugin_id,
+                compressor_plugin_id,
+                equalizer_plugin_id,
+                gain_plugin_id,
+            } => PmxChannelStrip {
+                id: channel_strip.id,
+                name: channel_strip.name.clone(),
+                channel_strip_type: PmxChannelStripType::CrossFaded as i32,
+                cross_fader_plugin_id: Some(cross_fader_plugin_id),
+                saturator_plugin_id,
+                compressor_plugin_id,
+                equalizer_plugin_id,
+                gain_plugin_id,
+                revision: channel_strip.revision,
+            },
+        }
+    }
+}
+
+impl PmxLooper {
+    fn from(looper: &Looper) -> Self {
+        PmxLooper {
+            id: looper.id,
+            name: looper.name.clone(),
+            loop_number: looper.loop_number,
+            revision: looper.revision,
+        }
+    }
+
+    /// Loopers are named after their loop number.
+    fn registered(request: &RegisterLooperRequest) -> Self {
+        PmxLooper {
+            id: 0,
+            name: format!("loop_{}", request.loop_number),
+            loop_number: request.loop_number,
+            revision: 0,
+        }
+    }
+}
+
+impl PmxOutputStage {
+    fn from(output_stage: &OutputStage) -> Self {
+        PmxOutputStage {
+            id: output_stage.id,
+            name: output_stage.name.clone(),
+            left_channel_strip_id: output_stage.left_channel_strip_id,
+            right_channel_strip_id: output_stage.right_channel_strip_id,
+            cross_fader_plugin_id: output_stage.cross_fader_plugin_id,
+            revision: output_stage.revision,
+        }
+    }
+
+    fn registered(request: &RegisterOutputStageRequest) -> Self {
+        PmxOutputStage {
+            id: 0,
+            name: request.name.clone(),
+            left_channel_strip_id: request.left_channel_strip_id,
+            right_channel_strip_id: request.right_channel_strip_id,
+            cross_fader_plugin_id: request.cross_fader_plugin_id,
+            revision: 0,
+        }
+    }
+}
+
+impl PmxScene {
+    fn from(scene: &Scene) -> Self {
+        PmxScene {
+            id: scene.id,
+            name: scene.name.clone(),
+            inputs: scene
+                .inputs
+                .iter()
+                .map(|input| {
+                    let (left, right) = input.pipewire_ports.paths();
+                    PmxSceneInput {
+                        id: input.id,
+                        name: input.name.clone(),
+                        input_type: match input.pipewire_ports {
+                            PipewirePorts::None => PmxInputType::None as i32,
+                            PipewirePorts::Mono(_) => PmxInputType::MonoInput as i32,
+                            PipewirePorts::Stereo(_, _) => PmxInputType::StereoInput as i32,
+                        },
+                        left_port_path: left.map(String::from),
+                        right_port_path: right.map(String::from),
+                        group_channel_strip_name: input.group_channel_strip_name.clone(),
+                    }
+                })
+                .collect(),
+            outputs: scene
+                .outputs
+                .iter()
+                .map(|output| {
+                    let (left, right) = output.pipewire_ports.paths();
+                    PmxSceneOutput {
+                        id: output.id,
+                        name: output.name.clone(),
+                        left_port_path: left.map(String::from),
+                        right_port_path: right.map(String::from),
+                    }
+                })
+                .collect(),
+            revision: scene.revision,
+        }
+    }
+}
+
+impl PmxGroup {
+    fn from(group: Group) -> Self {
+        PmxGroup {
+            name: group.name,
+            channel_strip_id: group.channel_strip_id,
+            input_ids: group.input_ids,
         }
     }
 }
 
+impl pmx::SceneDifference {
+    fn from(difference: SceneDifference) -> Self {
+        pmx::SceneDifference {
+            entity_kind: PmxEntityKind::from(difference.kind) as i32,
+            id: difference.id,
+            field: String::from(difference.field),
+            current: difference.current,
+            scene: difference.scene,
+        }
+    }
+}
+
+impl From<EntityKind> for PmxEntityKind {
+    fn from(kind: EntityKind) -> Self {
+        match kind {
+            EntityKind::Input => PmxEntityKind::Input,
+            EntityKind::Output => PmxEntityKind::Output,
+            EntityKind::Plugin => PmxEntityKind::Plugin,
+            EntityKind::ChannelStrip => PmxEntityKind::ChannelStrip,
+            EntityKind::Looper => PmxEntityKind::Looper,
+            EntityKind::OutputStage => PmxEntityKind::OutputStage,
+            EntityKind::Scene => PmxEntityKind::Scene,
+        }
+    }
+}
+
+impl RegistryChange {
+    fn from(change: &changes::RegistryChange) -> Self {
+        let value = match &change.entity {
+            Entity::Input(input) => registry_change::Value::Input(PmxInput::from(input)),
+            Entity::Output(output) => registry_change::Value::Output(PmxOutput::from(output)),
+            Entity::Plugin(plugin) => registry_change::Value::Plugin(PmxPlugin::from(plugin)),
+            Entity::ChannelStrip(channel_strip) => {
+                registry_change::Value::ChannelStrip(PmxChannelStrip::from(channel_strip))
+            }
+            Entity::Looper(looper) => registry_change::Value::Looper(PmxLooper::from(looper)),
+            Entity::OutputStage(output_stage) => {
+                registry_change::Value::OutputStage(PmxOutputStage::from(output_stage))
+            }
+            Entity::Scene(scene) => registry_change::Value::Scene(PmxScene::from(scene)),
+        };
+        RegistryChange {
+            resume_token: change.resume_token.to_string(),
+            change_type: match change.change_type {
+                ChangeType::Created => PmxChangeType::Created as i32,
+                ChangeType::Updated => PmxChangeType::Updated as i32,
+                ChangeType::Deleted => PmxChangeType::Deleted as i32,
+            },
+            entity_kind: PmxEntityKind::from(change.entity.kind()) as i32,
+            id: change.entity.id(),
+            value: Some(value),
+        }
+    }
+}
+
+impl From<PmxOutputType> for MixerOutputType {
+    fn from(output_type: PmxOutputType) -> Self {
+        match output_type {
+            PmxOutputType::Cue => MixerOutputType::Cue,
+            PmxOutputType::Main => MixerOutputType::Main,
+        }
+    }
+}
+
+/// Who made a mutation, for the journal: the `x-pmx-caller` metadata if the
+/// client set it, otherwise its address.
+fn operation<T>(request: &Request<T>, name: &str) -> Operation {
+    let caller = request
+        .metadata()
+        .get(CALLER_METADATA_KEY)
+        .and_then(|caller| caller.to_str().ok())
+        .map(String::from)
+        .or_else(|| request.remote_addr().map(|address| address.to_string()))
+        .unwrap_or_else(|| String::from("unknown"));
+    Operation {
+        name: String::from(name),
+        caller,
+    }
+}
+
+const CALLER_METADATA_KEY: &str = "x-pmx-caller";
+
+/// Response metadata with a warning about a request that still succeeded,
+/// one UTF-8 value per warning.
+const WARNING_METADATA_KEY: &str = "x-pmx-warning-bin";
+
+fn with_warnings<T>(message: T, warnings: impl IntoIterator<Item = String>) -> Response<T> {
+    let mut response = Response::new(message);
+    for warning in warnings {
+        response.metadata_mut().append_bin(
+            WARNING_METADATA_KEY,
+            MetadataValue::from_bytes(warning.as_bytes()),
+        );
+    }
+    response
+}
+
+/// Number of changes queued for a watcher's connection.
+const WATCH_STREAM_BUFFER: usize = 64;
+
 #[tonic::async_trait]
 impl PmxRegistry for PmxRegistryService {
+    type WatchRegistryStream = ReceiverStream<Result<RegistryChange, Status>>;
+
     async fn list_channel_strips(
         &self,
-        _request: Request<EmptyRequest>,
+        request: Request<ListChannelStripsRequest>,
     ) -> Result<Response<ListChannelStripsReply>, Status> {
+        let request = request.into_inner();
+        let page = validation::page_query(
+            request.order_by,
+            request.descending,
+            request.page_size,
+            &request.page_token,
+        )
+        .map_err(validation::invalid_argument)?;
         let registry = self.registry.read().await;
-        let channel_strips = registry.get_all_channel_strips();
+        let (channel_strips, next_page_token) = page.of(registry.get_all_channel_strips().iter());
 
         Ok(Response::new(ListChannelStripsReply {
             channel_strips: channel_strips
-                .iter()
-                .map(|c| match c.channel_strip_type {
-                    registry::ChannelStripType::Basic {
-                        saturator_plugin_id,
-                        compressor_plugin_id,
-                        equalizer_plugin_id,
-                        gain_plugin_id,
-                    } => PmxChannelStrip {
-                        id: c.id,
-                        name: c.name.clone(),
-                        channel_strip_type: PmxChannelStripType::Basic as i32,
-                        cross_fader_plugin_id: None,
-                        saturator_plugin_id,
-                        compressor_plugin_id,
-                        equalizer_plugin_id,
-                        gain_plugin_id,
-                    },
-                    registry::ChannelStripType::CrossFaded {
-                        cross_fader_plugin_id,
-                        saturator_plugin_id,
-                        compressor_plugin_id,
-                        equalizer_plugin_id,
-                        gain_plugin_id,
-                    } => PmxChannelStrip {
-                        id: c.id,
-                        name: c.name.clone(),
-                        channel_strip_type: PmxChannelStripType::CrossFaded as i32,
-                        cross_fader_plugin_id: Some(cross_fader_plugin_id),
-                        saturator_plugin_id,
-                        compressor_plugin_id,
-                        equalizer_plugin_id,
-                        gain_plugin_id,
-                    },
-                })
+                .into_iter()
+                .map(PmxChannelStrip::from)
                 .collect(),
+            next_page_token: next_page_token.map_or_else(String::new, |token| token.to_string()),
         }))
     }
 
     async fn list_inputs(
         &self,
-        _request: Request<EmptyRequest>,
+        request: Request<ListInputsRequest>,
     ) -> Result<Response<ListInputsReply>, Status> {
+        let (filter, page) =
+            validation::input_query(request.into_inner()).map_err(validation::invalid_argument)?;
         let registry = self.registry.read().await;
-        let inputs = registry.get_all_inputs().iter().map(PmxInput::from);
+        let inputs = registry
+            .get_all_inputs()
+            .iter()
+            .filter(|input| filter.matches(input));
+        let (inputs, next_page_token) = page.of(inputs);
 
         Ok(Response::new(ListInputsReply {
-            inputs: inputs.collect(),
+            inputs: inputs.into_iter().map(PmxInput::from).collect(),
+            next_page_token: next_page_token.map_or_else(String::new, |token| token.to_string()),
         }))
     }
 
     async fn get_input(&self, request: Request<ByIdRequest>) -> Result<Response<PmxInput>, Status> {
         let id = request.into_inner().id;
         let registry = self.registry.read().await;
-        match registry.input_by_id(id) {
-            Some(input) => Ok(Response::new(PmxInput::from(input))),
-            None => Err(Status::not_found(format!(
-                "Couldn't find input with id: {id}"
-            ))),
-        }
+        let input = registry.input_by_id(id)?;
+        Ok(Response::new(PmxInput::from(input)))
     }
 
     async fn update_input_name(
         &self,
         request: Request<UpdateInputNameRequest>,
     ) -> Result<Response<PmxInput>, Status> {
+        let operation = operation(&request, "UpdateInputName");
         let inner = request.into_inner();
         let id = inner.id;
         let name = inner.name;
+        validation::name(&name).map_err(validation::invalid_argument)?;
         let mut registry = self.registry.write().await;
-        match registry.update_input_name(id, name.as_str()) {
-            Ok(_) => {
-                let input = registry.input_by_id(id).unwrap();
-                Ok(Response::new(PmxInput::from(input)))
-            }
-            Err(_) => Err(Status::not_found(format!(
-                "Couldn't find input with id: {id}"
-            ))),
-        }
+        registry
+            .apply(&operation, |registry| {
+                registry.update_input_name(id, name.as_str(), inner.expected_revision)
+            })
+            .await?;
+        let input = registry.input_by_id(id)?;
+        Ok(Response::new(PmxInput::from(input)))
     }
 
     async fn update_input_port_assignments(
         &self,
         request: Request<UpdateInputPortAssignmentsRequest>,
     ) -> Result<Response<PmxInput>, Status> {
+        let operation = operation(&request, "UpdateInputPortAssignments");
         let inner = request.into_inner();
         let id = inner.id;
-        let left_path = inner.left_port_path;
-        let right_path = inner.right_port_path;
-        match inner.input_type {
-            x if x == PmxInputType::MonoInput as i32 => {
-                let mut registry = self.registry.write().await;
-                match registry.update_input_ports(id, PipewirePorts::Mono(left_path.unwrap())) {
-                    Ok(_) => {
-                        let input = registry.input_by_id(id).unwrap();
-                        Ok(Response::new(PmxInput::from(input)))
-                    }
-                    Err(_) => Err(Status::not_found(format!(
-                        "Couldn't find input with id: {id}"
-                    ))),
-                }
-            }
-            x if x == PmxInputType::StereoInput as i32 => {
-                let mut registry = self.registry.write().await;
-                match registry.update_input_ports(
-                    id,
-                    PipewirePorts::Stereo(left_path.unwrap(), right_path.unwrap()),
-                ) {
-                    Ok(_) => {
-                        let input = registry.input_by_id(id).unwrap();
-                        Ok(Response::new(PmxInput::from(input)))
-                    }
-                    Err(_) => Err(Status::not_found(format!(
-                        "Couldn't find input with id: {id}"
-                    ))),
-                }
-            }
-            x if x == PmxInputType::None as i32 => {
-                let mut registry = self.registry.write().await;
-                match registry.update_input_ports(id, PipewirePorts::None) {
-                    Ok(_) => {
-                        let input = registry.input_by_id(id).unwrap();
-                        Ok(Response::new(PmxInput::from(input)))
-                    }
-                    Err(_) => Err(Status::not_found(format!(
-                        "Couldn't find input with id: {id}"
-                    ))),
-                }
-            }
-            _ => Err(Status::invalid_argument("invalid input type code")),
-        }
+        let pipewire_ports =
+            validation::input_ports(&inner).map_err(validation::invalid_argument)?;
+        let warning = port_path::warning(&pipewire_ports);
+        let mut registry = self.registry.write().await;
+        registry
+            .apply(&operation, |registry| {
+                registry.update_input_ports(id, pipewire_ports, inner.expected_revision)
+            })
+            .await?;
+        let input = registry.input_by_id(id)?;
+        Ok(with_warnings(PmxInput::from(input), warning))
+    }
+
+    async fn update_input_group(
+        &self,
+        request: Request<UpdateInputGroupRequest>,
+    ) -> Result<Response<PmxInput>, Status> {
+        let operation = operation(&request, "UpdateInputGroup");
+        let inner = request.into_inner();
+        validation::input_group(&inner).map_err(validation::invalid_argument)?;
+        let mut registry = self.registry.write().await;
+        registry
+            .apply(&operation, |registry| {
+                registry.update_input_group(
+                    inner.id,
+                    &inner.group_channel_strip_name,
+                    inner.expected_revision,
+                )
+            })
+            .await?;
+        let input = registry.input_by_id(inner.id)?;
+        Ok(Response::new(PmxInput::from(input)))
+    }
+
+    async fn add_input(
+        &self,
+        request: Request<AddInputRequest>,
+    ) -> Result<Response<PmxInput>, Status> {
+        let operation = operation(&request, "AddInput");
+        let inner = request.into_inner();
+        validation::name(&inner.name).map_err(validation::invalid_argument)?;
+        let mut registry = self.registry.write().await;
+        let id = registry
+            .apply(&operation, |registry| {
+                registry.add_input(&inner.name, &inner.group_channel_strip_name)
+            })
+            .await?;
+        let input = registry.input_by_id(id)?;
+        Ok(Response::new(PmxInput::from(input)))
+    }
+
+    async fn remove_input(
+        &self,
+        request: Request<ByIdRequest>,
+    ) -> Result<Response<PmxInput>, Status> {
+        let operation = operation(&request, "RemoveInput");
+        let id = request.into_inner().id;
+        let mut registry = self.registry.write().await;
+        let input = registry
+            .apply(&operation, |registry| registry.remove_input(id))
+            .await?;
+        Ok(Response::new(PmxInput::from(&input)))
     }
 
     async fn register_channel_strip(
         &self,
         request: Request<RegisterChannelStripRequest>,
     ) -> Result<Response<PmxChannelStrip>, Status> {
+        let operation = operation(&request, "RegisterChannelStrip");
+        let inner = request.into_inner();
+        let channel_strip_to_register = validation::required(inner.channel_strip, "channel_strip")
+            .map_err(validation::invalid_argument)?;
+        validation::channel_strip(&channel_strip_to_register, "channel_strip")
+            .map_err(validation::invalid_argument)?;
         let mut registry = self.registry.write().await;
-        let channel_strip_to_register = request.into_inner().channel_strip.unwrap();
-        let id = channel_strip_to_register.id;
-        registry.register_channel_strip(channel_strip_to_register);
-        if let Some(channel_strip) = registry.get_channel_strip_by_id(id) {
-            Ok(Response::new(match channel_strip.channel_strip_type {
-                registry::ChannelStripType::Basic {
-                    saturator_plugin_id,
-                    compressor_plugin_id,
-                    equalizer_plugin_id,
-                    gain_plugin_id,
-                } => PmxChannelStrip {
-                    id: channel_strip.id,
-                    name: channel_strip.name.clone(),
-                    channel_strip_type: PmxChannelStripType::Basic as i32,
-                    cross_fader_plugin_id: None,
-                    saturator_plugin_id,
-                    compressor_plugin_id,
-                    equalizer_plugin_id,
-                    gain_plugin_id,
-                },
-                registry::ChannelStripType::CrossFaded {
-                    cross_fader_plugin_id,
-                    saturator_plugin_id,
-                    compressor_plugin_id,
-                    equalizer_plugin_id,
-                    gain_plugin_id,
-                } => PmxChannelStrip {
-                    id: channel_strip.id,
-                    name: channel_strip.name.clone(),
-                    channel_strip_type: PmxChannelStripType::CrossFaded as i32,
-                    cross_fader_plugin_id: Some(cross_fader_plugin_id),
-                    saturator_plugin_id,
-                    compressor_plugin_id,
-                    equalizer_plugin_id,
-                    gain_plugin_id,
-                },
-            }))
-        } else {
-            Err(Status::not_found("Channel Strip wasn't registered"))
-        }
+        let id = registry
+            .apply(&operation, |registry| {
+                registry.register_channel_strip(
+                    channel_strip_to_register,
+                    inner.idempotency_token.as_deref(),
+                )
+            })
+            .await?;
+        let channel_strip = registry.get_channel_strip_by_id(id)?;
+        Ok(Response::new(PmxChannelStrip::from(channel_strip)))
+    }
+
+    async fn get_channel_strip(
+        &self,
+        request: Request<ByIdRequest>,
+    ) -> Result<Response<PmxChannelStrip>, Status> {
+        let id = request.into_inner().id;
+        let registry = self.registry.read().await;
+        let channel_strip = registry.get_channel_strip_by_id(id)?;
+        Ok(Response::new(PmxChannelStrip::from(channel_strip)))
+    }
+
+    async fn update_channel_strip(
+        &self,
+        request: Request<UpdateChannelStripRequest>,
+    ) -> Result<Response<PmxChannelStrip>, Status> {
+        let operation = operation(&request, "UpdateChannelStrip");
+        let inner = request.into_inner();
+        let channel_strip = validation::required(inner.channel_strip, "channel_strip")
+            .map_err(validation::invalid_argument)?;
+        validation::channel_strip(&channel_strip, "channel_strip")
+            .map_err(validation::invalid_argument)?;
+        let id = channel_strip.id;
+        let mut registry = self.registry.write().await;
+        registry
+            .apply(&operation, |registry| {
+                registry.update_channel_strip(channel_strip, inner.expected_revision)
+            })
+            .await?;
+        let channel_strip = registry.get_channel_strip_by_id(id)?;
+        Ok(Response::new(PmxChannelStrip::from(channel_strip)))
+    }
+
+    async fn unregister_channel_strip(
+        &self,
+        request: Request<UnregisterRequest>,
+    ) -> Result<Response<PmxChannelStrip>, Status> {
+        let operation = operation(&request, "UnregisterChannelStrip");
+        let UnregisterRequest { id, cascade } = request.into_inner();
+        let mut registry = self.registry.write().await;
+        let channel_strip = registry
+            .apply(&operation, |registry| {
+                registry.unregister_channel_strip(id, cascade)
+            })
+            .await?;
+        Ok(Response::new(PmxChannelStrip::from(&channel_strip)))
     }
 
     async fn register_plugin(
         &self,
         request: Request<RegisterPluginRequest>,
     ) -> Result<Response<PmxPlugin>, Status> {
+        let operation = operation(&request, "RegisterPlugin");
+        let inner = request.into_inner();
+        let plugin_to_register =
+            validation::required(inner.plugin, "plugin").map_err(validation::invalid_argument)?;
+        validation::nested_name(&plugin_to_register.name, "plugin")
+            .map_err(validation::invalid_argument)?;
         let mut registry = self.registry.write().await;
-        let plugin_to_register = request.into_inner().plugin.unwrap();
-        let id = plugin_to_register.id;
-        registry.register_plugin(plugin_to_register);
-        if let Some(plugin) = registry.get_plugin_by_id(id) {
-            Ok(Response::new(PmxPlugin {
-                id: plugin.id,
-                mod_host_id: plugin.mod_host_id,
-                name: plugin.name.clone(),
-                plugin_uri: plugin.plugin_uri.clone(),
-                plugin_type: match plugin.plugin_type {
-                    registry::PluginType::Lv2 => PmxPluginType::Lv2 as i32,
-                },
-            }))
-        } else {
-            Err(Status::not_found(String::from("Plugin was not created")))
-        }
+        let id = registry
+            .apply(&operation, |registry| {
+                registry.register_plugin(plugin_to_register, inner.idempotency_token.as_deref())
+            })
+            .await?;
+        let plugin = registry.get_plugin_by_id(id)?;
+        Ok(Response::new(PmxPlugin::from(plugin)))
     }
 
     async fn list_plugins(
         &self,
-        _request: Request<EmptyRequest>,
+        request: Request<ListPluginsRequest>,
     ) -> Result<Response<ListPluginsReply>, Status> {
+        let (filter, page) =
+            validation::plugin_query(request.into_inner()).map_err(validation::invalid_argument)?;
         let registry = self.registry.read().await;
-        let plugins = registry.get_all_plugins();
+        let plugins = registry
+            .get_all_plugins()
+            .iter()
+            .filter(|plugin| filter.matches(plugin));
+        let (plugins, next_page_token) = page.of(plugins);
+
         Ok(Response::new(ListPluginsReply {
-            plugins: plugins
-                .iter()
-                .map(|p| PmxPlugin {
-                    id: p.id,
-                    mod_host_id: p.mod_host_id,
-                    name: p.name.clone(),
-                    plugin_uri: p.plugin_uri.clone(),
-                    plugin_type: match p.plugin_type {
-                        registry::PluginType::Lv2 => PmxPluginType::Lv2 as i32,
-                    },
-                })
-                .collect(),
+            plugins: plugins.into_iter().map(PmxPlugin::from).collect(),
+            next_page_token: next_page_token.map_or_else(String::new, |token| token.to_string()),
         }))
     }
 
+    async fn get_plugin(
+        &self,
+        request: Request<ByIdRequest>,
+    ) -> Result<Response<PmxPlugin>, Status> {
+        let id = request.into_inner().id;
+        let registry = self.registry.read().await;
+        let plugin = registry.get_plugin_by_id(id)?;
+        Ok(Response::new(PmxPlugin::from(plugin)))
+    }
+
+    async fn update_plugin(
+        &self,
+        request: Request<UpdatePluginRequest>,
+    ) -> Result<Response<PmxPlugin>, Status> {
+        let operation = operation(&request, "UpdatePlugin");
+        let inner = request.into_inner();
+        let plugin =
+            validation::required(inner.plugin, "plugin").map_err(validation::invalid_argument)?;
+        validation::nested_name(&plugin.name, "plugin").map_err(validation::invalid_argument)?;
+        let id = plugin.id;
+        let mut registry = self.registry.write().await;
+        registry
+            .apply(&operation, |registry| {
+                registry.update_plugin(plugin, inner.expected_revision)
+            })
+            .await?;
+        let plugin = registry.get_plugin_by_id(id)?;
+        Ok(Response::new(PmxPlugin::from(plugin)))
+    }
+
+    async fn unregister_plugin(
+        &self,
+        request: Request<UnregisterRequest>,
+    ) -> Result<Response<PmxPlugin>, Status> {
+        let operation = operation(&request, "UnregisterPlugin");
+        let UnregisterRequest { id, cascade } = request.into_inner();
+        let mut registry = self.registry.write().await;
+        let plugin = registry
+            .apply(&operation, |registry| {
+                registry.unregister_plugin(id, cascade)
+            })
+            .await?;
+        Ok(Response::new(PmxPlugin::from(&plugin)))
+    }
+
     async fn register_looper(
         &self,
         request: Request<RegisterLooperRequest>,
     ) -> Result<Response<PmxLooper>, Status> {
+        let operation = operation(&request, "RegisterLooper");
         let inner = request.into_inner();
         let mut registry = self.registry.write().await;
-        registry.register_looper(PmxLooper {
-            id: inner.loop_number,
-            name: format!("loop_{}", inner.loop_number),
-            loop_number: inner.loop_number,
-        });
-        let looper = registry.get_looper_by_id(inner.loop_number).unwrap();
-        Ok(Response::new(PmxLooper {
-            id: looper.id,
-            name: looper.name.clone(),
-            loop_number: looper.loop_number,
-        }))
+        let id = registry
+            .apply(&operation, |registry| {
+                registry.register_looper(
+                    PmxLooper::registered(&inner),
+                    inner.idempotency_token.as_deref(),
+                )
+            })
+            .await?;
+        let looper = registry.get_looper_by_id(id)?;
+        Ok(Response::new(PmxLooper::from(looper)))
     }
 
     async fn list_loopers(
         &self,
-        _request: Request<EmptyRequest>,
+        request: Request<ListLoopersRequest>,
     ) -> Result<Response<ListLoopersReply>, Status> {
+        let request = request.into_inner();
+        let page = validation::page_query(
+            request.order_by,
+            request.descending,
+            request.page_size,
+            &request.page_token,
+        )
+        .map_err(validation::invalid_argument)?;
         let registry = self.registry.read().await;
-        let loopers = registry.get_all_loopers();
+        let (loopers, next_page_token) = page.of(registry.get_all_loopers().iter());
+
         Ok(Response::new(ListLoopersReply {
-            loopers: loopers
-                .iter()
-                .map(|l| PmxLooper {
-                    id: l.id,
-                    name: l.name.clone(),
-                    loop_number: l.loop_number,
-                })
-                .collect(),
+            loopers: loopers.into_iter().map(PmxLooper::from).collect(),
+            next_page_token: next_page_token.map_or_else(String::new, |token| token.to_string()),
         }))
     }
 
+    async fn get_looper(
+        &self,
+        request: Request<ByIdRequest>,
+    ) -> Result<Response<PmxLooper>, Status> {
+        let id = request.into_inner().id;
+        let registry = self.registry.read().await;
+        let looper = registry.get_looper_by_id(id)?;
+        Ok(Response::new(PmxLooper::from(looper)))
+    }
+
+    async fn update_looper(
+        &self,
+        request: Request<UpdateLooperRequest>,
+    ) -> Result<Response<PmxLooper>, Status> {
+        let operation = operation(&request, "UpdateLooper");
+        let inner = request.into_inner();
+        let looper =
+            validation::required(inner.looper, "looper").map_err(validation::invalid_argument)?;
+        validation::nested_name(&looper.name, "looper").map_err(validation::invalid_argument)?;
+        let id = looper.id;
+        let mut registry = self.registry.write().await;
+        registry
+            .apply(&operation, |registry| {
+                registry.update_looper(looper, inner.expected_revision)
+            })
+            .await?;
+        let looper = registry.get_looper_by_id(id)?;
+        Ok(Response::new(PmxLooper::from(looper)))
+    }
+
+    async fn unregister_looper(
+        &self,
+        request: Request<ByIdRequest>,
+    ) -> Result<Response<PmxLooper>, Status> {
+        let operation = operation(&request, "UnregisterLooper");
+        let id = request.into_inner().id;
+        let mut registry = self.registry.write().await;
+        let looper = registry
+            .apply(&operation, |registry| registry.unregister_looper(id))
+            .await?;
+        Ok(Response::new(PmxLooper::from(&looper)))
+    }
+
     async fn list_outputs(
         &self,
-        _request: Request<EmptyRequest>,
+        request: Request<ListOutputsRequest>,
     ) -> Result<Response<ListOutputsReply>, Status> {
+        let (filter, page) =
+            validation::output_query(request.into_inner()).map_err(validation::invalid_argument)?;
         let registry = self.registry.read().await;
-        let outputs = registry.get_all_outputs();
+        let outputs = registry
+            .get_all_outputs()
+            .iter()
+            .filter(|output| filter.matches(output));
+        let (outputs, next_page_token) = page.of(outputs);
+
         Ok(Response::new(ListOutputsReply {
-            outputs: outputs
-                .iter()
-                .map(|o| PmxOutput {
-                    id: o.id,
-                    name: o.name.clone(),
-                    output_type: match o.output_type {
-                        registry::MixerOutputType::Cue => PmxOutputType::Cue as i32,
-                        registry::MixerOutputType::Main => PmxOutputType::Main as i32,
-                    },
-                    left_port_path: match o.pipewire_ports.clone() {
-                        PipewirePorts::None => None,
-                        PipewirePorts::Mono(left) => Some(left),
-                        PipewirePorts::Stereo(left, _) => Some(left),
-                    },
-                    right_port_path: match o.pipewire_ports.clone() {
-                        PipewirePorts::None => None,
-                        PipewirePorts::Mono(left) => Some(left),
-                        PipewirePorts::Stereo(_, right) => Some(right),
-                    },
-                })
-                .collect(),
+            outputs: outputs.into_iter().map(PmxOutput::from).collect(),
+            next_page_token: next_page_token.map_or_else(String::new, |token| token.to_string()),
         }))
     }
 
+    async fn get_output(
+        &self,
+        request: Request<ByIdRequest>,
+    ) -> Result<Response<PmxOutput>, Status> {
+        let id = request.into_inner().id;
+        let registry = self.registry.read().await;
+        let output = registry.output_by_id(id)?;
+        Ok(Response::new(PmxOutput::from(output)))
+    }
+
+    async fn add_output(
+        &self,
+        request: Request<AddOutputRequest>,
+    ) -> Result<Response<PmxOutput>, Status> {
+        let operation = operation(&request, "AddOutput");
+        let inner = request.into_inner();
+        validation::name(&inner.name).map_err(validation::invalid_argument)?;
+        let output_type = MixerOutputType::from(
+            validation::output_type(inner.output_type, "output_type")
+                .map_err(validation::invalid_argument)?,
+        );
+        let mut registry = self.registry.write().await;
+        let id = registry
+            .apply(&operation, |registry| {
+                registry.add_output(&inner.name, output_type)
+            })
+            .await?;
+        let output = registry.output_by_id(id)?;
+        Ok(Response::new(PmxOutput::from(output)))
+    }
+
+    async fn update_output_name(
+        &self,
+        request: Request<UpdateOutputNameRequest>,
+    ) -> Result<Response<PmxOutput>, Status> {
+        let operation = operation(&request, "UpdateOutputName");
+        let inner = request.into_inner();
+        validation::name(&inner.name).map_err(validation::invalid_argument)?;
+        let id = inner.id;
+        let mut registry = self.registry.write().await;
+        registry
+            .apply(&operation, |registry| {
+                registry.update_output_name(id, &inner.name, inner.expected_revision)
+            })
+            .await?;
+        let output = registry.output_by_id(id)?;
+        Ok(Response::new(PmxOutput::from(output)))
+    }
+
+    async fn update_output_type(
+        &self,
+        request: Request<UpdateOutputTypeRequest>,
+    ) -> Result<Response<PmxOutput>, Status> {
+        let operation = operation(&request, "UpdateOutputType");
+        let inner = request.into_inner();
+        let id = inner.id;
+        let output_type = MixerOutputType::from(
+            validation::output_type(inner.output_type, "output_type")
+                .map_err(validation::invalid_argument)?,
+        );
+        let mut registry = self.registry.write().await;
+        registry
+            .apply(&operation, |registry| {
+                registry.update_output_type(id, output_type, inner.expected_revision)
+            })
+            .await?;
+        let output = registry.output_by_id(id)?;
+        Ok(Response::new(PmxOutput::from(output)))
+    }
+
+    async fn remove_output(
+        &self,
+        request: Request<ByIdRequest>,
+    ) -> Result<Response<PmxOutput>, Status> {
+        let operation = operation(&request, "RemoveOutput");
+        let id = request.into_inner().id;
+        let mut registry = self.registry.write().await;
+        let output = registry
+            .apply(&operation, |registry| registry.remove_output(id))
+            .await?;
+        Ok(Response::new(PmxOutput::from(&output)))
+    }
+
     async fn update_output_port_assignments(
         &self,
         request: Request<UpdateOutputPortAssignmentsRequest>,
     ) -> Result<Response<PmxOutput>, Status> {
+        let operation = operation(&request, "UpdateOutputPortAssignments");
         let inner = request.into_inner();
-        let pipewire_ports = match (inner.left_port_path, inner.right_port_path) {
-            (None, None) => PipewirePorts::None,
-            (None, Some(right)) => PipewirePorts::Mono(right),
-            (Some(left), None) => PipewirePorts::Mono(left),
-            (Some(left), Some(right)) => PipewirePorts::Stereo(left, right),
-        };
+        let pipewire_ports = validation::output_ports(inner.left_port_path, inner.right_port_path)
+            .map_err(validation::invalid_argument)?;
+        let warning = port_path::warning(&pipewire_ports);
         let mut registry = self.registry.write().await;
         registry
-            .update_output_ports(inner.id, pipewire_ports)
-            .unwrap();
-        let output = registry.output_by_id(inner.id).unwrap();
-        Ok(Response::new(PmxOutput {
-            id: output.id,
-            name: output.name.clone(),
-            output_type: match output.output_type {
-                registry::MixerOutputType::Cue => PmxOutputType::Cue as i32,
-                registry::MixerOutputType::Main => PmxOutputType::Main as i32,
-            },
-            left_port_path: match &output.pipewire_ports {
-                PipewirePorts::None => None,
-                PipewirePorts::Mono(left) => Some(left.clone()),
-                PipewirePorts::Stereo(left, _) => Some(left.clone()),
-            },
-            right_port_path: match &output.pipewire_ports {
-                PipewirePorts::None => None,
-                PipewirePorts::Mono(left) => Some(left.clone()),
-                PipewirePorts::Stereo(_, right) => Some(right.clone()),
-            },
-        }))
+            .apply(&operation, |registry| {
+                registry.update_output_ports(inner.id, pipewire_ports, inner.expected_revision)
+            })
+            .await?;
+        let output = registry.output_by_id(inner.id)?;
+        Ok(with_warnings(PmxOutput::from(output), warning))
     }
 
     async fn register_output_stage(
         &self,
         request: Request<RegisterOutputStageRequest>,
     ) -> Result<Response<PmxOutputStage>, Status> {
+        let operation = operation(&request, "RegisterOutputStage");
         let inner = request.into_inner();
+        validation::name(&inner.name).map_err(validation::invalid_argument)?;
         let mut registry = self.registry.write().await;
-        registry.register_output_stage(PmxOutputStage {
-            id: 0,
-            name: inner.name.clone(),
-            left_channel_strip_id: inner.left_channel_strip_id,
-            right_channel_strip_id: inner.right_channel_strip_id,
-            cross_fader_plugin_id: inner.cross_fader_plugin_id,
-        });
-        Ok(Response::new(PmxOutputStage {
-            id: 0,
-            name: inner.name,
-            left_channel_strip_id: inner.left_channel_strip_id,
-            right_channel_strip_id: inner.right_channel_strip_id,
-            cross_fader_plugin_id: inner.cross_fader_plugin_id,
-        }))
+        let id = registry
+            .apply(&operation, |registry| {
+                registry.register_output_stage(
+                    PmxOutputStage::registered(&inner),
+                    inner.idempotency_token.as_deref(),
+                )
+            })
+            .await?;
+        let output_stage = registry.get_output_stage_by_id(id)?;
+        Ok(Response::new(PmxOutputStage::from(output_stage)))
     }
 
     async fn list_output_stages(
         &self,
-        _request: Request<EmptyRequest>,
+        request: Request<ListOutputStagesRequest>,
     ) -> Result<Response<ListOutputStagesReply>, Status> {
+        let request = request.into_inner();
+        let page = validation::page_query(
+            request.order_by,
+            request.descending,
+            request.page_size,
+            &request.page_token,
+        )
+        .map_err(validation::invalid_argument)?;
         let registry = self.registry.read().await;
-        let output_stages = registry.get_all_output_stages();
+        let (output_stages, next_page_token) = page.of(registry.get_all_output_stages().iter());
+
         Ok(Response::new(ListOutputStagesReply {
             output_stages: output_stages
-                .iter()
-                .map(|o| PmxOutputStage {
-                    id: o.id,
-                    name: o.name.clone(),
-                    left_channel_strip_id: o.left_channel_strip_id,
-                    right_channel_strip_id: o.right_channel_strip_id,
-                    cross_fader_plugin_id: o.cross_fader_plugin_id,
-                })
+                .into_iter()
+                .map(PmxOutputStage::from)
                 .collect(),
+            next_page_token: next_page_token.map_or_else(String::new, |token| token.to_string()),
         }))
     }
+
+    async fn get_output_stage(
+        &self,
+        request: Request<ByIdRequest>,
+    ) -> Result<Response<PmxOutputStage>, Status> {
+        let id = request.into_inner().id;
+        let registry = self.registry.read().await;
+        let output_stage = registry.get_output_stage_by_id(id)?;
+        Ok(Response::new(PmxOutputStage::from(output_stage)))
+    }
+
+    async fn update_output_stage(
+        &self,
+        request: Request<UpdateOutputStageRequest>,
+    ) -> Result<Response<PmxOutputStage>, Status> {
+        let operation = operation(&request, "UpdateOutputStage");
+        let inner = request.into_inner();
+        let output_stage = validation::required(inner.output_stage, "output_stage")
+            .map_err(validation::invalid_argument)?;
+        validation::nested_name(&output_stage.name, "output_stage")
+            .map_err(validation::invalid_argument)?;
+        let id = output_stage.id;
+        let mut registry = self.registry.write().await;
+        registry
+            .apply(&operation, |registry| {
+                registry.update_output_stage(output_stage, inner.expected_revision)
+            })
+            .await?;
+        let output_stage = registry.get_output_stage_by_id(id)?;
+        Ok(Response::new(PmxOutputStage::from(output_stage)))
+    }
+
+    async fn unregister_output_stage(
+        &self,
+        request: Request<ByIdRequest>,
+    ) -> Result<Response<PmxOutputStage>, Status> {
+        let operation = operation(&request, "UnregisterOutputStage");
+        let id = request.into_inner().id;
+        let mut registry = self.registry.write().await;
+        let output_stage = registry
+            .apply(&operation, |registry| registry.unregister_output_stage(id))
+            .await?;
+        Ok(Response::new(PmxOutputStage::from(&output_stage)))
+    }
+
+    async fn apply_batch(
+        &self,
+        request: Request<ApplyBatchRequest>,
+    ) -> Result<Response<ApplyBatchReply>, Status> {
+        let operation = operation(&request, "ApplyBatch");
+        let mutations = validation::batch(request.into_inner().mutations)
+            .map_err(validation::invalid_argument)?;
+        let warnings: Vec<String> = mutations
+            .iter()
+            .enumerate()
+            .filter_map(|(index, mutation)| match mutation {
+                Mutation::UpdateInputPorts { ports, .. }
+                | Mutation::UpdateOutputPorts { ports, .. } => port_path::warning(ports)
+                    .map(|warning| format!("mutations[{index}]: {warning}")),
+                _ => None,
+            })
+            .collect();
+        let mut registry = self.registry.write().await;
+        let ids = registry
+            .apply(&operation, |registry| registry.apply_batch(mutations))
+            .await?;
+        Ok(with_warnings(ApplyBatchReply { ids }, warnings))
+    }
+
+    async fn save_scene(
+        &self,
+        request: Request<SaveSceneRequest>,
+    ) -> Result<Response<PmxScene>, Status> {
+        let operation = operation(&request, "SaveScene");
+        let name = request.into_inner().name;
+        validation::name(&name).map_err(validation::invalid_argument)?;
+        let mut registry = self.registry.write().await;
+        registry
+            .apply(&operation, |registry| registry.save_scene(&name))
+            .await?;
+        let scene = registry.scene_by_name(&name)?;
+        Ok(Response::new(PmxScene::from(scene)))
+    }
+
+    async fn list_scenes(
+        &self,
+        _request: Request<EmptyRequest>,
+    ) -> Result<Response<ListScenesReply>, Status> {
+        let registry = self.registry.read().await;
+        let scenes = registry.get_all_scenes().iter().map(PmxScene::from);
+
+        Ok(Response::new(ListScenesReply {
+            scenes: scenes.collect(),
+        }))
+    }
+
+    async fn recall_scene(
+        &self,
+        request: Request<SceneByNameRequest>,
+    ) -> Result<Response<RecallSceneReply>, Status> {
+        let operation = operation(&request, "RecallScene");
+        let name = request.into_inner().name;
+        let mut registry = self.registry.write().await;
+        let recall = registry
+            .apply(&operation, |registry| registry.recall_scene(&name))
+            .await?;
+        let scene = registry.scene_by_name(&name)?;
+        Ok(Response::new(RecallSceneReply {
+            scene: Some(PmxScene::from(scene)),
+            missing_input_ids: recall.missing_input_ids,
+            missing_output_ids: recall.missing_output_ids,
+            missing_groups: recall.missing_groups,
+        }))
+    }
+
+    async fn delete_scene(
+        &self,
+        request: Request<SceneByNameRequest>,
+    ) -> Result<Response<PmxScene>, Status> {
+        let operation = operation(&request, "DeleteScene");
+        let name = request.into_inner().name;
+        let mut registry = self.registry.write().await;
+        let scene = registry
+            .apply(&operation, |registry| registry.delete_scene(&name))
+            .await?;
+        Ok(Response::new(PmxScene::from(&scene)))
+    }
+
+    async fn diff_scene(
+        &self,
+        request: Request<SceneByNameRequest>,
+    ) -> Result<Response<DiffSceneReply>, Status> {
+        let name = request.into_inner().name;
+        let registry = self.registry.read().await;
+        let differences = registry.diff_scene(&name)?;
+        Ok(Response::new(DiffSceneReply {
+            differences: differences
+                .into_iter()
+                .map(pmx::SceneDifference::from)
+                .collect(),
+        }))
+    }
+
+    async fn list_groups(
+        &self,
+        _request: Request<EmptyRequest>,
+    ) -> Result<Response<ListGroupsReply>, Status> {
+        let registry = self.registry.read().await;
+        let groups = registry.get_all_groups().into_iter().map(PmxGroup::from);
+
+        Ok(Response::new(ListGroupsReply {
+            groups: groups.collect(),
+        }))
+    }
+
+    async fn rename_group(
+        &self,
+        request: Request<RenameGroupRequest>,
+    ) -> Result<Response<PmxGroup>, Status> {
+        let operation = operation(&request, "RenameGroup");
+        let inner = request.into_inner();
+        validation::rename_group(&inner).map_err(validation::invalid_argument)?;
+        let mut registry = self.registry.write().await;
+        registry
+            .apply(&operation, |registry| {
+                registry.rename_group(&inner.name, &inner.new_name)
+            })
+            .await?;
+        let group = registry.group_by_name(&inner.new_name)?;
+        Ok(Response::new(PmxGroup::from(group)))
+    }
+
+    async fn move_inputs_to_group(
+        &self,
+        request: Request<MoveInputsToGroupRequest>,
+    ) -> Result<Response<PmxGroup>, Status> {
+        let operation = operation(&request, "MoveInputsToGroup");
+        let inner = request.into_inner();
+        validation::move_inputs_to_group(&inner).map_err(validation::invalid_argument)?;
+        let mut registry = self.registry.write().await;
+        registry
+            .apply(&operation, |registry| {
+                registry.move_inputs_to_group(&inner.input_ids, &inner.group)
+            })
+            .await?;
+        let group = registry.group_by_name(&inner.group)?;
+        Ok(Response::new(PmxGroup::from(group)))
+    }
+
+    async fn undo(&self, request: Request<EmptyRequest>) -> Result<Response<HistoryReply>, Status> {
+        let operation = operation(&request, "Undo");
+        self.step_history(operation, HistoryDirection::Undo).await
+    }
+
+    async fn redo(&self, request: Request<EmptyRequest>) -> Result<Response<HistoryReply>, Status> {
+        let operation = operation(&request, "Redo");
+        self.step_history(operation, HistoryDirection::Redo).await
+    }
+
+    async fn get_persistence_status(
+        &self,
+        _request: Request<EmptyRequest>,
+    ) -> Result<Response<PersistenceStatusReply>, Status> {
+        let status = self.registry.read().await.persistence_status();
+        Ok(Response::new(PersistenceStatusReply {
+            write_pending: status.write_pending,
+            failed_attempts: status.failed_attempts,
+            last_error: status.last_error,
+        }))
+    }
+
+    async fn watch_registry(
+        &self,
+        request: Request<WatchRegistryRequest>,
+    ) -> Result<Response<Self::WatchRegistryStream>, Status> {
+        let resume_token = validation::resume_token(request.into_inner().resume_token)
+            .map_err(validation::invalid_argument)?;
+        let (missed, mut receiver) = self.registry.read().await.watch(resume_token)?;
+        let (sender, stream_receiver) = tokio::sync::mpsc::channel(WATCH_STREAM_BUFFER);
+        let mut shutting_down = self.shutting_down.clone();
+        tokio::spawn(async move {
+            for change in &missed {
+                if sender.send(Ok(RegistryChange::from(change))).await.is_err() {
+                    return;
+                }
+            }
+            loop {
+                let received = tokio::select! {
+                    received = receiver.recv() => received,
+                    _ = shutting_down.wait_for(|shutting_down| *shutting_down) => return,
+                };
+                let item = match received {
+                    Ok(change) => Ok(RegistryChange::from(&change)),
+                    Err(RecvError::Lagged(_)) => Err(Status::aborted(
+                        "watcher fell behind; resume from the last change received",
+                    )),
+                    Err(RecvError::Closed) => return,
+                };
+                let lagged = item.is_err();
+                if sender.send(item).await.is_err() || lagged {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(stream_receiver)))
+    }
+}
+
+/// What to do when the data files exist but can't be read or parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CorruptDataPolicy {
+    /// Exit with an error and leave the files untouched.
+    Refuse,
+    /// Move the unreadable files aside and start from the template.
+    Quarantine,
+    /// Serve the last state that can still be read without ever writing the
+    /// data files: the snapshot if it parses and the template otherwise,
+    /// never with the journal replayed onto it. Mutations fail with
+    /// READ_ONLY and the unreadable files stay as they are for inspection.
+    ReadOnly,
+}
+
+#[derive(Parser)]
+#[command(version, about, long_about=None)]
+struct Arguments {
+    #[arg(long, value_enum, default_value_t = CorruptDataPolicy::Refuse)]
+    on_corrupt_data: CorruptDataPolicy,
+    /// Seeds an empty install. Either a path to a template file or the name
+    /// of a template in the `templates` directory next to the data file.
+    #[arg(long)]
+    template: Option<String>,
+    /// How long the registry has to go without a change before the data file
+    /// is written, so a burst of changes results in a single write.
+    #[arg(long, default_value_t = 500)]
+    persist_interval_ms: u64,
+    /// Longest a change waits for the data file to be written while further
+    /// changes keep arriving.
+    #[arg(long, default_value_t = 5000)]
+    persist_max_delay_ms: u64,
+    /// How many journal entries a written snapshot and its backups have to
+    /// cover before they are dropped from the journal. Entries still in the
+    /// journal serve as an audit trail of recent changes.
+    #[arg(long, default_value_t = 1000)]
+    journal_compact_after: u64,
+}
+
+async fn quarantine_file(path: &str) -> std::io::Result<()> {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let quarantine_path = format!("{path}.corrupt-{timestamp}");
+    tokio::fs::rename(path, &quarantine_path).await?;
+    log::warn!("moved unreadable {path} to {quarantine_path}");
+    Ok(())
+}
+
+/// Loads the registry from the snapshot at `data_file` and replays its
+/// journal, applying `on_corrupt_data` to whichever of them can't be read.
+async fn load_registry(
+    data_file: &str,
+    outputs_file: &str,
+    template: &RegistryTemplate,
+    on_corrupt_data: CorruptDataPolicy,
+    persistence: PersistenceHandle,
+) -> Result<Registry, Box<dyn std::error::Error>> {
+    let mut read_only = false;
+    let mut snapshot_quarantined = false;
+    let initial_snapshot = loop {
+        let result = file_reader::read_snapshot_file(
+            data_file,
+            file_reader::LegacyDataFiles { outputs_file },
+            template,
+        )
+        .await;
+        match (result, on_corrupt_data) {
+            (Ok(snapshot), _) => break snapshot,
+            (Err(why), CorruptDataPolicy::Refuse) => return Err(why.into()),
+            (Err(why), CorruptDataPolicy::Quarantine) => {
+                log::error!("{why}");
+                quarantine_file(why.path()).await?;
+                snapshot_quarantined = true;
+            }
+            (Err(why), CorruptDataPolicy::ReadOnly) => {
+                log::error!("{why}, starting read-only from the template");
+                read_only = true;
+                break file_reader::snapshot_from_template(template);
+            }
+        }
+    };
+    let journal_path = journal::journal_path(data_file);
+    // A snapshot is only quarantined when no generation of it can be read, so
+    // the registry starts from the template. The journal continues the
+    // quarantined snapshot and replaying it onto the template would mix the
+    // two, so it goes aside as well.
+    if snapshot_quarantined && Path::new(&journal_path).exists() {
+        log::warn!("{journal_path} continues the quarantined snapshot");
+        quarantine_file(&journal_path).await?;
+    }
+    let (journal, journal_entries) = if read_only {
+        // The journal continues the unreadable snapshot, not the template.
+        (Journal::unread(&journal_path), Vec::new())
+    } else {
+        loop {
+            match (
+                Journal::open(&journal_path, initial_snapshot.journal_sequence),
+                on_corrupt_data,
+            ) {
+                (Ok(opened), _) => break opened,
+                (Err(why), CorruptDataPolicy::Refuse) => return Err(why.into()),
+                (Err(why), CorruptDataPolicy::Quarantine) => {
+                    log::error!("{why}");
+                    quarantine_file(why.path()).await?;
+                }
+                (Err(why), CorruptDataPolicy::ReadOnly) => {
+                    log::error!("{why}, starting read-only from the snapshot alone");
+                    read_only = true;
+                    break (Journal::unread(&journal_path), Vec::new());
+                }
+            }
+        }
+    };
+    let mut registry = Registry::new(initial_snapshot, persistence, journal, read_only);
+    registry.replay(journal_entries);
+    Ok(registry)
+}
+
+/// Exit status when the server couldn't start or failed.
+const EXIT_FAILED: u8 = 1;
+/// Exit status when the server stopped but changes couldn't be written.
+const EXIT_UNFLUSHED_CHANGES: u8 = 2;
+
+/// Resolves on the first SIGINT or SIGTERM.
+async fn shutdown_signal(mut terminate: Signal) {
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+/// Picks the exit status for the outcome of `run`: 0 after a clean shutdown,
+/// `EXIT_FAILED` if the server couldn't start or failed, and
+/// `EXIT_UNFLUSHED_CHANGES` if the final write failed.
+fn exit_status(outcome: Result<Result<(), String>, Box<dyn std::error::Error>>) -> u8 {
+    match outcome {
+        Ok(Ok(())) => 0,
+        Ok(Err(why)) => {
+            log::error!("exiting with unwritten changes: {why}");
+            EXIT_UNFLUSHED_CHANGES
+        }
+        Err(why) => {
+            log::error!("{why}");
+            EXIT_FAILED
+        }
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+async fn main() -> ExitCode {
+    logging::init();
+    ExitCode::from(exit_status(run(Arguments::parse()).await))
+}
+
+/// Serves until a shutdown signal, then stops accepting calls, waits for the
+/// ones in flight and writes pending changes. The inner result is that of the
+/// final write.
+async fn run(arguments: Arguments) -> Result<Result<(), String>, Box<dyn std::error::Error>> {
+    let terminate = signal(SignalKind::terminate())?;
     let data_paths = fr_pmx_config_lib::read_data_file_paths();
     let service_address = fr_pmx_config_lib::read_service_urls()
         .pmx_registry_url
         .replace("http://", "");
     let addr = service_address.parse().unwrap();
 
-    let initial_inputs = file_reader::read_inputs_file(&data_paths.pmx_registry_data_file).await;
-    let initial_outputs =
-        file_reader::read_outputs_file(&data_paths.pmx_registry_output_data_file).await;
-    let (outputs_sender, outputs_receiver) = tokio::sync::mpsc::unbounded_channel();
-    let service = PmxRegistryService::new(initial_inputs, initial_outputs, sender, outputs_sender);
-    let server = Server::builder()
-        .add_service(PmxRegistryServer::new(service))
-        .serve(addr);
-
-    let file_writer =
-        file_writer::run_input_file_writer(receiver, &data_paths.pmx_registry_data_file);
-
-    let outputs_file_writer = file_writer::run_output_file_writer(
-        outputs_receiver,
-        &data_paths.pmx_registry_output_data_file,
+    let template = match &arguments.template {
+        Some(name_or_path) => {
+            let templates_directory =
+                Path::new(&data_paths.pmx_registry_data_file).with_file_name("templates");
+            let template_path =
+                file_reader::resolve_template_path(name_or_path, &templates_directory);
+            file_reader::read_template_file(&template_path)
+                .await
+                .map_err(|why| why.to_string())?
+        }
+        None => RegistryTemplate::builtin(),
+    };
+    let (persistence, snapshot_writer) = persistence::channel(
+        &data_paths.pmx_registry_data_file,
+        Duration::from_millis(arguments.persist_interval_ms),
+        Duration::from_millis(arguments.persist_max_delay_ms),
+        arguments.journal_compact_after,
     );
+    let registry = load_registry(
+        &data_paths.pmx_registry_data_file,
+        &data_paths.pmx_registry_output_data_file,
+        &template,
+        arguments.on_corrupt_data,
+        persistence,
+    )
+    .await?;
+    let registry = Arc::new(RwLock::new(registry));
+    let (stop_writer, writer_stopped) = tokio::sync::oneshot::channel();
+    let snapshot_writer = tokio::spawn(snapshot_writer.run(registry.clone(), writer_stopped));
 
-    tokio::select! {
-        _ = server => {Ok(())}
-        _ = file_writer => {Ok(())}
-        _ = outputs_file_writer => {Ok(())}
-    }
+    let (stop_watchers, shutting_down) = watch::channel(false);
+    let service = PmxRegistryService::new(registry, shutting_down);
+    let served = Server::builder()
+        .add_service(PmxRegistryServer::new(service))
+        .serve_with_shutdown(addr, async {
+            shutdown_signal(terminate).await;
+            log::info!("shutting down");
+            let _ = stop_watchers.send(true);
+        })
+        .await;
+
+    let _ = stop_writer.send(());
+    let flushed = snapshot_writer.await?;
+    served?;
+    Ok(flushed)
 }
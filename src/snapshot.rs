@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::registry::{
+    ChannelStrip, IdempotencyToken, Looper, MixerInput, MixerOutput, NextIds, OutputStage, Plugin,
+    Scene,
+};
+
+pub const CURRENT_SCHEMA_VERSION: u64 = 5;
+
+/// A single document holding every collection of the registry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistrySnapshot {
+    pub schema_version: u64,
+    pub inputs: Vec<MixerInput>,
+    pub outputs: Vec<MixerOutput>,
+    pub plugins: Vec<Plugin>,
+    pub channel_strips: Vec<ChannelStrip>,
+    pub loopers: Vec<Looper>,
+    pub output_stages: Vec<OutputStage>,
+    pub scenes: Vec<Scene>,
+    pub next_ids: NextIds,
+    /// The most recent idempotency tokens, oldest first.
+    pub idempotency_tokens: Vec<IdempotencyToken>,
+    /// Sequence of the last journal entry included in the snapshot.
+    pub journal_sequence: u64,
+}
+
+/// Upgrades a document from `version` to `version + 1`. The step at index `n`
+/// upgrades schema version `n`.
+type Migration = fn(Map<String, Value>) -> Map<String, Value>;
+
+const MIGRATIONS: [Migration; CURRENT_SCHEMA_VERSION as usize] = [
+    migrate_v0_to_v1,
+    migrate_v1_to_v2,
+    migrate_v2_to_v3,
+    migrate_v3_to_v4,
+    migrate_v4_to_v5,
+];
+
+/// Version 0 is the legacy layout: a bare `Vec` of inputs and one of outputs in
+/// two files, assembled by the reader into one object without a
+/// `schema_version`.
+fn migrate_v0_to_v1(mut document: Map<String, Value>) -> Map<String, Value> {
+    for collection in [
+        "inputs",
+        "outputs",
+        "plugins",
+        "channel_strips",
+        "loopers",
+        "output_stages",
+    ] {
+        document
+            .entry(collection)
+            .or_insert_with(|| Value::Array(Vec::new()));
+    }
+    document
+}
+
+/// Version 2 adds the persisted id allocators. Before it, clients chose ids,
+/// so duplicates within a collection are renumbered, keeping the id of the
+/// first entry since that is the one lookups used to find.
+fn migrate_v1_to_v2(mut document: Map<String, Value>) -> Map<String, Value> {
+    let mut next_ids = Map::new();
+    for (collection, kind) in [
+        ("inputs", "input"),
+        ("outputs", "output"),
+        ("plugins", "plugin"),
+        ("channel_strips", "channel_strip"),
+        ("loopers", "looper"),
+        ("output_stages", "output_stage"),
+    ] {
+        let entries = match document.get_mut(collection) {
+            Some(Value::Array(entries)) => entries,
+            _ => continue,
+        };
+        let mut next_id = entries
+            .iter()
+            .filter_map(|entry| entry.get("id").and_then(Value::as_u64))
+            .max()
+            .map_or(1, |id| id + 1);
+        let mut seen = std::collections::HashSet::new();
+        for entry in entries.iter_mut() {
+            let id = entry.get("id").and_then(Value::as_u64);
+            if let (Some(id), Value::Object(entry)) = (id, entry) {
+                if !seen.insert(id) {
+                    entry.insert(String::from("id"), Value::from(next_id));
+                    next_id += 1;
+                }
+            }
+        }
+        next_ids.insert(String::from(kind), Value::from(next_id));
+    }
+    document.insert(String::from("next_ids"), Value::Object(next_ids));
+    document
+}
+
+/// Version 3 keeps the idempotency tokens. Earlier versions forgot them on
+/// every restart.
+fn migrate_v2_to_v3(mut document: Map<String, Value>) -> Map<String, Value> {
+    document.insert(String::from("idempotency_tokens"), Value::Array(Vec::new()));
+    document
+}
+
+/// Version 4 records how much of the journal the snapshot includes. Earlier
+/// versions were written before there was a journal.
+fn migrate_v3_to_v4(mut document: Map<String, Value>) -> Map<String, Value> {
+    document.insert(String::from("journal_sequence"), Value::from(0));
+    document
+}
+
+/// Version 5 adds scenes.
+fn migrate_v4_to_v5(mut document: Map<String, Value>) -> Map<String, Value> {
+    document.insert(String::from("scenes"), Value::Array(Vec::new()));
+    if let Some(Value::Object(next_ids)) = document.get_mut("next_ids") {
+        next_ids.insert(String::from("scene"), Value::from(1));
+    }
+    document
+}
+
+pub fn schema_version(document: &Value) -> u64 {
+    document
+        .get("schema_version")
+        .and_then(Value::as_u64)
+        .unwrap_or(0)
+}
+
+/// Sequence of the last journal entry the document includes. Documents from
+/// before the journal include none.
+pub fn journal_sequence(document: &Value) -> u64 {
+    document
+        .get("journal_sequence")
+        .and_then(Value::as_u64)
+        .unwrap_or(0)
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    NotAnObject,
+    UnsupportedVersion(u64),
+    InvalidDocument(serde_json::Error),
+}
+
+impl std::fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrationError::NotAnObject => f.write_str("snapshot isn't a JSON object"),
+            MigrationError::UnsupportedVersion(version) => write!(
+                f,
+                "snapshot schema version {version} is newer than the supported version {CURRENT_SCHEMA_VERSION}"
+            ),
+            MigrationError::InvalidDocument(why) => write!(f, "invalid snapshot: {why}"),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+/// Runs every migration between the document's schema version and
+/// `CURRENT_SCHEMA_VERSION`, then deserializes the result.
+pub fn migrate(document: Value) -> Result<RegistrySnapshot, MigrationError> {
+    let version = schema_version(&document);
+    if version > CURRENT_SCHEMA_VERSION {
+        return Err(MigrationError::UnsupportedVersion(version));
+    }
+    let Value::Object(mut document) = document else {
+        return Err(MigrationError::NotAnObject);
+    };
+    for migration in &MIGRATIONS[version as usize..] {
+        document = migration(document);
+    }
+    document.insert(
+        String::from("schema_version"),
+        Value::from(CURRENT_SCHEMA_VERSION),
+    );
+    serde_json::from_value(Value::Object(document)).map_err(MigrationError::InvalidDocument)
+}
@@ -0,0 +1,260 @@
+//! Turns registry failures into gRPC statuses. Besides the code and message,
+//! every status carries `google.rpc` error details in the
+//! `grpc-status-details-bin` trailer: an `ErrorInfo` with a machine readable
+//! reason, plus a `ResourceInfo` or `PreconditionFailure` where one applies.
+
+use std::collections::HashMap;
+
+use prost::Message;
+use tonic::{Code, Status};
+
+use crate::pmx::error_details::{
+    precondition_failure::Violation, Any, ErrorInfo, PreconditionFailure, ResourceInfo,
+    Status as StatusDetails,
+};
+use crate::registry::{EntityKind, RegistryError};
+
+const ERROR_DOMAIN: &str = "pmx-registry";
+
+pub fn detail(type_name: &str, message: impl Message) -> Any {
+    Any {
+        type_url: format!("type.googleapis.com/google.rpc.{type_name}"),
+        value: message.encode_to_vec(),
+    }
+}
+
+pub fn with_details(code: Code, message: String, details: Vec<Any>) -> Status {
+    let status = StatusDetails {
+        code: code as i32,
+        message: message.clone(),
+        details,
+    };
+    Status::with_details(code, message, status.encode_to_vec().into())
+}
+
+fn error_info(reason: &str, metadata: &[(&str, String)]) -> Any {
+    detail(
+        "ErrorInfo",
+        ErrorInfo {
+            reason: String::from(reason),
+            domain: String::from(ERROR_DOMAIN),
+            metadata: metadata
+                .iter()
+                .map(|(key, value)| (String::from(*key), value.clone()))
+                .collect::<HashMap<_, _>>(),
+        },
+    )
+}
+
+fn resource_info(
+    resource_type: impl std::fmt::Display,
+    resource_name: String,
+    description: &str,
+) -> Any {
+    detail(
+        "ResourceInfo",
+        ResourceInfo {
+            resource_type: resource_type.to_string(),
+            resource_name,
+            owner: String::new(),
+            description: String::from(description),
+        },
+    )
+}
+
+fn precondition_failure(violation_type: &str, subject: String, description: &str) -> Any {
+    detail(
+        "PreconditionFailure",
+        PreconditionFailure {
+            violations: vec![Violation {
+                r#type: String::from(violation_type),
+                subject,
+                description: String::from(description),
+            }],
+        },
+    )
+}
+
+impl From<RegistryError> for Status {
+    fn from(error: RegistryError) -> Self {
+        let message = error.to_string();
+        // A failed batch is reported as the failure of its mutation, with the
+        // mutation's index added to the ErrorInfo.
+        let (error, context) = match error {
+            RegistryError::BatchFailed { index, source } => {
+                (*source, vec![("mutation_index", index.to_string())])
+            }
+            error => (error, Vec::new()),
+        };
+        let error_info = |reason: &str, metadata: &[(&str, String)]| {
+            error_info(reason, &[metadata, &context].concat())
+        };
+        let (code, details) = match &error {
+            RegistryError::NotFound { kind, id } => (
+                Code::NotFound,
+                vec![
+                    error_info(
+                        "NOT_FOUND",
+                        &[("kind", kind.to_string()), ("id", id.to_string())],
+                    ),
+                    resource_info(*kind, id.to_string(), &message),
+                ],
+            ),
+            RegistryError::AlreadyExists { kind, id } => (
+                Code::AlreadyExists,
+                vec![
+                    error_info(
+                        "ALREADY_EXISTS",
+                        &[("kind", kind.to_string()), ("id", id.to_string())],
+                    ),
+                    resource_info(*kind, id.to_string(), &message),
+                ],
+            ),
+            RegistryError::UnexpectedId { kind, id } => (
+                Code::InvalidArgument,
+                vec![
+                    error_info(
+                        "UNEXPECTED_ID",
+                        &[("kind", kind.to_string()), ("id", id.to_string())],
+                    ),
+                    resource_info(*kind, id.to_string(), &message),
+                ],
+            ),
+            RegistryError::MissingCrossFader { name } => (
+                Code::InvalidArgument,
+                vec![
+                    error_info("MISSING_CROSS_FADER", &[("name", name.clone())]),
+                    resource_info(EntityKind::ChannelStrip, name.clone(), &message),
+                ],
+            ),
+            RegistryError::InvalidReference { kind, id } => (
+                Code::FailedPrecondition,
+                vec![
+                    error_info(
+                        "INVALID_REFERENCE",
+                        &[("kind", kind.to_string()), ("id", id.to_string())],
+                    ),
+                    precondition_failure("INVALID_REFERENCE", format!("{kind}/{id}"), &message),
+                ],
+            ),
+            RegistryError::InvalidGroupReference { name } => (
+                Code::FailedPrecondition,
+                vec![
+                    error_info(
+                        "INVALID_REFERENCE",
+                        &[("kind", String::from("group")), ("name", name.clone())],
+                    ),
+                    precondition_failure("INVALID_REFERENCE", format!("group/{name}"), &message),
+                ],
+            ),
+            RegistryError::InUse {
+                kind,
+                id,
+                used_by_kind,
+                used_by_id,
+            } => (
+                Code::FailedPrecondition,
+                vec![
+                    error_info(
+                        "IN_USE",
+                        &[
+                            ("kind", kind.to_string()),
+                            ("id", id.to_string()),
+                            ("used_by_kind", used_by_kind.to_string()),
+                            ("used_by_id", used_by_id.to_string()),
+                        ],
+                    ),
+                    precondition_failure("IN_USE", format!("{kind}/{id}"), &message),
+                ],
+            ),
+            RegistryError::ReadOnly => (
+                Code::FailedPrecondition,
+                vec![
+                    error_info("READ_ONLY", &[]),
+                    precondition_failure("READ_ONLY", String::from("registry"), &message),
+                ],
+            ),
+            RegistryError::IdsExhausted { kind } => (
+                Code::ResourceExhausted,
+                vec![error_info("IDS_EXHAUSTED", &[("kind", kind.to_string())])],
+            ),
+            RegistryError::SceneNotFound { name } => (
+                Code::NotFound,
+                vec![
+                    error_info(
+                        "NOT_FOUND",
+                        &[
+                            ("kind", EntityKind::Scene.to_string()),
+                            ("name", name.clone()),
+                        ],
+                    ),
+                    resource_info(EntityKind::Scene, name.clone(), &message),
+                ],
+            ),
+            RegistryError::GroupNotFound { name } => (
+                Code::NotFound,
+                vec![
+                    error_info(
+                        "NOT_FOUND",
+                        &[("kind", String::from("group")), ("name", name.clone())],
+                    ),
+                    resource_info("group", name.clone(), &message),
+                ],
+            ),
+            RegistryError::GroupExists { name } => (
+                Code::AlreadyExists,
+                vec![
+                    error_info(
+                        "ALREADY_EXISTS",
+                        &[("kind", String::from("group")), ("name", name.clone())],
+                    ),
+                    resource_info("group", name.clone(), &message),
+                ],
+            ),
+            RegistryError::HistoryEmpty { .. } => (
+                Code::FailedPrecondition,
+                vec![
+                    error_info("HISTORY_EMPTY", &[]),
+                    precondition_failure("HISTORY_EMPTY", String::from("history"), &message),
+                ],
+            ),
+            RegistryError::RevisionMismatch {
+                kind,
+                id,
+                expected,
+                actual,
+            } => (
+                Code::Aborted,
+                vec![
+                    error_info(
+                        "REVISION_MISMATCH",
+                        &[
+                            ("kind", kind.to_string()),
+                            ("id", id.to_string()),
+                            ("expected_revision", expected.to_string()),
+                            ("actual_revision", actual.to_string()),
+                        ],
+                    ),
+                    resource_info(*kind, id.to_string(), &message),
+                ],
+            ),
+            // The change was reverted, so the call can be retried once the
+            // disk accepts writes again.
+            RegistryError::PersistenceFailed { reason } => (
+                Code::Unavailable,
+                vec![error_info(
+                    "PERSISTENCE_FAILED",
+                    &[("reason", reason.clone())],
+                )],
+            ),
+            RegistryError::BatchFailed { .. } => {
+                (Code::Internal, vec![error_info("BATCH_FAILED", &[])])
+            }
+            RegistryError::ResumeTokenExpired => (
+                Code::OutOfRange,
+                vec![error_info("RESUME_TOKEN_EXPIRED", &[])],
+            ),
+        };
+        with_details(code, message, details)
+    }
+}
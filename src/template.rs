@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+/// Seed data the registry uses to bootstrap an install without data files.
+/// Ids are assigned in order and every port starts unassigned.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistryTemplate {
+    pub inputs: Vec<TemplateInput>,
+    pub outputs: Vec<TemplateOutput>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateInput {
+    pub name: String,
+    pub group_channel_strip_name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TemplateOutputType {
+    Cue,
+    Main,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateOutput {
+    pub name: String,
+    pub output_type: TemplateOutputType,
+}
+
+impl RegistryTemplate {
+    /// Used when no template is selected: no inputs and a main and cue output.
+    pub fn builtin() -> Self {
+        RegistryTemplate {
+            inputs: Vec::new(),
+            outputs: vec![
+                TemplateOutput {
+                    name: String::from("Main"),
+                    output_type: TemplateOutputType::Main,
+                },
+                TemplateOutput {
+                    name: String::from("Cue"),
+                    output_type: TemplateOutputType::Cue,
+                },
+            ],
+        }
+    }
+}
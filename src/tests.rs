@@ -0,0 +1,2509 @@
+//! Drives the handlers with malformed requests. They have to be answered with
+//! `INVALID_ARGUMENT` naming the offending fields, or with `NOT_FOUND`, and
+//! never panic the handler task. The rest covers what is hard to get right
+//! by reading: loading the data files, retried register calls, the journal
+//! and reverting operations.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use proptest::prelude::*;
+use prost::Message;
+use tokio::runtime::Runtime;
+use tokio::sync::{watch, RwLock};
+use tonic::{Code, Request, Status};
+
+use crate::changes::Entity;
+use crate::file_reader::ReadError;
+use crate::journal::{self, Journal, JournalError, Operation};
+use crate::persistence::{self, SnapshotWriter};
+use crate::pmx::batch_mutation::Mutation as Requested;
+use crate::pmx::channel_strip::{PmxChannelStrip, PmxChannelStripType};
+use crate::pmx::error_details::{BadRequest, ErrorInfo, Status as StatusDetails};
+use crate::pmx::input::PmxInputType;
+use crate::pmx::looper::PmxLooper;
+use crate::pmx::output_stage::PmxOutputStage;
+use crate::pmx::plugin::PmxPlugin;
+use crate::pmx::pmx_registry_server::PmxRegistry;
+use crate::pmx::{
+    AddInputRequest, AddOutputRequest, ApplyBatchRequest, BatchMutation, ByIdRequest,
+    ListChannelStripsRequest, ListLoopersRequest, MoveInputsToGroupRequest, PmxListOrder,
+    RegisterChannelStripRequest, RegisterLooperRequest, RegisterOutputStageRequest,
+    RegisterPluginRequest, SaveSceneRequest, SceneByNameRequest, UnregisterRequest,
+    UpdateChannelStripRequest, UpdateInputNameRequest, UpdateInputPortAssignmentsRequest,
+    UpdateLooperRequest, UpdateOutputNameRequest, UpdateOutputStageRequest, UpdatePluginRequest,
+};
+use crate::port_path::{self, PortPathError};
+use crate::query::{Order, Page};
+use crate::registry::{
+    EntityKind, HistoryDirection, Looper, MixerOutputType, PipewirePorts, Registry, RegistryError,
+};
+use crate::snapshot::{RegistrySnapshot, CURRENT_SCHEMA_VERSION};
+use crate::template::{RegistryTemplate, TemplateInput, TemplateOutput, TemplateOutputType};
+use crate::{
+    exit_status, file_reader, file_writer, load_registry, CorruptDataPolicy, PmxRegistryService,
+    EXIT_FAILED, EXIT_UNFLUSHED_CHANGES,
+};
+
+/// A service on the built-in template, journaling into a directory that is
+/// removed when the fixture is dropped.
+struct Fixture {
+    service: PmxRegistryService,
+    _snapshot_writer: SnapshotWriter,
+    data_file: String,
+    _directory: tempfile::TempDir,
+}
+
+fn data_file(directory: &tempfile::TempDir) -> String {
+    directory
+        .path()
+        .join("pmx_registry.json")
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn fixture() -> Fixture {
+    let directory = tempfile::tempdir().unwrap();
+    let data_file = data_file(&directory);
+    let (persistence, snapshot_writer) = persistence::channel(
+        &data_file,
+        Duration::from_secs(60),
+        Duration::from_secs(60),
+        1000,
+    );
+    let registry = Registry::new(
+        file_reader::snapshot_from_template(&RegistryTemplate::builtin()),
+        persistence,
+        Journal::unread(&journal::journal_path(&data_file)),
+        false,
+    );
+    let (_, shutting_down) = watch::channel(false);
+    Fixture {
+        service: PmxRegistryService::new(Arc::new(RwLock::new(registry)), shutting_down),
+        _snapshot_writer: snapshot_writer,
+        data_file,
+        _directory: directory,
+    }
+}
+
+/// The fields named by the `BadRequest` in the status details.
+fn violated_fields(status: &Status) -> Vec<String> {
+    let details = StatusDetails::decode(status.details()).unwrap();
+    details
+        .details
+        .iter()
+        .filter(|detail| detail.type_url.ends_with("google.rpc.BadRequest"))
+        .flat_map(|detail| {
+            BadRequest::decode(detail.value.as_slice())
+                .unwrap()
+                .field_violations
+        })
+        .map(|violation| violation.field)
+        .collect()
+}
+
+fn assert_invalid_argument<T: std::fmt::Debug>(result: Result<T, Status>, fields: &[&str]) {
+    let status = result.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument, "{status:?}");
+    assert_eq!(violated_fields(&status), fields);
+}
+
+fn channel_strip(channel_strip_type: PmxChannelStripType) -> PmxChannelStrip {
+    PmxChannelStrip {
+        name: String::from("vocals"),
+        channel_strip_type: channel_strip_type as i32,
+        ..PmxChannelStrip::default()
+    }
+}
+
+fn port_assignments(input_type: PmxInputType) -> UpdateInputPortAssignmentsRequest {
+    UpdateInputPortAssignmentsRequest {
+        id: 1,
+        input_type: input_type as i32,
+        left_port_path: None,
+        right_port_path: None,
+        expected_revision: None,
+    }
+}
+
+#[tokio::test]
+async fn register_channel_strip_requires_the_channel_strip() {
+    let fixture = fixture();
+    let result = fixture
+        .service
+        .register_channel_strip(Request::new(RegisterChannelStripRequest {
+            channel_strip: None,
+            idempotency_token: None,
+        }))
+        .await;
+    assert_invalid_argument(result, &["channel_strip"]);
+}
+
+#[tokio::test]
+async fn cross_faded_channel_strip_requires_the_cross_fader() {
+    let fixture = fixture();
+    let result = fixture
+        .service
+        .register_channel_strip(Request::new(RegisterChannelStripRequest {
+            channel_strip: Some(channel_strip(PmxChannelStripType::CrossFaded)),
+            idempotency_token: None,
+        }))
+        .await;
+    assert_invalid_argument(result, &["channel_strip.cross_fader_plugin_id"]);
+}
+
+#[tokio::test]
+async fn unknown_channel_strip_type_is_rejected() {
+    let fixture = fixture();
+    let result = fixture
+        .service
+        .register_channel_strip(Request::new(RegisterChannelStripRequest {
+            channel_strip: Some(PmxChannelStrip {
+                channel_strip_type: 7,
+                ..channel_strip(PmxChannelStripType::Basic)
+            }),
+            idempotency_token: None,
+        }))
+        .await;
+    assert_invalid_argument(result, &["channel_strip.channel_strip_type"]);
+}
+
+#[tokio::test]
+async fn mono_input_requires_the_left_port() {
+    let fixture = fixture();
+    let result = fixture
+        .service
+        .update_input_port_assignments(Request::new(port_assignments(PmxInputType::MonoInput)))
+        .await;
+    assert_invalid_argument(result, &["left_port_path"]);
+}
+
+#[tokio::test]
+async fn stereo_input_reports_both_missing_ports() {
+    let fixture = fixture();
+    let result = fixture
+        .service
+        .update_input_port_assignments(Request::new(port_assignments(PmxInputType::StereoInput)))
+        .await;
+    assert_invalid_argument(result, &["left_port_path", "right_port_path"]);
+}
+
+#[tokio::test]
+async fn malformed_port_path_is_rejected() {
+    let fixture = fixture();
+    let result = fixture
+        .service
+        .update_input_port_assignments(Request::new(UpdateInputPortAssignmentsRequest {
+            left_port_path: Some(String::from("no separator")),
+            ..port_assignments(PmxInputType::MonoInput)
+        }))
+        .await;
+    assert_invalid_argument(result, &["left_port_path"]);
+}
+
+#[tokio::test]
+async fn unknown_output_is_not_found() {
+    let fixture = fixture();
+    let status = fixture
+        .service
+        .get_output(Request::new(ByIdRequest { id: 9999 }))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::NotFound, "{status:?}");
+}
+
+#[tokio::test]
+async fn blank_names_are_rejected() {
+    let fixture = fixture();
+    let service = &fixture.service;
+    assert_invalid_argument(
+        service
+            .add_input(Request::new(AddInputRequest {
+                name: String::from(" "),
+                group_channel_strip_name: String::new(),
+            }))
+            .await,
+        &["name"],
+    );
+    assert_invalid_argument(
+        service
+            .update_input_name(Request::new(UpdateInputNameRequest {
+                id: 1,
+                name: String::new(),
+                expected_revision: None,
+            }))
+            .await,
+        &["name"],
+    );
+    assert_invalid_argument(
+        service
+            .add_output(Request::new(AddOutputRequest {
+                name: String::from("\t"),
+                output_type: 0,
+            }))
+            .await,
+        &["name"],
+    );
+    assert_invalid_argument(
+        service
+            .update_output_name(Request::new(UpdateOutputNameRequest {
+                id: 1,
+                name: String::new(),
+                expected_revision: None,
+            }))
+            .await,
+        &["name"],
+    );
+    assert_invalid_argument(
+        service
+            .register_plugin(Request::new(RegisterPluginRequest {
+                plugin: Some(PmxPlugin::default()),
+                idempotency_token: None,
+            }))
+            .await,
+        &["plugin.name"],
+    );
+    assert_invalid_argument(
+        service
+            .update_plugin(Request::new(UpdatePluginRequest {
+                plugin: Some(PmxPlugin::default()),
+                expected_revision: None,
+            }))
+            .await,
+        &["plugin.name"],
+    );
+    assert_invalid_argument(
+        service
+            .update_looper(Request::new(UpdateLooperRequest {
+                looper: Some(PmxLooper::default()),
+                expected_revision: None,
+            }))
+            .await,
+        &["looper.name"],
+    );
+    assert_invalid_argument(
+        service
+            .register_output_stage(Request::new(RegisterOutputStageRequest {
+                name: String::from(" "),
+                ..RegisterOutputStageRequest::default()
+            }))
+            .await,
+        &["name"],
+    );
+    assert_invalid_argument(
+        service
+            .update_output_stage(Request::new(UpdateOutputStageRequest {
+                output_stage: Some(PmxOutputStage::default()),
+                expected_revision: None,
+            }))
+            .await,
+        &["output_stage.name"],
+    );
+}
+
+#[tokio::test]
+async fn batch_reports_violations_by_mutation() {
+    let fixture = fixture();
+    let mutation = |mutation| BatchMutation {
+        mutation: Some(mutation),
+    };
+    let result = fixture
+        .service
+        .apply_batch(Request::new(ApplyBatchRequest {
+            mutations: vec![
+                mutation(Requested::AddInput(AddInputRequest {
+                    name: String::from("guitar"),
+                    group_channel_strip_name: String::new(),
+                })),
+                mutation(Requested::AddInput(AddInputRequest {
+                    name: String::new(),
+                    group_channel_strip_name: String::new(),
+                })),
+                BatchMutation { mutation: None },
+                mutation(Requested::RegisterOutputStage(
+                    RegisterOutputStageRequest::default(),
+                )),
+                mutation(Requested::RegisterChannelStrip(
+                    RegisterChannelStripRequest {
+                        channel_strip: Some(channel_strip(PmxChannelStripType::CrossFaded)),
+                        idempotency_token: None,
+                    },
+                )),
+            ],
+        }))
+        .await;
+    assert_invalid_argument(
+        result,
+        &[
+            "mutations[1].add_input.name",
+            "mutations[2].mutation",
+            "mutations[3].register_output_stage.name",
+            "mutations[4].register_channel_strip.channel_strip.cross_fader_plugin_id",
+        ],
+    );
+}
+
+/// Registers the plugins a basic channel strip needs and the strip itself.
+async fn register_basic_channel_strip(service: &PmxRegistryService, name: &str) -> PmxChannelStrip {
+    let mut plugin_ids = Vec::new();
+    for plugin in ["saturator", "compressor", "equalizer", "gain"] {
+        let plugin = service
+            .register_plugin(Request::new(RegisterPluginRequest {
+                plugin: Some(PmxPlugin {
+                    name: format!("{name} {plugin}"),
+                    plugin_uri: format!("urn:{name}:{plugin}"),
+                    ..PmxPlugin::default()
+                }),
+                idempotency_token: None,
+            }))
+            .await
+            .unwrap();
+        plugin_ids.push(plugin.into_inner().id);
+    }
+    service
+        .register_channel_strip(Request::new(RegisterChannelStripRequest {
+            channel_strip: Some(PmxChannelStrip {
+                name: String::from(name),
+                saturator_plugin_id: plugin_ids[0],
+                compressor_plugin_id: plugin_ids[1],
+                equalizer_plugin_id: plugin_ids[2],
+                gain_plugin_id: plugin_ids[3],
+                ..channel_strip(PmxChannelStripType::Basic)
+            }),
+            idempotency_token: None,
+        }))
+        .await
+        .unwrap()
+        .into_inner()
+}
+
+fn add_input(name: &str, group: &str) -> Request<AddInputRequest> {
+    Request::new(AddInputRequest {
+        name: String::from(name),
+        group_channel_strip_name: String::from(group),
+    })
+}
+
+#[tokio::test]
+async fn input_has_to_join_an_existing_group() {
+    let fixture = fixture();
+    let status = fixture
+        .service
+        .add_input(add_input("guitar", "nowhere"))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition, "{status:?}");
+
+    let mutation = Requested::AddInput(AddInputRequest {
+        name: String::from("guitar"),
+        group_channel_strip_name: String::from("nowhere"),
+    });
+    let status = fixture
+        .service
+        .apply_batch(Request::new(ApplyBatchRequest {
+            mutations: vec![BatchMutation {
+                mutation: Some(mutation),
+            }],
+        }))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition, "{status:?}");
+}
+
+fn move_inputs(input_ids: &[u32], group: &str) -> Request<MoveInputsToGroupRequest> {
+    Request::new(MoveInputsToGroupRequest {
+        input_ids: input_ids.to_vec(),
+        group: String::from(group),
+    })
+}
+
+#[tokio::test]
+async fn inputs_cannot_move_to_a_missing_group() {
+    let fixture = fixture();
+    let service = &fixture.service;
+    register_basic_channel_strip(service, "drums").await;
+    let kick = service
+        .add_input(add_input("kick", "drums"))
+        .await
+        .unwrap()
+        .into_inner();
+
+    let status = service
+        .move_inputs_to_group(move_inputs(&[kick.id], "nowhere"))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition, "{status:?}");
+    assert_eq!(error_info(&status).metadata["name"], "nowhere");
+
+    assert_invalid_argument(
+        service
+            .move_inputs_to_group(move_inputs(&[kick.id], " "))
+            .await,
+        &["group"],
+    );
+}
+
+#[tokio::test]
+async fn inputs_move_together_or_not_at_all() {
+    let fixture = fixture();
+    let service = &fixture.service;
+    register_basic_channel_strip(service, "drums").await;
+    register_basic_channel_strip(service, "bass").await;
+    let kick = service
+        .add_input(add_input("kick", "drums"))
+        .await
+        .unwrap()
+        .into_inner();
+
+    let status = service
+        .move_inputs_to_group(move_inputs(&[kick.id, 99], "bass"))
+        .await
+        .unwrap_err();
+
+    assert_eq!(status.code(), Code::NotFound, "{status:?}");
+    let kick = service
+        .get_input(Request::new(ByIdRequest { id: kick.id }))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(kick.group_channel_strip_name, "drums");
+}
+
+#[tokio::test]
+async fn renamed_channel_strip_takes_its_inputs_along() {
+    let fixture = fixture();
+    let service = &fixture.service;
+    let drums = register_basic_channel_strip(service, "drums").await;
+    let kick = service
+        .add_input(add_input("kick", "drums"))
+        .await
+        .unwrap()
+        .into_inner();
+
+    service
+        .update_channel_strip(Request::new(UpdateChannelStripRequest {
+            channel_strip: Some(PmxChannelStrip {
+                name: String::from("percussion"),
+                ..drums
+            }),
+            expected_revision: None,
+        }))
+        .await
+        .unwrap();
+    let kick = service
+        .get_input(Request::new(ByIdRequest { id: kick.id }))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(kick.group_channel_strip_name, "percussion");
+}
+
+#[tokio::test]
+async fn channel_strip_cannot_take_the_name_of_another_group() {
+    let fixture = fixture();
+    let service = &fixture.service;
+    let drums = register_basic_channel_strip(service, "drums").await;
+    let bass = register_basic_channel_strip(service, "bass").await;
+    service.add_input(add_input("kick", "drums")).await.unwrap();
+    // The kick stays in the "drums" group without a strip of that name.
+    service
+        .unregister_channel_strip(Request::new(UnregisterRequest {
+            id: drums.id,
+            cascade: false,
+        }))
+        .await
+        .unwrap();
+
+    let status = service
+        .update_channel_strip(Request::new(UpdateChannelStripRequest {
+            channel_strip: Some(PmxChannelStrip {
+                name: String::from("drums"),
+                ..bass
+            }),
+            expected_revision: None,
+        }))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::AlreadyExists, "{status:?}");
+}
+
+#[tokio::test]
+async fn same_port_on_both_sides_is_warned_about() {
+    let fixture = fixture();
+    let service = &fixture.service;
+    register_basic_channel_strip(service, "drums").await;
+    let kick = service
+        .add_input(add_input("kick", "drums"))
+        .await
+        .unwrap()
+        .into_inner();
+    let response = service
+        .update_input_port_assignments(Request::new(UpdateInputPortAssignmentsRequest {
+            id: kick.id,
+            left_port_path: Some(String::from("node:capture_FL")),
+            right_port_path: Some(String::from("node:capture_fl")),
+            ..port_assignments(PmxInputType::StereoInput)
+        }))
+        .await
+        .unwrap();
+    let warnings: Vec<_> = response
+        .metadata()
+        .get_all_bin("x-pmx-warning-bin")
+        .iter()
+        .map(|warning| warning.to_bytes().unwrap())
+        .collect();
+    assert_eq!(
+        warnings,
+        ["both the left and the right port are node:capture_FL".as_bytes()]
+    );
+}
+
+#[test]
+fn port_paths_are_normalized() {
+    let cases: &[(&str, Result<&str, PortPathError>)] = &[
+        ("", Err(PortPathError::Empty)),
+        ("  \t ", Err(PortPathError::Empty)),
+        ("node", Err(PortPathError::MissingSeparator)),
+        (":capture_FL", Err(PortPathError::EmptyNode)),
+        ("  :capture_FL", Err(PortPathError::EmptyNode)),
+        ("node:", Err(PortPathError::EmptyPort)),
+        ("node:  ", Err(PortPathError::EmptyPort)),
+        ("node:\ncapture_FL", Err(PortPathError::ControlCharacter)),
+        (
+            "no\u{7f}de:capture_FL",
+            Err(PortPathError::ControlCharacter),
+        ),
+        ("node:capture_FL", Ok("node:capture_FL")),
+        ("  node : capture_FL  ", Ok("node:capture_FL")),
+        ("USB  Mixer:capture   FL", Ok("USB Mixer:capture FL")),
+        // Only the first ':' separates the node from the port.
+        ("node:monitor:out_FL", Ok("node:monitor:out_FL")),
+        ("node::port", Err(PortPathError::EmptySegment)),
+        ("node:monitor:", Err(PortPathError::EmptySegment)),
+        ("node:monitor: :out_FL", Err(PortPathError::EmptySegment)),
+        // Known positions are upper-cased, anything else is left alone.
+        ("node:capture_fl", Ok("node:capture_FL")),
+        ("node:playback_lfe", Ok("node:playback_LFE")),
+        ("node:capture_Mono", Ok("node:capture_MONO")),
+        ("node:out_l", Ok("node:out_l")),
+        ("node:out_left", Ok("node:out_left")),
+        ("node:out_r", Ok("node:out_r")),
+        ("node:aux_c", Ok("node:aux_c")),
+        ("node:_fl", Ok("node:_fl")),
+        ("node:fl", Ok("node:fl")),
+    ];
+    for (path, expected) in cases {
+        let expected = expected.map(String::from);
+        assert_eq!(port_path::normalize(path), expected, "normalizing {path:?}");
+    }
+}
+
+#[test]
+fn only_the_same_port_on_both_sides_is_warned_about() {
+    let same = PipewirePorts::Stereo(String::from("node:FL"), String::from("node:FL"));
+    assert!(port_path::warning(&same).is_some());
+    let pair = PipewirePorts::Stereo(String::from("node:FL"), String::from("node:FR"));
+    assert_eq!(port_path::warning(&pair), None);
+    assert_eq!(
+        port_path::warning(&PipewirePorts::Mono(String::from("node:FL"))),
+        None
+    );
+}
+
+/// Port paths as clients might send them, well-formed or not.
+fn any_port_path() -> impl Strategy<Value = Option<String>> {
+    proptest::option::of(prop_oneof![
+        "[a-z_.-]{0,12}:[a-zA-Z_ ]{0,12}",
+        "[ :\t]{0,4}",
+        any::<String>(),
+    ])
+}
+
+#[test]
+fn arbitrary_port_assignments_are_answered() {
+    let runtime = Runtime::new().unwrap();
+    let fixture = fixture();
+    proptest!(|(
+        id in 0..40u32,
+        input_type in -1..5i32,
+        left_port_path in any_port_path(),
+        right_port_path in any_port_path(),
+    )| {
+        let result = runtime.block_on(fixture.service.update_input_port_assignments(
+            Request::new(UpdateInputPortAssignmentsRequest {
+                id,
+                input_type,
+                left_port_path,
+                right_port_path,
+                expected_revision: None,
+            }),
+        ));
+        if let Err(status) = result {
+            match status.code() {
+                Code::InvalidArgument => {
+                    let fields = violated_fields(&status);
+                    prop_assert!(!fields.is_empty());
+                    for field in fields {
+                        prop_assert!(
+                            ["input_type", "left_port_path", "right_port_path"]
+                                .contains(&field.as_str()),
+                            "unexpected field {}",
+                            field
+                        );
+                    }
+                }
+                Code::NotFound => {}
+                code => prop_assert!(false, "unexpected {:?}: {}", code, status.message()),
+            }
+        }
+    });
+}
+
+#[test]
+fn arbitrary_channel_strips_are_answered() {
+    let runtime = Runtime::new().unwrap();
+    let fixture = fixture();
+    proptest!(|(
+        present in any::<bool>(),
+        channel_strip_type in -1..3i32,
+        cross_fader_plugin_id in proptest::option::of(0..5u32),
+        plugin_ids in proptest::array::uniform4(0..5u32),
+    )| {
+        let channel_strip = present.then(|| PmxChannelStrip {
+            name: String::from("strip"),
+            channel_strip_type,
+            cross_fader_plugin_id,
+            saturator_plugin_id: plugin_ids[0],
+            compressor_plugin_id: plugin_ids[1],
+            equalizer_plugin_id: plugin_ids[2],
+            gain_plugin_id: plugin_ids[3],
+            ..PmxChannelStrip::default()
+        });
+        let result = runtime.block_on(fixture.service.register_channel_strip(Request::new(
+            RegisterChannelStripRequest {
+                channel_strip,
+                idempotency_token: None,
+            },
+        )));
+        if let Err(status) = result {
+            match status.code() {
+                Code::InvalidArgument => prop_assert!(!violated_fields(&status).is_empty()),
+                // The plugins the strip refers to don't exist.
+                Code::FailedPrecondition | Code::NotFound => {}
+                code => prop_assert!(false, "unexpected {:?}: {}", code, status.message()),
+            }
+        }
+    });
+}
+
+#[test]
+fn template_seeds_ids_in_order_with_unassigned_ports() {
+    let template = RegistryTemplate {
+        inputs: ["DSMPL", "SE02"]
+            .map(|name| TemplateInput {
+                name: String::from(name),
+                group_channel_strip_name: String::from("Synths"),
+            })
+            .into(),
+        outputs: vec![TemplateOutput {
+            name: String::from("Booth"),
+            output_type: TemplateOutputType::Cue,
+        }],
+    };
+
+    let snapshot = file_reader::snapshot_from_template(&template);
+    let inputs: Vec<_> = snapshot
+        .inputs
+        .iter()
+        .map(|i| (i.id, i.name.as_str(), i.group_channel_strip_name.as_str()))
+        .collect();
+    assert_eq!(inputs, [(1, "DSMPL", "Synths"), (2, "SE02", "Synths")]);
+    assert_eq!(snapshot.outputs[0].id, 1);
+    assert_eq!(snapshot.outputs[0].output_type, MixerOutputType::Cue);
+    assert!(snapshot
+        .inputs
+        .iter()
+        .map(|input| &input.pipewire_ports)
+        .chain(snapshot.outputs.iter().map(|output| &output.pipewire_ports))
+        .all(|ports| *ports == PipewirePorts::None));
+    assert_eq!((snapshot.next_ids.input, snapshot.next_ids.output), (3, 2));
+}
+
+#[test]
+fn template_is_selected_by_name_or_by_path() {
+    let templates = std::path::Path::new("/etc/pmx/templates");
+    let resolve = |name_or_path| file_reader::resolve_template_path(name_or_path, templates);
+    assert_eq!(resolve("studio-a"), templates.join("studio-a.json"));
+    assert_eq!(
+        resolve("studio-a.json"),
+        std::path::Path::new("studio-a.json")
+    );
+    assert_eq!(
+        resolve("seeds/studio-a"),
+        std::path::Path::new("seeds/studio-a")
+    );
+}
+
+/// Writes `contents` to `name` in `directory` and returns its path.
+fn write_file(directory: &tempfile::TempDir, name: &str, contents: &str) -> String {
+    let path = directory.path().join(name).to_string_lossy().into_owned();
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[tokio::test]
+async fn baseline_data_files_are_migrated() {
+    let directory = tempfile::tempdir().unwrap();
+    let inputs_file = write_file(
+        &directory,
+        "pmx_registry.json",
+        r#"[
+            {"name": "DSMPL", "pipewire_ports": "None", "id": 1, "group_channel_strip_name": "Drums"},
+            {"name": "SE02", "pipewire_ports": {"Mono": "se02:out"}, "id": 1, "group_channel_strip_name": "Bass"}
+        ]"#,
+    );
+    let outputs_file = write_file(
+        &directory,
+        "pmx_registry_outputs.json",
+        r#"[{"name": "Main", "pipewire_ports": {"Stereo": ["main:FL", "main:FR"]}, "id": 1, "output_type": "Main"}]"#,
+    );
+
+    let snapshot = file_reader::read_snapshot_file(
+        &inputs_file,
+        file_reader::LegacyDataFiles {
+            outputs_file: &outputs_file,
+        },
+        &RegistryTemplate::builtin(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(snapshot.schema_version, CURRENT_SCHEMA_VERSION);
+    let inputs: Vec<_> = snapshot
+        .inputs
+        .iter()
+        .map(|i| (i.id, i.name.as_str(), i.pipewire_ports.clone(), i.revision))
+        .collect();
+    // Clients used to choose ids, so the duplicate is renumbered.
+    assert_eq!(
+        inputs,
+        [
+            (1, "DSMPL", PipewirePorts::None, 0),
+            (2, "SE02", PipewirePorts::Mono(String::from("se02:out")), 0),
+        ]
+    );
+    assert_eq!(snapshot.outputs.len(), 1);
+    assert_eq!(snapshot.outputs[0].output_type, MixerOutputType::Main);
+    assert_eq!(snapshot.next_ids.input, 3);
+    assert_eq!(snapshot.next_ids.output, 2);
+    assert!(snapshot.plugins.is_empty() && snapshot.scenes.is_empty());
+    assert_eq!(snapshot.journal_sequence, 0);
+}
+
+#[tokio::test]
+async fn truncated_data_file_falls_back_to_the_newest_backup() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = |name: &str| directory.path().join(name).to_string_lossy().into_owned();
+    let (data_file, outputs_file) = (path("pmx_registry.json"), path("outputs.json"));
+    let template = RegistryTemplate::builtin();
+    let mut snapshot = file_reader::snapshot_from_template(&template);
+    for generation in 1..=2 {
+        snapshot.outputs[0].name = format!("written {generation}");
+        file_writer::write_snapshot_file(&data_file, &snapshot)
+            .await
+            .unwrap();
+    }
+    let contents = std::fs::read_to_string(&data_file).unwrap();
+    std::fs::write(&data_file, &contents[..contents.len() / 2]).unwrap();
+
+    let read = file_reader::read_snapshot_file(
+        &data_file,
+        file_reader::LegacyDataFiles {
+            outputs_file: &outputs_file,
+        },
+        &template,
+    )
+    .await
+    .unwrap();
+    assert_eq!(read.outputs[0].name, "written 1");
+}
+
+#[tokio::test]
+async fn snapshots_written_in_a_row_keep_the_previous_generations() {
+    let directory = tempfile::tempdir().unwrap();
+    let data_file = data_file(&directory);
+    let mut snapshot = template_snapshot();
+    let written = file_writer::BACKUP_GENERATIONS + 4;
+    for generation in 1..=written {
+        snapshot.outputs[0].name = format!("written {generation}");
+        file_writer::write_snapshot_file(&data_file, &snapshot)
+            .await
+            .unwrap();
+    }
+
+    let output_name = |path: &str| {
+        let contents = std::fs::read_to_string(path).unwrap();
+        let snapshot: RegistrySnapshot = serde_json::from_str(&contents).unwrap();
+        snapshot.outputs[0].name.clone()
+    };
+    for generation in 1..=file_writer::BACKUP_GENERATIONS {
+        let backup = file_writer::backup_path(&data_file, generation);
+        assert_eq!(
+            output_name(&backup),
+            format!("written {}", written - generation)
+        );
+    }
+    let beyond = file_writer::backup_path(&data_file, file_writer::BACKUP_GENERATIONS + 1);
+    assert!(!std::path::Path::new(&beyond).exists());
+
+    let read = file_reader::read_snapshot_file(
+        &data_file,
+        file_reader::LegacyDataFiles {
+            outputs_file: &directory.path().join("outputs.json").to_string_lossy(),
+        },
+        &RegistryTemplate::builtin(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        serde_json::to_value(read).unwrap(),
+        serde_json::to_value(snapshot).unwrap()
+    );
+}
+
+/// A registry whose snapshot writer waits for 100ms without a change, but
+/// never longer than a second. The journal is kept apart from the data file so
+/// tests can break either on its own.
+fn debounced_registry(
+    data_file: &str,
+    journal_path: &str,
+) -> (Arc<RwLock<Registry>>, SnapshotWriter) {
+    let (persistence, snapshot_writer) = persistence::channel(
+        data_file,
+        Duration::from_millis(100),
+        Duration::from_secs(1),
+        1000,
+    );
+    let registry = Registry::new(
+        template_snapshot(),
+        persistence,
+        Journal::unread(journal_path),
+        false,
+    );
+    (Arc::new(RwLock::new(registry)), snapshot_writer)
+}
+
+async fn add_output_every(registry: &RwLock<Registry>, changes: usize, interval: Duration) {
+    for change in 0..changes {
+        add_outputs(&mut *registry.write().await, &[&format!("output {change}")]).await;
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn burst_of_changes_is_written_once() {
+    let directory = tempfile::tempdir().unwrap();
+    let data_file = data_file(&directory);
+    let journal_path = journal::journal_path(&data_file);
+    let (registry, snapshot_writer) = debounced_registry(&data_file, &journal_path);
+    let (stop_writer, writer_stopped) = tokio::sync::oneshot::channel();
+    let writer = tokio::spawn(snapshot_writer.run(registry.clone(), writer_stopped));
+
+    add_output_every(&registry, 10, Duration::from_millis(50)).await;
+    assert!(!std::path::Path::new(&data_file).exists());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert!(std::path::Path::new(&data_file).exists());
+    assert!(!registry.read().await.persistence_status().write_pending);
+    stop_writer.send(()).unwrap();
+    writer.await.unwrap().unwrap();
+    let second_write = file_writer::backup_path(&data_file, 1);
+    assert!(!std::path::Path::new(&second_write).exists());
+}
+
+#[tokio::test(start_paused = true)]
+async fn steady_changes_are_written_after_the_maximum_delay() {
+    let directory = tempfile::tempdir().unwrap();
+    let data_file = data_file(&directory);
+    let journal_path = journal::journal_path(&data_file);
+    let (registry, snapshot_writer) = debounced_registry(&data_file, &journal_path);
+    let (_stop_writer, writer_stopped) = tokio::sync::oneshot::channel();
+    tokio::spawn(snapshot_writer.run(registry.clone(), writer_stopped));
+
+    add_output_every(&registry, 21, Duration::from_millis(50)).await;
+
+    assert!(std::path::Path::new(&data_file).exists());
+}
+
+#[tokio::test(start_paused = true)]
+async fn failed_writes_are_reported_and_retried() {
+    let directory = tempfile::tempdir().unwrap();
+    let data_file = directory
+        .path()
+        .join("missing")
+        .join("pmx_registry.json")
+        .to_string_lossy()
+        .into_owned();
+    let journal_path = journal::journal_path(&self::data_file(&directory));
+    let (registry, snapshot_writer) = debounced_registry(&data_file, &journal_path);
+    let (stop_writer, writer_stopped) = tokio::sync::oneshot::channel();
+    let writer = tokio::spawn(snapshot_writer.run(registry.clone(), writer_stopped));
+
+    add_output_every(&registry, 1, Duration::from_millis(150)).await;
+    let status = registry.read().await.persistence_status();
+    assert!(status.write_pending);
+    assert_eq!(status.failed_attempts, 1);
+    assert!(status.last_error.unwrap().contains("couldn't write"));
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(
+        registry.read().await.persistence_status().failed_attempts,
+        2
+    );
+    stop_writer.send(()).unwrap();
+    assert!(writer.await.unwrap().is_err());
+}
+
+#[tokio::test(start_paused = true)]
+async fn unwritten_changes_at_shutdown_exit_with_their_own_status() {
+    let directory = tempfile::tempdir().unwrap();
+    let data_file = directory
+        .path()
+        .join("missing")
+        .join("pmx_registry.json")
+        .to_string_lossy()
+        .into_owned();
+    let journal_path = journal::journal_path(&self::data_file(&directory));
+    let (registry, snapshot_writer) = debounced_registry(&data_file, &journal_path);
+    let (stop_writer, writer_stopped) = tokio::sync::oneshot::channel();
+    let writer = tokio::spawn(snapshot_writer.run(registry.clone(), writer_stopped));
+    add_outputs(&mut *registry.write().await, &["monitor"]).await;
+
+    stop_writer.send(()).unwrap();
+    let flushed = writer.await.unwrap();
+
+    assert_eq!(exit_status(Ok(flushed)), EXIT_UNFLUSHED_CHANGES);
+}
+
+#[test]
+fn clean_shutdown_and_failures_exit_with_their_own_status() {
+    assert_eq!(exit_status(Ok(Ok(()))), 0);
+    assert_eq!(exit_status(Err("couldn't bind".into())), EXIT_FAILED);
+}
+
+fn plugin(name: &str) -> PmxPlugin {
+    PmxPlugin {
+        name: String::from(name),
+        plugin_uri: format!("urn:{name}"),
+        ..PmxPlugin::default()
+    }
+}
+
+#[tokio::test]
+async fn register_call_may_only_name_the_id_of_the_entity_it_matches() {
+    let fixture = fixture();
+    let service = &fixture.service;
+    let register = |id| {
+        Request::new(RegisterPluginRequest {
+            plugin: Some(PmxPlugin {
+                id,
+                ..plugin("reverb")
+            }),
+            idempotency_token: None,
+        })
+    };
+    let status = service.register_plugin(register(42)).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument, "{status:?}");
+
+    let id = service
+        .register_plugin(register(0))
+        .await
+        .unwrap()
+        .into_inner()
+        .id;
+    let matched = service.register_plugin(register(id)).await.unwrap();
+    assert_eq!(matched.into_inner().id, id);
+    let status = service.register_plugin(register(id + 1)).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument, "{status:?}");
+    assert_eq!(service.registry.read().await.get_all_plugins().len(), 1);
+
+    for _ in 0..2 {
+        service
+            .register_looper(Request::new(RegisterLooperRequest {
+                loop_number: 3,
+                idempotency_token: None,
+            }))
+            .await
+            .unwrap();
+    }
+    assert_eq!(service.registry.read().await.get_all_loopers().len(), 1);
+}
+
+#[tokio::test]
+async fn token_of_a_register_call_matching_by_natural_key_is_journaled() {
+    let fixture = fixture();
+    let service = &fixture.service;
+    let register = |idempotency_token: Option<&str>| {
+        Request::new(RegisterPluginRequest {
+            plugin: Some(plugin("reverb")),
+            idempotency_token: idempotency_token.map(String::from),
+        })
+    };
+    let id = service
+        .register_plugin(register(None))
+        .await
+        .unwrap()
+        .into_inner()
+        .id;
+    let matched = service.register_plugin(register(Some("T"))).await.unwrap();
+    assert_eq!(matched.into_inner().id, id);
+
+    let (_, entries) = Journal::open(&journal::journal_path(&fixture.data_file), 0).unwrap();
+    let tokens: Vec<_> = entries
+        .iter()
+        .flat_map(|entry| &entry.idempotency_tokens)
+        .map(|token| (token.token.as_str(), token.id))
+        .collect();
+    assert_eq!(tokens, [("T", id)]);
+}
+
+/// A registry started from `snapshot` that replays the journal next to
+/// `data_file`, as after a restart. Covered entries are compacted right away.
+fn open_registry(data_file: &str, snapshot: RegistrySnapshot) -> (Registry, SnapshotWriter) {
+    let (journal, entries) =
+        Journal::open(&journal::journal_path(data_file), snapshot.journal_sequence).unwrap();
+    let (persistence, snapshot_writer) = persistence::channel(
+        data_file,
+        Duration::from_secs(60),
+        Duration::from_secs(60),
+        1,
+    );
+    let mut registry = Registry::new(snapshot, persistence, journal, false);
+    registry.replay(entries);
+    (registry, snapshot_writer)
+}
+
+fn template_snapshot() -> RegistrySnapshot {
+    file_reader::snapshot_from_template(&RegistryTemplate::builtin())
+}
+
+fn test_operation(name: &str) -> Operation {
+    Operation {
+        name: String::from(name),
+        caller: String::from("test"),
+    }
+}
+
+#[tokio::test]
+async fn idempotency_token_is_remembered_across_a_restart() {
+    let directory = tempfile::tempdir().unwrap();
+    let data_file = data_file(&directory);
+    let (mut registry, _snapshot_writer) = open_registry(&data_file, template_snapshot());
+    let id = registry
+        .apply(&test_operation("RegisterPlugin"), |registry| {
+            registry.register_plugin(plugin("reverb"), Some("T"))
+        })
+        .await
+        .unwrap();
+    drop(registry);
+
+    let (mut registry, _snapshot_writer) = open_registry(&data_file, template_snapshot());
+    // The token decides, not the attributes of the retry.
+    let retried = registry
+        .apply(&test_operation("RegisterPlugin"), |registry| {
+            registry.register_plugin(plugin("delay"), Some("T"))
+        })
+        .await
+        .unwrap();
+    assert_eq!(retried, id);
+}
+
+#[tokio::test]
+async fn watcher_resumes_across_a_restart() {
+    let directory = tempfile::tempdir().unwrap();
+    let data_file = data_file(&directory);
+    let (mut registry, _snapshot_writer) = open_registry(&data_file, template_snapshot());
+    let (_, mut receiver) = registry.watch(None).unwrap();
+    for name in ["monitors", "headphones"] {
+        registry
+            .apply(&test_operation("AddOutput"), |registry| {
+                registry.add_output(name, MixerOutputType::Cue)
+            })
+            .await
+            .unwrap();
+    }
+    let seen = receiver.try_recv().unwrap();
+    drop(registry);
+
+    let (registry, _snapshot_writer) = open_registry(&data_file, template_snapshot());
+    let (missed, _) = registry.watch(Some(seen.resume_token)).unwrap();
+    let missed: Vec<_> = missed.iter().map(|change| &change.entity).collect();
+    assert!(
+        matches!(missed[..], [Entity::Output(ref output)] if output.name == "headphones"),
+        "{missed:?}"
+    );
+    // The token of a change the server never made can't be resumed from.
+    assert!(matches!(
+        registry.watch(Some("9.0".parse().unwrap())),
+        Err(RegistryError::ResumeTokenExpired)
+    ));
+}
+
+async fn add_outputs(registry: &mut Registry, names: &[&str]) {
+    for name in names {
+        registry
+            .apply(&test_operation("AddOutput"), |registry| {
+                registry.add_output(name, MixerOutputType::Cue)
+            })
+            .await
+            .unwrap();
+    }
+}
+
+fn output_names(registry: &Registry) -> Vec<String> {
+    registry
+        .get_all_outputs()
+        .iter()
+        .map(|output| output.name.clone())
+        .collect()
+}
+
+#[tokio::test]
+async fn compacting_an_empty_journal_leaves_it_alone() {
+    let directory = tempfile::tempdir().unwrap();
+    let journal_path = journal::journal_path(&data_file(&directory));
+    let journal = Journal::unread(&journal_path);
+
+    journal.compact(5, 0).await.unwrap();
+
+    assert!(!std::path::Path::new(&journal_path).exists());
+}
+
+#[tokio::test]
+async fn journal_is_replayed_onto_a_backup_after_compaction() {
+    let directory = tempfile::tempdir().unwrap();
+    let data_file = data_file(&directory);
+    let (registry, mut snapshot_writer) = open_registry(&data_file, template_snapshot());
+    let registry = RwLock::new(registry);
+    for name in ["monitors", "headphones", "booth"] {
+        add_outputs(&mut *registry.write().await, &[name]).await;
+        snapshot_writer.flush(&registry).await.unwrap();
+    }
+    let expected = output_names(&*registry.read().await);
+    drop(registry);
+
+    // The oldest backup ends at entry 1, so only that entry is compacted.
+    let journal_path = journal::journal_path(&data_file);
+    let (_, entries) = Journal::open(&journal_path, 3).unwrap();
+    let sequences: Vec<_> = entries.iter().map(|entry| entry.sequence).collect();
+    assert_eq!(sequences, [2, 3]);
+
+    let contents = std::fs::read_to_string(&data_file).unwrap();
+    std::fs::write(&data_file, &contents[..contents.len() / 2]).unwrap();
+    let snapshot = file_reader::read_snapshot_file(
+        &data_file,
+        file_reader::LegacyDataFiles {
+            outputs_file: &directory.path().join("outputs.json").to_string_lossy(),
+        },
+        &RegistryTemplate::builtin(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(snapshot.journal_sequence, 2);
+    let (registry, _snapshot_writer) = open_registry(&data_file, snapshot);
+    assert_eq!(output_names(&registry), expected);
+    assert_eq!(registry.snapshot().journal_sequence, 3);
+}
+
+#[tokio::test]
+async fn corrupt_line_in_the_middle_of_the_journal_is_an_error() {
+    let directory = tempfile::tempdir().unwrap();
+    let data_file = data_file(&directory);
+    let (mut registry, _snapshot_writer) = open_registry(&data_file, template_snapshot());
+    add_outputs(&mut registry, &["monitors", "headphones", "booth"]).await;
+    drop(registry);
+
+    let journal_path = journal::journal_path(&data_file);
+    let contents = std::fs::read_to_string(&journal_path).unwrap();
+    let mut lines: Vec<&str> = contents.lines().collect();
+    lines[1] = "{\"sequence\": 2, \"tim";
+    let corrupted = lines.join("\n") + "\n";
+    std::fs::write(&journal_path, &corrupted).unwrap();
+
+    let error = Journal::open(&journal_path, 0).unwrap_err();
+    assert!(
+        matches!(error, JournalError::Corrupt { line: 2, .. }),
+        "{error}"
+    );
+    assert_eq!(std::fs::read_to_string(&journal_path).unwrap(), corrupted);
+}
+
+#[tokio::test]
+async fn torn_last_line_of_the_journal_is_skipped_and_cut_off() {
+    let directory = tempfile::tempdir().unwrap();
+    let data_file = data_file(&directory);
+    let (mut registry, _snapshot_writer) = open_registry(&data_file, template_snapshot());
+    add_outputs(&mut registry, &["monitors", "headphones"]).await;
+    drop(registry);
+
+    let journal_path = journal::journal_path(&data_file);
+    let mut contents = std::fs::read_to_string(&journal_path).unwrap();
+    contents.push_str("{\"sequence\": 3, \"tim");
+    std::fs::write(&journal_path, contents).unwrap();
+
+    let (mut registry, _snapshot_writer) = open_registry(&data_file, template_snapshot());
+    assert_eq!(registry.snapshot().journal_sequence, 2);
+    add_outputs(&mut registry, &["booth"]).await;
+    let (_, entries) = Journal::open(&journal_path, 0).unwrap();
+    let sequences: Vec<_> = entries.iter().map(|entry| entry.sequence).collect();
+    assert_eq!(sequences, [1, 2, 3]);
+}
+
+/// Writes the template snapshot to `data_file` and cuts it off halfway, as if
+/// the disk filled up while writing it.
+async fn write_truncated_snapshot(data_file: &str) -> String {
+    file_writer::write_snapshot_file(data_file, &template_snapshot())
+        .await
+        .unwrap();
+    let contents = std::fs::read_to_string(data_file).unwrap();
+    let truncated = String::from(&contents[..contents.len() / 2]);
+    std::fs::write(data_file, &truncated).unwrap();
+    truncated
+}
+
+/// Loads the registry next to `data_file` the way the server does at startup.
+async fn start(
+    data_file: &str,
+    on_corrupt_data: CorruptDataPolicy,
+) -> Result<(Registry, SnapshotWriter), Box<dyn std::error::Error>> {
+    let (persistence, snapshot_writer) = persistence::channel(
+        data_file,
+        Duration::from_secs(60),
+        Duration::from_secs(60),
+        1000,
+    );
+    let outputs_file = format!("{data_file}.outputs");
+    let registry = load_registry(
+        data_file,
+        &outputs_file,
+        &RegistryTemplate::builtin(),
+        on_corrupt_data,
+        persistence,
+    )
+    .await?;
+    Ok((registry, snapshot_writer))
+}
+
+#[tokio::test]
+async fn truncated_data_file_refuses_startup_with_the_position() {
+    let directory = tempfile::tempdir().unwrap();
+    let data_file = data_file(&directory);
+    let truncated = write_truncated_snapshot(&data_file).await;
+
+    let why = start(&data_file, CorruptDataPolicy::Refuse)
+        .await
+        .unwrap_err();
+    match why.downcast_ref::<ReadError>() {
+        Some(ReadError::Parse { path, line, .. }) => {
+            assert_eq!(path, &data_file);
+            assert_eq!(*line, truncated.lines().count());
+        }
+        _ => panic!("expected a parse error, got {why:?}"),
+    }
+    assert_eq!(std::fs::read_to_string(&data_file).unwrap(), truncated);
+}
+
+#[tokio::test]
+async fn truncated_data_file_is_quarantined() {
+    let directory = tempfile::tempdir().unwrap();
+    let data_file = data_file(&directory);
+    let truncated = write_truncated_snapshot(&data_file).await;
+
+    let (registry, _snapshot_writer) = start(&data_file, CorruptDataPolicy::Quarantine)
+        .await
+        .unwrap();
+    assert!(!registry.is_read_only());
+    let template_outputs: Vec<_> = template_snapshot()
+        .outputs
+        .into_iter()
+        .map(|output| output.name)
+        .collect();
+    assert_eq!(output_names(&registry), template_outputs);
+    assert!(!std::path::Path::new(&data_file).exists());
+    let quarantined: Vec<_> = std::fs::read_dir(directory.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.to_string_lossy()
+                .starts_with(&format!("{data_file}.corrupt-"))
+        })
+        .collect();
+    assert_eq!(quarantined.len(), 1, "{quarantined:?}");
+    assert_eq!(std::fs::read_to_string(&quarantined[0]).unwrap(), truncated);
+}
+
+#[tokio::test]
+async fn truncated_data_file_is_served_read_only() {
+    let directory = tempfile::tempdir().unwrap();
+    let data_file = data_file(&directory);
+    let truncated = write_truncated_snapshot(&data_file).await;
+
+    let (mut registry, _snapshot_writer) = start(&data_file, CorruptDataPolicy::ReadOnly)
+        .await
+        .unwrap();
+    assert!(registry.is_read_only());
+    let added = registry
+        .apply(&test_operation("AddOutput"), |registry| {
+            registry.add_output("monitors", MixerOutputType::Cue)
+        })
+        .await;
+    assert!(matches!(added, Err(RegistryError::ReadOnly)), "{added:?}");
+    assert_eq!(std::fs::read_to_string(&data_file).unwrap(), truncated);
+}
+
+#[tokio::test]
+async fn truncated_data_file_starts_from_the_newest_backup() {
+    let directory = tempfile::tempdir().unwrap();
+    let data_file = data_file(&directory);
+    let (mut registry, mut snapshot_writer) = open_registry(&data_file, template_snapshot());
+    add_outputs(&mut registry, &["monitors"]).await;
+    let registry = RwLock::new(registry);
+    snapshot_writer.flush(&registry).await.unwrap();
+    let expected = output_names(&*registry.read().await);
+    add_outputs(&mut *registry.write().await, &["headphones"]).await;
+    snapshot_writer.flush(&registry).await.unwrap();
+    drop(registry);
+    // Without the journal the backup is all that is left of the last change.
+    std::fs::remove_file(journal::journal_path(&data_file)).unwrap();
+    let contents = std::fs::read_to_string(&data_file).unwrap();
+    std::fs::write(&data_file, &contents[..contents.len() / 2]).unwrap();
+
+    let (registry, _snapshot_writer) = start(&data_file, CorruptDataPolicy::Refuse).await.unwrap();
+    assert!(!registry.is_read_only());
+    assert_eq!(output_names(&registry), expected);
+}
+
+#[tokio::test]
+async fn added_input_can_be_read_until_it_is_removed() {
+    let directory = tempfile::tempdir().unwrap();
+    let template = RegistryTemplate {
+        inputs: vec![TemplateInput {
+            name: String::from("DSMPL"),
+            group_channel_strip_name: String::from("Synths"),
+        }],
+        ..RegistryTemplate::builtin()
+    };
+    let (mut registry, _snapshot_writer) = open_registry(
+        &data_file(&directory),
+        file_reader::snapshot_from_template(&template),
+    );
+    let id = registry
+        .apply(&test_operation("AddInput"), |registry| {
+            registry.add_input("guitar", "Synths")
+        })
+        .await
+        .unwrap();
+    let input = registry.input_by_id(id).unwrap();
+    assert_eq!(input.name, "guitar");
+    assert_eq!(input.group_channel_strip_name, "Synths");
+    assert_eq!(input.pipewire_ports, PipewirePorts::None);
+
+    let removed = registry
+        .apply(&test_operation("RemoveInput"), |registry| {
+            registry.remove_input(id)
+        })
+        .await
+        .unwrap();
+    assert_eq!(removed.name, "guitar");
+    let not_found = RegistryError::NotFound {
+        kind: EntityKind::Input,
+        id,
+    };
+    assert_eq!(registry.input_by_id(id), Err(not_found.clone()));
+    let removed_again = registry
+        .apply(&test_operation("RemoveInput"), |registry| {
+            registry.remove_input(id)
+        })
+        .await;
+    assert_eq!(removed_again, Err(not_found));
+}
+
+#[tokio::test]
+async fn output_type_and_name_updates_are_read_back() {
+    let directory = tempfile::tempdir().unwrap();
+    let (mut registry, _snapshot_writer) =
+        open_registry(&data_file(&directory), template_snapshot());
+    let id = registry
+        .apply(&test_operation("AddOutput"), |registry| {
+            registry.add_output("booth", MixerOutputType::Cue)
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        registry.output_by_id(id).unwrap().output_type,
+        MixerOutputType::Cue
+    );
+
+    registry
+        .apply(&test_operation("UpdateOutputType"), |registry| {
+            registry.update_output_type(id, MixerOutputType::Main, None)
+        })
+        .await
+        .unwrap();
+    registry
+        .apply(&test_operation("UpdateOutputName"), |registry| {
+            registry.update_output_name(id, "main 2", None)
+        })
+        .await
+        .unwrap();
+    let output = registry.output_by_id(id).unwrap();
+    assert_eq!(output.output_type, MixerOutputType::Main);
+    assert_eq!(output.name, "main 2");
+
+    registry
+        .apply(&test_operation("RemoveOutput"), |registry| {
+            registry.remove_output(id)
+        })
+        .await
+        .unwrap();
+    let not_found = RegistryError::NotFound {
+        kind: EntityKind::Output,
+        id,
+    };
+    assert_eq!(registry.output_by_id(id), Err(not_found.clone()));
+    let updated = registry
+        .apply(&test_operation("UpdateOutputType"), |registry| {
+            registry.update_output_type(id, MixerOutputType::Cue, None)
+        })
+        .await;
+    assert_eq!(updated, Err(not_found));
+}
+
+#[tokio::test]
+async fn registered_looper_and_plugin_are_updated_and_unregistered() {
+    let directory = tempfile::tempdir().unwrap();
+    let (mut registry, _snapshot_writer) =
+        open_registry(&data_file(&directory), template_snapshot());
+    let looper = |id, name: &str| PmxLooper {
+        id,
+        name: String::from(name),
+        loop_number: 1,
+        ..PmxLooper::default()
+    };
+    let looper_id = registry
+        .apply(&test_operation("RegisterLooper"), |registry| {
+            registry.register_looper(looper(0, "drums"), None)
+        })
+        .await
+        .unwrap();
+    registry
+        .apply(&test_operation("UpdateLooper"), |registry| {
+            registry.update_looper(looper(looper_id, "drum loop"), None)
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        registry.get_looper_by_id(looper_id).unwrap().name,
+        "drum loop"
+    );
+    let plugin_id = registry
+        .apply(&test_operation("RegisterPlugin"), |registry| {
+            registry.register_plugin(plugin("reverb"), None)
+        })
+        .await
+        .unwrap();
+    assert_eq!(registry.get_plugin_by_id(plugin_id).unwrap().name, "reverb");
+
+    registry
+        .apply(&test_operation("UnregisterLooper"), |registry| {
+            registry.unregister_looper(looper_id)
+        })
+        .await
+        .unwrap();
+    let unregistered = registry
+        .apply(&test_operation("UnregisterPlugin"), |registry| {
+            registry.unregister_plugin(plugin_id, false)
+        })
+        .await
+        .unwrap();
+    assert_eq!(unregistered.name, "reverb");
+    assert_eq!(
+        registry.get_looper_by_id(looper_id),
+        Err(RegistryError::NotFound {
+            kind: EntityKind::Looper,
+            id: looper_id,
+        })
+    );
+    assert_eq!(
+        registry.get_plugin_by_id(plugin_id),
+        Err(RegistryError::NotFound {
+            kind: EntityKind::Plugin,
+            id: plugin_id,
+        })
+    );
+    let unregistered_again = registry
+        .apply(&test_operation("UnregisterLooper"), |registry| {
+            registry.unregister_looper(looper_id)
+        })
+        .await;
+    assert!(
+        matches!(unregistered_again, Err(RegistryError::NotFound { .. })),
+        "{unregistered_again:?}"
+    );
+}
+
+/// Registers the plugins a basic channel strip named `name` needs and the
+/// strip itself. Returns the strip's id and those of its plugins.
+async fn register_channel_strip(registry: &mut Registry, name: &str) -> (u32, Vec<u32>) {
+    let mut plugin_ids = Vec::new();
+    for role in ["saturator", "compressor", "equalizer", "gain"] {
+        let id = registry
+            .apply(&test_operation("RegisterPlugin"), |registry| {
+                registry.register_plugin(plugin(&format!("{name}-{role}")), None)
+            })
+            .await
+            .unwrap();
+        plugin_ids.push(id);
+    }
+    let channel_strip = PmxChannelStrip {
+        name: String::from(name),
+        channel_strip_type: PmxChannelStripType::Basic as i32,
+        saturator_plugin_id: plugin_ids[0],
+        compressor_plugin_id: plugin_ids[1],
+        equalizer_plugin_id: plugin_ids[2],
+        gain_plugin_id: plugin_ids[3],
+        ..PmxChannelStrip::default()
+    };
+    let id = registry
+        .apply(&test_operation("RegisterChannelStrip"), |registry| {
+            registry.register_channel_strip(channel_strip, None)
+        })
+        .await
+        .unwrap();
+    (id, plugin_ids)
+}
+
+#[tokio::test]
+async fn references_to_missing_entities_are_rejected_without_a_change() {
+    let directory = tempfile::tempdir().unwrap();
+    let (mut registry, _snapshot_writer) =
+        open_registry(&data_file(&directory), template_snapshot());
+    let (channel_strip_id, plugin_ids) = register_channel_strip(&mut registry, "vocals").await;
+    let before = serde_json::to_value(registry.snapshot()).unwrap();
+
+    let registered = registry
+        .apply(&test_operation("RegisterChannelStrip"), |registry| {
+            registry.register_channel_strip(
+                PmxChannelStrip {
+                    name: String::from("drums"),
+                    saturator_plugin_id: plugin_ids[0],
+                    compressor_plugin_id: plugin_ids[1],
+                    equalizer_plugin_id: plugin_ids[2],
+                    gain_plugin_id: 99,
+                    ..PmxChannelStrip::default()
+                },
+                None,
+            )
+        })
+        .await;
+    assert_eq!(
+        registered,
+        Err(RegistryError::InvalidReference {
+            kind: EntityKind::Plugin,
+            id: 99,
+        })
+    );
+    let registered = registry
+        .apply(&test_operation("RegisterOutputStage"), |registry| {
+            registry.register_output_stage(
+                PmxOutputStage {
+                    name: String::from("main"),
+                    left_channel_strip_id: channel_strip_id,
+                    right_channel_strip_id: 77,
+                    cross_fader_plugin_id: plugin_ids[0],
+                    ..PmxOutputStage::default()
+                },
+                None,
+            )
+        })
+        .await;
+    assert_eq!(
+        registered,
+        Err(RegistryError::InvalidReference {
+            kind: EntityKind::ChannelStrip,
+            id: 77,
+        })
+    );
+    assert_eq!(serde_json::to_value(registry.snapshot()).unwrap(), before);
+}
+
+#[tokio::test]
+async fn cross_faded_channel_strip_without_a_cross_fader_is_an_invalid_argument() {
+    let directory = tempfile::tempdir().unwrap();
+    let (mut registry, _snapshot_writer) =
+        open_registry(&data_file(&directory), template_snapshot());
+
+    let registered = registry
+        .apply(&test_operation("RegisterChannelStrip"), |registry| {
+            registry.register_channel_strip(channel_strip(PmxChannelStripType::CrossFaded), None)
+        })
+        .await;
+
+    let error = registered.unwrap_err();
+    assert_eq!(
+        error,
+        RegistryError::MissingCrossFader {
+            name: String::from("vocals")
+        }
+    );
+    assert_eq!(Status::from(error).code(), Code::InvalidArgument);
+    assert!(registry.snapshot().channel_strips.is_empty());
+}
+
+#[tokio::test]
+async fn used_entities_are_only_unregistered_with_their_dependents() {
+    let directory = tempfile::tempdir().unwrap();
+    let (mut registry, _snapshot_writer) =
+        open_registry(&data_file(&directory), template_snapshot());
+    let (channel_strip_id, plugin_ids) = register_channel_strip(&mut registry, "vocals").await;
+    let output_stage_id = registry
+        .apply(&test_operation("RegisterOutputStage"), |registry| {
+            registry.register_output_stage(
+                PmxOutputStage {
+                    name: String::from("main"),
+                    left_channel_strip_id: channel_strip_id,
+                    right_channel_strip_id: channel_strip_id,
+                    cross_fader_plugin_id: plugin_ids[3],
+                    ..PmxOutputStage::default()
+                },
+                None,
+            )
+        })
+        .await
+        .unwrap();
+    let before = serde_json::to_value(registry.snapshot()).unwrap();
+
+    let unregistered = registry
+        .apply(&test_operation("UnregisterPlugin"), |registry| {
+            registry.unregister_plugin(plugin_ids[0], false)
+        })
+        .await;
+    assert_eq!(
+        unregistered,
+        Err(RegistryError::InUse {
+            kind: EntityKind::Plugin,
+            id: plugin_ids[0],
+            used_by_kind: EntityKind::ChannelStrip,
+            used_by_id: channel_strip_id,
+        })
+    );
+    let unregistered = registry
+        .apply(&test_operation("UnregisterChannelStrip"), |registry| {
+            registry.unregister_channel_strip(channel_strip_id, false)
+        })
+        .await;
+    assert_eq!(
+        unregistered,
+        Err(RegistryError::InUse {
+            kind: EntityKind::ChannelStrip,
+            id: channel_strip_id,
+            used_by_kind: EntityKind::OutputStage,
+            used_by_id: output_stage_id,
+        })
+    );
+    assert_eq!(serde_json::to_value(registry.snapshot()).unwrap(), before);
+
+    registry
+        .apply(&test_operation("UnregisterPlugin"), |registry| {
+            registry.unregister_plugin(plugin_ids[0], true)
+        })
+        .await
+        .unwrap();
+    assert!(registry.get_all_channel_strips().is_empty());
+    assert!(registry.get_all_output_stages().is_empty());
+    let plugins: Vec<_> = registry.get_all_plugins().iter().map(|p| p.id).collect();
+    assert_eq!(plugins, plugin_ids[1..]);
+}
+
+#[tokio::test]
+async fn undo_and_redo_step_over_the_last_operation() {
+    let directory = tempfile::tempdir().unwrap();
+    let (mut registry, _snapshot_writer) =
+        open_registry(&data_file(&directory), template_snapshot());
+    add_outputs(&mut registry, &["monitors"]).await;
+    let id = registry.get_all_outputs().last().unwrap().id;
+    registry
+        .apply(&test_operation("UpdateOutputName"), |registry| {
+            registry.update_output_name(id, "speakers", None)
+        })
+        .await
+        .unwrap();
+    let name = |registry: &Registry| registry.output_by_id(id).unwrap().name.clone();
+
+    let undone = registry
+        .step_history(&test_operation("Undo"), HistoryDirection::Undo)
+        .await
+        .unwrap();
+    assert_eq!(undone, "UpdateOutputName");
+    assert_eq!(name(&registry), "monitors");
+
+    registry
+        .step_history(&test_operation("Redo"), HistoryDirection::Redo)
+        .await
+        .unwrap();
+    assert_eq!(name(&registry), "speakers");
+    let redone_again = registry
+        .step_history(&test_operation("Redo"), HistoryDirection::Redo)
+        .await;
+    assert!(
+        matches!(
+            redone_again,
+            Err(RegistryError::HistoryEmpty {
+                direction: HistoryDirection::Redo
+            })
+        ),
+        "{redone_again:?}"
+    );
+    // The redone operation is journaled like any other.
+    let (_, entries) = Journal::open(&journal::journal_path(&data_file(&directory)), 0).unwrap();
+    let operations: Vec<_> = entries
+        .iter()
+        .map(|entry| entry.operation.as_str())
+        .collect();
+    assert_eq!(
+        operations,
+        ["AddOutput", "UpdateOutputName", "Undo", "Redo"]
+    );
+}
+
+#[tokio::test]
+async fn undo_and_redo_move_revisions_forward() {
+    let directory = tempfile::tempdir().unwrap();
+    let (mut registry, _snapshot_writer) =
+        open_registry(&data_file(&directory), template_snapshot());
+    add_outputs(&mut registry, &["monitors"]).await;
+    let id = registry.get_all_outputs().last().unwrap().id;
+    registry
+        .apply(&test_operation("UpdateOutputName"), |registry| {
+            registry.update_output_name(id, "speakers", Some(0))
+        })
+        .await
+        .unwrap();
+    let output = |registry: &Registry| {
+        let output = registry.output_by_id(id).unwrap();
+        (output.name.clone(), output.revision)
+    };
+    assert_eq!(output(&registry), (String::from("speakers"), 1));
+
+    let undone = registry
+        .step_history(&test_operation("Undo"), HistoryDirection::Undo)
+        .await
+        .unwrap();
+    assert_eq!(undone, "UpdateOutputName");
+    assert_eq!(output(&registry), (String::from("monitors"), 2));
+
+    registry
+        .step_history(&test_operation("Redo"), HistoryDirection::Redo)
+        .await
+        .unwrap();
+    assert_eq!(output(&registry), (String::from("speakers"), 3));
+    // A writer that read the output before the undo is turned away.
+    let stale = registry
+        .apply(&test_operation("UpdateOutputName"), |registry| {
+            registry.update_output_name(id, "wedges", Some(1))
+        })
+        .await;
+    assert!(
+        matches!(
+            stale,
+            Err(RegistryError::RevisionMismatch { actual: 3, .. })
+        ),
+        "{stale:?}"
+    );
+}
+
+#[tokio::test]
+async fn input_update_with_a_stale_revision_is_aborted() {
+    let fixture = fixture();
+    let service = &fixture.service;
+    register_basic_channel_strip(service, "drums").await;
+    let kick = service
+        .add_input(add_input("kick", "drums"))
+        .await
+        .unwrap()
+        .into_inner();
+    let rename = |name: &str, expected_revision| {
+        Request::new(UpdateInputNameRequest {
+            id: kick.id,
+            name: String::from(name),
+            expected_revision,
+        })
+    };
+
+    let renamed = service
+        .update_input_name(rename("snare", Some(kick.revision)))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(renamed.revision > kick.revision);
+    let stale = service
+        .update_input_name(rename("tom", Some(kick.revision)))
+        .await
+        .unwrap_err();
+    assert_eq!(stale.code(), Code::Aborted);
+    assert_eq!(error_info(&stale).reason, "REVISION_MISMATCH");
+    let renamed = service
+        .update_input_name(rename("hat", None))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(renamed.name, "hat");
+}
+
+#[tokio::test]
+async fn output_update_with_a_stale_revision_is_aborted() {
+    let fixture = fixture();
+    let service = &fixture.service;
+    let booth = service
+        .add_output(Request::new(AddOutputRequest {
+            name: String::from("Booth"),
+            output_type: 0,
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    let rename = |name: &str, expected_revision| {
+        Request::new(UpdateOutputNameRequest {
+            id: booth.id,
+            name: String::from(name),
+            expected_revision,
+        })
+    };
+
+    let renamed = service
+        .update_output_name(rename("Stage", Some(booth.revision)))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(renamed.revision > booth.revision);
+    let stale = service
+        .update_output_name(rename("Monitor", Some(booth.revision)))
+        .await
+        .unwrap_err();
+    assert_eq!(stale.code(), Code::Aborted);
+    let output = service
+        .get_output(Request::new(ByIdRequest { id: booth.id }))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(output.name, "Stage");
+    let renamed = service
+        .update_output_name(rename("Monitor", None))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(renamed.name, "Monitor");
+}
+
+#[tokio::test]
+async fn failed_batch_reverts_its_changes_and_idempotency_tokens() {
+    let fixture = fixture();
+    let service = &fixture.service;
+    let register = |plugin: PmxPlugin| RegisterPluginRequest {
+        plugin: Some(plugin),
+        idempotency_token: Some(String::from("T")),
+    };
+    let mutation = |mutation| BatchMutation {
+        mutation: Some(mutation),
+    };
+    let status = service
+        .apply_batch(Request::new(ApplyBatchRequest {
+            mutations: vec![
+                mutation(Requested::RegisterPlugin(register(plugin("reverb")))),
+                mutation(Requested::RemoveInput(ByIdRequest { id: 9999 })),
+            ],
+        }))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::NotFound, "{status:?}");
+    let registry = service.registry.read().await;
+    assert!(registry.get_all_plugins().is_empty());
+    drop(registry);
+
+    let registered = service
+        .register_plugin(Request::new(register(plugin("delay"))))
+        .await
+        .unwrap()
+        .into_inner();
+    // The token decides, not the attributes of the retry.
+    let retried = service
+        .register_plugin(Request::new(register(PmxPlugin {
+            name: String::from("tape delay"),
+            ..plugin("delay")
+        })))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(retried.id, registered.id);
+}
+
+fn scene_by_name(name: &str) -> Request<SceneByNameRequest> {
+    Request::new(SceneByNameRequest {
+        name: String::from(name),
+    })
+}
+
+#[tokio::test]
+async fn recalled_scene_follows_a_renamed_channel_strip() {
+    let fixture = fixture();
+    let service = &fixture.service;
+    let drums = register_basic_channel_strip(service, "drums").await;
+    let kick = service
+        .add_input(add_input("kick", "drums"))
+        .await
+        .unwrap()
+        .into_inner();
+    service
+        .save_scene(Request::new(SaveSceneRequest {
+            name: String::from("rehearsal"),
+        }))
+        .await
+        .unwrap();
+    service
+        .update_channel_strip(Request::new(UpdateChannelStripRequest {
+            channel_strip: Some(PmxChannelStrip {
+                name: String::from("percussion"),
+                ..drums
+            }),
+            expected_revision: None,
+        }))
+        .await
+        .unwrap();
+
+    let recall = service
+        .recall_scene(scene_by_name("rehearsal"))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(recall.missing_groups.is_empty());
+    let kick = service
+        .get_input(Request::new(ByIdRequest { id: kick.id }))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(kick.group_channel_strip_name, "percussion");
+}
+
+#[tokio::test]
+async fn recalled_scene_reports_groups_that_are_gone() {
+    let fixture = fixture();
+    let service = &fixture.service;
+    let drums = register_basic_channel_strip(service, "drums").await;
+    register_basic_channel_strip(service, "bass").await;
+    let kick = service
+        .add_input(add_input("kick", "drums"))
+        .await
+        .unwrap()
+        .into_inner();
+    service
+        .save_scene(Request::new(SaveSceneRequest {
+            name: String::from("rehearsal"),
+        }))
+        .await
+        .unwrap();
+    service
+        .move_inputs_to_group(Request::new(MoveInputsToGroupRequest {
+            input_ids: vec![kick.id],
+            group: String::from("bass"),
+        }))
+        .await
+        .unwrap();
+    service
+        .unregister_channel_strip(Request::new(UnregisterRequest {
+            id: drums.id,
+            cascade: false,
+        }))
+        .await
+        .unwrap();
+
+    let recall = service
+        .recall_scene(scene_by_name("rehearsal"))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(recall.missing_groups, ["drums"]);
+    let kick = service
+        .get_input(Request::new(ByIdRequest { id: kick.id }))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(kick.group_channel_strip_name, "bass");
+}
+
+#[tokio::test]
+async fn saved_scene_is_diffed_against_changes_until_it_is_deleted() {
+    let fixture = fixture();
+    let service = &fixture.service;
+    register_basic_channel_strip(service, "drums").await;
+    let kick = service
+        .add_input(add_input("kick", "drums"))
+        .await
+        .unwrap()
+        .into_inner();
+    service
+        .save_scene(Request::new(SaveSceneRequest {
+            name: String::from("rehearsal"),
+        }))
+        .await
+        .unwrap();
+    let diff = service
+        .diff_scene(scene_by_name("rehearsal"))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(diff.differences.is_empty(), "{diff:?}");
+
+    service
+        .update_input_name(Request::new(UpdateInputNameRequest {
+            id: kick.id,
+            name: String::from("snare"),
+            expected_revision: None,
+        }))
+        .await
+        .unwrap();
+    let diff = service
+        .diff_scene(scene_by_name("rehearsal"))
+        .await
+        .unwrap()
+        .into_inner();
+    let [difference] = diff.differences.as_slice() else {
+        panic!("{diff:?}");
+    };
+    assert_eq!(difference.id, kick.id);
+    assert_eq!(difference.field, "name");
+    assert_eq!(difference.current.as_deref(), Some("snare"));
+    assert_eq!(difference.scene.as_deref(), Some("kick"));
+
+    let deleted = service
+        .delete_scene(scene_by_name("rehearsal"))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(deleted.name, "rehearsal");
+    let diffed = service.diff_scene(scene_by_name("rehearsal")).await;
+    assert_eq!(diffed.unwrap_err().code(), Code::NotFound);
+}
+
+#[tokio::test]
+async fn unknown_scene_is_not_found() {
+    let fixture = fixture();
+
+    let recalled = fixture.service.recall_scene(scene_by_name("gig")).await;
+
+    let status = recalled.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+    assert_eq!(error_info(&status).metadata["name"], "gig");
+}
+
+/// A registry with `pad` in group `Keys` and `lead` and `hold` in group
+/// `Synths`, and a scene `set` saved in that state.
+async fn registry_with_a_saved_scene(data_file: &str) -> (Registry, SnapshotWriter) {
+    let template = RegistryTemplate {
+        inputs: [("pad", "Keys"), ("lead", "Synths"), ("hold", "Synths")]
+            .map(|(name, group)| TemplateInput {
+                name: String::from(name),
+                group_channel_strip_name: String::from(group),
+            })
+            .into(),
+        ..RegistryTemplate::builtin()
+    };
+    let (mut registry, snapshot_writer) =
+        open_registry(data_file, file_reader::snapshot_from_template(&template));
+    registry
+        .apply(&test_operation("SaveScene"), |registry| {
+            registry.save_scene("set")
+        })
+        .await
+        .unwrap();
+    (registry, snapshot_writer)
+}
+
+fn input_id(registry: &Registry, name: &str) -> u32 {
+    registry
+        .get_all_inputs()
+        .iter()
+        .find(|input| input.name == name)
+        .unwrap()
+        .id
+}
+
+async fn remove_inputs(registry: &mut Registry, ids: &[u32]) {
+    for &id in ids {
+        registry
+            .apply(&test_operation("RemoveInput"), |registry| {
+                registry.remove_input(id)
+            })
+            .await
+            .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn recalled_scene_checks_groups_before_moving_inputs() {
+    let directory = tempfile::tempdir().unwrap();
+    let (mut registry, _snapshot_writer) =
+        registry_with_a_saved_scene(&data_file(&directory)).await;
+    let (pad, lead, hold) = (
+        input_id(&registry, "pad"),
+        input_id(&registry, "lead"),
+        input_id(&registry, "hold"),
+    );
+    // Swap `pad` and `lead`, so moving `pad` back empties `Synths` before
+    // `lead` returns to it.
+    for (id, group) in [(lead, "Keys"), (pad, "Synths")] {
+        registry
+            .apply(&test_operation("MoveInputsToGroup"), |registry| {
+                registry.move_inputs_to_group(&[id], group)
+            })
+            .await
+            .unwrap();
+    }
+    remove_inputs(&mut registry, &[hold]).await;
+
+    let recall = registry
+        .apply(&test_operation("RecallScene"), |registry| {
+            registry.recall_scene("set")
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(recall.missing_input_ids, [hold]);
+    assert!(recall.missing_groups.is_empty(), "{recall:?}");
+    let group = |id| {
+        registry
+            .input_by_id(id)
+            .unwrap()
+            .group_channel_strip_name
+            .clone()
+    };
+    assert_eq!(group(pad), "Keys");
+    assert_eq!(group(lead), "Synths");
+}
+
+#[tokio::test]
+async fn recalled_scene_reports_missing_inputs_without_their_groups() {
+    let directory = tempfile::tempdir().unwrap();
+    let (mut registry, _snapshot_writer) =
+        registry_with_a_saved_scene(&data_file(&directory)).await;
+    let (lead, hold) = (input_id(&registry, "lead"), input_id(&registry, "hold"));
+    remove_inputs(&mut registry, &[lead, hold]).await;
+
+    let recall = registry
+        .apply(&test_operation("RecallScene"), |registry| {
+            registry.recall_scene("set")
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(recall.missing_input_ids, [lead, hold]);
+    assert!(recall.missing_groups.is_empty(), "{recall:?}");
+}
+
+fn loopers(names: &[&str]) -> Vec<Looper> {
+    names
+        .iter()
+        .zip(1..)
+        .map(|(name, id)| Looper {
+            id,
+            name: String::from(*name),
+            loop_number: id,
+            revision: 0,
+        })
+        .collect()
+}
+
+fn page(order: Order, descending: bool, size: usize, after: Option<&str>) -> Page {
+    Page {
+        order,
+        descending,
+        size,
+        after: after.map(|token| token.parse().unwrap()),
+    }
+}
+
+/// The ids on the page and the token of the next one.
+fn page_ids(page: &Page, loopers: &[Looper]) -> (Vec<u32>, Option<String>) {
+    let (listed, next) = page.of(loopers.iter());
+    (
+        listed.iter().map(|looper| looper.id).collect(),
+        next.map(|token| token.to_string()),
+    )
+}
+
+#[test]
+fn descending_pages_continue_after_the_token() {
+    let loopers = loopers(&["a", "b", "c", "d", "e"]);
+
+    let (ids, next) = page_ids(&page(Order::Id, true, 2, None), &loopers);
+    assert_eq!(ids, [5, 4]);
+    let (ids, next) = page_ids(&page(Order::Id, true, 2, next.as_deref()), &loopers);
+    assert_eq!(ids, [3, 2]);
+    let (ids, next) = page_ids(&page(Order::Id, true, 2, next.as_deref()), &loopers);
+    assert_eq!(ids, [1]);
+    assert_eq!(next, None);
+}
+
+#[test]
+fn entities_with_the_same_name_are_paged_by_id() {
+    let loopers = loopers(&["b", "a", "b", "a", "b"]);
+
+    let (ids, next) = page_ids(&page(Order::Name, false, 3, None), &loopers);
+    assert_eq!(ids, [2, 4, 1]);
+    let (ids, next) = page_ids(&page(Order::Name, false, 3, next.as_deref()), &loopers);
+    assert_eq!(ids, [3, 5]);
+    assert_eq!(next, None);
+}
+
+#[test]
+fn page_continues_after_an_entity_removed_since() {
+    let mut loopers = loopers(&["a", "b", "c", "d", "e"]);
+
+    let (ids, next) = page_ids(&page(Order::Name, false, 3, None), &loopers);
+    assert_eq!(ids, [1, 2, 3]);
+    loopers.retain(|looper| looper.id != 3);
+    let (ids, _) = page_ids(&page(Order::Name, false, 3, next.as_deref()), &loopers);
+    assert_eq!(ids, [4, 5]);
+}
+
+#[test]
+fn page_size_zero_lists_everything() {
+    let loopers = loopers(&["a", "b", "c"]);
+
+    let (ids, next) = page_ids(&page(Order::Id, false, 0, None), &loopers);
+
+    assert_eq!(ids, [1, 2, 3]);
+    assert_eq!(next, None);
+}
+
+#[tokio::test]
+async fn malformed_page_token_is_rejected() {
+    let fixture = fixture();
+
+    let listed = fixture
+        .service
+        .list_loopers(Request::new(ListLoopersRequest {
+            page_token: String::from("last"),
+            ..ListLoopersRequest::default()
+        }))
+        .await;
+
+    assert_invalid_argument(listed, &["page_token"]);
+}
+
+#[tokio::test]
+async fn channel_strips_are_listed_in_pages() {
+    let fixture = fixture();
+    let service = &fixture.service;
+    for name in ["vocals", "drums", "bass"] {
+        register_basic_channel_strip(service, name).await;
+    }
+    let list = |page_token| {
+        Request::new(ListChannelStripsRequest {
+            order_by: PmxListOrder::ByName as i32,
+            page_size: 2,
+            page_token,
+            ..ListChannelStripsRequest::default()
+        })
+    };
+
+    let first = service
+        .list_channel_strips(list(String::new()))
+        .await
+        .unwrap()
+        .into_inner();
+    let second = service
+        .list_channel_strips(list(first.next_page_token.clone()))
+        .await
+        .unwrap()
+        .into_inner();
+
+    let names = |channel_strips: &[PmxChannelStrip]| {
+        channel_strips
+            .iter()
+            .map(|channel_strip| channel_strip.name.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names(&first.channel_strips), ["bass", "drums"]);
+    assert_eq!(names(&second.channel_strips), ["vocals"]);
+    assert!(second.next_page_token.is_empty());
+}
+
+/// The `ErrorInfo` in the status details.
+fn error_info(status: &Status) -> ErrorInfo {
+    let details = StatusDetails::decode(status.details()).unwrap();
+    let detail = details
+        .details
+        .iter()
+        .find(|detail| detail.type_url.ends_with("google.rpc.ErrorInfo"))
+        .unwrap();
+    ErrorInfo::decode(detail.value.as_slice()).unwrap()
+}
+
+#[test]
+fn registry_errors_map_to_codes_and_reasons() {
+    let cases = [
+        (
+            RegistryError::NotFound {
+                kind: EntityKind::Input,
+                id: 3,
+            },
+            Code::NotFound,
+            "NOT_FOUND",
+        ),
+        (
+            RegistryError::AlreadyExists {
+                kind: EntityKind::Output,
+                id: 3,
+            },
+            Code::AlreadyExists,
+            "ALREADY_EXISTS",
+        ),
+        (
+            RegistryError::UnexpectedId {
+                kind: EntityKind::Looper,
+                id: 3,
+            },
+            Code::InvalidArgument,
+            "UNEXPECTED_ID",
+        ),
+        (
+            RegistryError::MissingCrossFader {
+                name: String::from("vocals"),
+            },
+            Code::InvalidArgument,
+            "MISSING_CROSS_FADER",
+        ),
+        (
+            RegistryError::InvalidReference {
+                kind: EntityKind::ChannelStrip,
+                id: 3,
+            },
+            Code::FailedPrecondition,
+            "INVALID_REFERENCE",
+        ),
+        (
+            RegistryError::InvalidGroupReference {
+                name: String::from("drums"),
+            },
+            Code::FailedPrecondition,
+            "INVALID_REFERENCE",
+        ),
+        (
+            RegistryError::InUse {
+                kind: EntityKind::Plugin,
+                id: 3,
+                used_by_kind: EntityKind::ChannelStrip,
+                used_by_id: 4,
+            },
+            Code::FailedPrecondition,
+            "IN_USE",
+        ),
+        (
+            RegistryError::ReadOnly,
+            Code::FailedPrecondition,
+            "READ_ONLY",
+        ),
+        (
+            RegistryError::IdsExhausted {
+                kind: EntityKind::Input,
+            },
+            Code::ResourceExhausted,
+            "IDS_EXHAUSTED",
+        ),
+        (
+            RegistryError::ResumeTokenExpired,
+            Code::OutOfRange,
+            "RESUME_TOKEN_EXPIRED",
+        ),
+        (
+            RegistryError::PersistenceFailed {
+                reason: String::from("disk full"),
+            },
+            Code::Unavailable,
+            "PERSISTENCE_FAILED",
+        ),
+        (
+            RegistryError::SceneNotFound {
+                name: String::from("rehearsal"),
+            },
+            Code::NotFound,
+            "NOT_FOUND",
+        ),
+        (
+            RegistryError::GroupNotFound {
+                name: String::from("drums"),
+            },
+            Code::NotFound,
+            "NOT_FOUND",
+        ),
+        (
+            RegistryError::GroupExists {
+                name: String::from("drums"),
+            },
+            Code::AlreadyExists,
+            "ALREADY_EXISTS",
+        ),
+        (
+            RegistryError::RevisionMismatch {
+                kind: EntityKind::Input,
+                id: 3,
+                expected: 1,
+                actual: 2,
+            },
+            Code::Aborted,
+            "REVISION_MISMATCH",
+        ),
+        (
+            RegistryError::HistoryEmpty {
+                direction: HistoryDirection::Undo,
+            },
+            Code::FailedPrecondition,
+            "HISTORY_EMPTY",
+        ),
+    ];
+    for (error, code, reason) in cases {
+        let status = Status::from(error.clone());
+        assert_eq!(status.code(), code, "{error:?}");
+        assert_eq!(status.message(), error.to_string());
+        let info = error_info(&status);
+        assert_eq!(info.reason, reason, "{error:?}");
+        assert_eq!(info.domain, "pmx-registry");
+    }
+}
+
+#[test]
+fn failed_batch_is_reported_as_its_mutation_with_the_index() {
+    let status = Status::from(RegistryError::BatchFailed {
+        index: 2,
+        source: Box::new(RegistryError::InvalidReference {
+            kind: EntityKind::Output,
+            id: 7,
+        }),
+    });
+
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    let info = error_info(&status);
+    assert_eq!(info.reason, "INVALID_REFERENCE");
+    assert_eq!(info.metadata["mutation_index"], "2");
+    assert_eq!(info.metadata["kind"], EntityKind::Output.to_string());
+    assert_eq!(info.metadata["id"], "7");
+}
+
+#[test]
+fn persistence_failure_carries_the_reason() {
+    let status = Status::from(RegistryError::PersistenceFailed {
+        reason: String::from("disk full"),
+    });
+
+    assert_eq!(error_info(&status).metadata["reason"], "disk full");
+}
@@ -0,0 +1,541 @@
+//! Request checks that run before a handler touches the registry, so malformed
+//! requests are answered with `INVALID_ARGUMENT` instead of panicking the
+//! handler task. Checks return every violation they find and handlers turn
+//! them into a status with `invalid_argument`.
+
+use tonic::{Code, Status};
+
+use crate::changes::ResumeToken;
+use crate::pmx::channel_strip::{PmxChannelStrip, PmxChannelStripType};
+use crate::pmx::error_details::{BadRequest, FieldViolation};
+use crate::pmx::input::PmxInputType;
+use crate::pmx::looper::PmxLooper;
+use crate::pmx::output::PmxOutputType;
+use crate::pmx::output_stage::PmxOutputStage;
+use crate::pmx::{
+    batch_mutation, BatchMutation, ListInputsRequest, ListOutputsRequest, ListPluginsRequest,
+    MoveInputsToGroupRequest, PmxListOrder, RenameGroupRequest, UpdateInputGroupRequest,
+    UpdateInputPortAssignmentsRequest,
+};
+use crate::port_path;
+use crate::query::{InputFilter, Order, OutputFilter, Page, PluginFilter, PortState};
+use crate::registry::{MixerOutputType, Mutation, PipewirePorts};
+use crate::status;
+
+pub fn violation(field: &str, description: impl Into<String>) -> FieldViolation {
+    FieldViolation {
+        field: String::from(field),
+        description: description.into(),
+    }
+}
+
+/// `INVALID_ARGUMENT` carrying a `google.rpc.BadRequest` with one entry per
+/// offending field.
+pub fn invalid_argument(field_violations: Vec<FieldViolation>) -> Status {
+    let message = field_violations
+        .iter()
+        .map(|v| format!("{}: {}", v.field, v.description))
+        .collect::<Vec<_>>()
+        .join("; ");
+    status::with_details(
+        Code::InvalidArgument,
+        message,
+        vec![status::detail(
+            "BadRequest",
+            BadRequest { field_violations },
+        )],
+    )
+}
+
+/// Unwraps a message field proto3 leaves optional.
+pub fn required<T>(value: Option<T>, field: &str) -> Result<T, Vec<FieldViolation>> {
+    value.ok_or_else(|| vec![violation(field, "is required")])
+}
+
+pub fn output_type(value: i32, field: &str) -> Result<PmxOutputType, Vec<FieldViolation>> {
+    PmxOutputType::try_from(value).map_err(|_| {
+        vec![violation(
+            field,
+            format!("{value} isn't a known output type"),
+        )]
+    })
+}
+
+/// Checks the fields the registry's conversion relies on. `field` is the path
+/// of the channel strip within the request.
+pub fn channel_strip(
+    channel_strip: &PmxChannelStrip,
+    field: &str,
+) -> Result<(), Vec<FieldViolation>> {
+    let mut violations = Vec::new();
+    match PmxChannelStripType::try_from(channel_strip.channel_strip_type) {
+        Ok(PmxChannelStripType::CrossFaded) if channel_strip.cross_fader_plugin_id.is_none() => {
+            violations.push(violation(
+                &format!("{field}.cross_fader_plugin_id"),
+                "is required for CrossFaded channel strips",
+            ));
+        }
+        Ok(_) => {}
+        Err(_) => violations.push(violation(
+            &format!("{field}.channel_strip_type"),
+            format!(
+                "{} isn't a known channel strip type",
+                channel_strip.channel_strip_type
+            ),
+        )),
+    }
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+/// A port path in the form the registry stores it.
+fn port_path(path: &str, field: &str) -> Result<String, FieldViolation> {
+    port_path::normalize(path).map_err(|why| violation(field, format!("{path:?} {why}")))
+}
+
+/// Turns the port fields of the request into the ports the input type needs.
+pub fn input_ports(
+    request: &UpdateInputPortAssignmentsRequest,
+) -> Result<PipewirePorts, Vec<FieldViolation>> {
+    let port_path = |path: &Option<String>, field: &str, requirement: &str| match path {
+        Some(path) => port_path(path, field),
+        None => Err(violation(field, requirement)),
+    };
+    match PmxInputType::try_from(request.input_type) {
+        Ok(PmxInputType::None) => Ok(PipewirePorts::None),
+        Ok(PmxInputType::MonoInput) => {
+            port_path(&request.left_port_path, "left_port_path", "is required")
+                .map(PipewirePorts::Mono)
+                .map_err(|violation| vec![violation])
+        }
+        Ok(PmxInputType::StereoInput) => {
+            let requirement = "is required for STEREO_INPUT";
+            match (
+                port_path(&request.left_port_path, "left_port_path", requirement),
+                port_path(&request.right_port_path, "right_port_path", requirement),
+            ) {
+                (Ok(left), Ok(right)) => Ok(PipewirePorts::Stereo(left, right)),
+                (left, right) => Err([left.err(), right.err()].into_iter().flatten().collect()),
+            }
+        }
+        Err(_) => Err(vec![violation(
+            "input_type",
+            format!("{} isn't a known input type", request.input_type),
+        )]),
+    }
+}
+
+/// Outputs take whichever ports are set; a single one is mono.
+pub fn output_ports(
+    left: Option<String>,
+    right: Option<String>,
+) -> Result<PipewirePorts, Vec<FieldViolation>> {
+    let left = left
+        .map(|path| port_path(&path, "left_port_path"))
+        .transpose();
+    let right = right
+        .map(|path| port_path(&path, "right_port_path"))
+        .transpose();
+    match (left, right) {
+        (Ok(None), Ok(None)) => Ok(PipewirePorts::None),
+        (Ok(None), Ok(Some(right))) => Ok(PipewirePorts::Mono(right)),
+        (Ok(Some(left)), Ok(None)) => Ok(PipewirePorts::Mono(left)),
+        (Ok(Some(left)), Ok(Some(right))) => Ok(PipewirePorts::Stereo(left, right)),
+        (left, right) => Err([left.err(), right.err()].into_iter().flatten().collect()),
+    }
+}
+
+/// Checks every mutation of a batch and reports the violations of all of
+/// them, under `mutations[<index>]`.
+pub fn batch(mutations: Vec<BatchMutation>) -> Result<Vec<Mutation>, Vec<FieldViolation>> {
+    let mut validated = Vec::new();
+    let mut violations = Vec::new();
+    for (index, mutation) in mutations.into_iter().enumerate() {
+        match nested(batch_mutation(mutation), &format!("mutations[{index}]")) {
+            Ok(mutation) => validated.push(mutation),
+            Err(found) => violations.extend(found),
+        }
+    }
+    if violations.is_empty() {
+        Ok(validated)
+    } else {
+        Err(violations)
+    }
+}
+
+/// Prefixes the fields of the violations with the path of the message they
+/// were found in.
+fn nested<T>(result: Result<T, Vec<FieldViolation>>, path: &str) -> Result<T, Vec<FieldViolation>> {
+    result.map_err(|violations| {
+        violations
+            .into_iter()
+            .map(|v| violation(&format!("{path}.{}", v.field), v.description))
+            .collect()
+    })
+}
+
+fn batch_mutation(mutation: BatchMutation) -> Result<Mutation, Vec<FieldViolation>> {
+    use batch_mutation::Mutation as Requested;
+
+    Ok(match required(mutation.mutation, "mutation")? {
+        Requested::AddInput(request) => {
+            nested(name(&request.name), "add_input")?;
+            Mutation::AddInput {
+                name: request.name,
+                group_channel_strip_name: request.group_channel_strip_name,
+            }
+        }
+        Requested::UpdateInputName(request) => {
+            nested(name(&request.name), "update_input_name")?;
+            Mutation::UpdateInputName {
+                id: request.id,
+                name: request.name,
+                expected_revision: request.expected_revision,
+            }
+        }
+        Requested::UpdateInputPortAssignments(request) => Mutation::UpdateInputPorts {
+            id: request.id,
+            ports: nested(input_ports(&request), "update_input_port_assignments")?,
+            expected_revision: request.expected_revision,
+        },
+        Requested::UpdateInputGroup(request) => {
+            nested(input_group(&request), "update_input_group")?;
+            Mutation::UpdateInputGroup {
+                id: request.id,
+                group_channel_strip_name: request.group_channel_strip_name,
+                expected_revision: request.expected_revision,
+            }
+        }
+        Requested::RemoveInput(request) => Mutation::RemoveInput { id: request.id },
+        Requested::AddOutput(request) => {
+            nested(name(&request.name), "add_output")?;
+            Mutation::AddOutput {
+                name: request.name,
+                output_type: MixerOutputType::from(nested(
+                    output_type(request.output_type, "output_type"),
+                    "add_output",
+                )?),
+            }
+        }
+        Requested::UpdateOutputName(request) => {
+            nested(name(&request.name), "update_output_name")?;
+            Mutation::UpdateOutputName {
+                id: request.id,
+                name: request.name,
+                expected_revision: request.expected_revision,
+            }
+        }
+        Requested::UpdateOutputType(request) => Mutation::UpdateOutputType {
+            id: request.id,
+            output_type: MixerOutputType::from(nested(
+                output_type(request.output_type, "output_type"),
+                "update_output_type",
+            )?),
+            expected_revision: request.expected_revision,
+        },
+        Requested::UpdateOutputPortAssignments(request) => Mutation::UpdateOutputPorts {
+            id: request.id,
+            ports: nested(
+                output_ports(request.left_port_path, request.right_port_path),
+                "update_output_port_assignments",
+            )?,
+            expected_revision: request.expected_revision,
+        },
+        Requested::RemoveOutput(request) => Mutation::RemoveOutput { id: request.id },
+        Requested::RegisterPlugin(request) => {
+            let plugin = nested(required(request.plugin, "plugin"), "register_plugin")?;
+            nested(nested_name(&plugin.name, "plugin"), "register_plugin")?;
+            Mutation::RegisterPlugin {
+                plugin,
+                idempotency_token: request.idempotency_token,
+            }
+        }
+        Requested::UpdatePlugin(request) => {
+            let plugin = nested(required(request.plugin, "plugin"), "update_plugin")?;
+            nested(nested_name(&plugin.name, "plugin"), "update_plugin")?;
+            Mutation::UpdatePlugin {
+                plugin,
+                expected_revision: request.expected_revision,
+            }
+        }
+        Requested::UnregisterPlugin(request) => Mutation::UnregisterPlugin {
+            id: request.id,
+            cascade: request.cascade,
+        },
+        Requested::RegisterChannelStrip(request) => {
+            let channel_strip = nested(
+                required(request.channel_strip, "channel_strip"),
+                "register_channel_strip",
+            )?;
+            nested(
+                self::channel_strip(&channel_strip, "channel_strip"),
+                "register_channel_strip",
+            )?;
+            Mutation::RegisterChannelStrip {
+                channel_strip,
+                idempotency_token: request.idempotency_token,
+            }
+        }
+        Requested::UpdateChannelStrip(request) => {
+            let channel_strip = nested(
+                required(request.channel_strip, "channel_strip"),
+                "update_channel_strip",
+            )?;
+            nested(
+                self::channel_strip(&channel_strip, "channel_strip"),
+                "update_channel_strip",
+            )?;
+            Mutation::UpdateChannelStrip {
+                channel_strip,
+                expected_revision: request.expected_revision,
+            }
+        }
+        Requested::UnregisterChannelStrip(request) => Mutation::UnregisterChannelStrip {
+            id: request.id,
+            cascade: request.cascade,
+        },
+        Requested::RegisterLooper(request) => Mutation::RegisterLooper {
+            looper: PmxLooper::registered(&request),
+            idempotency_token: request.idempotency_token,
+        },
+        Requested::UpdateLooper(request) => {
+            let looper = nested(required(request.looper, "looper"), "update_looper")?;
+            nested(nested_name(&looper.name, "looper"), "update_looper")?;
+            Mutation::UpdateLooper {
+                looper,
+                expected_revision: request.expected_revision,
+            }
+        }
+        Requested::UnregisterLooper(request) => Mutation::UnregisterLooper { id: request.id },
+        Requested::RegisterOutputStage(request) => {
+            nested(name(&request.name), "register_output_stage")?;
+            Mutation::RegisterOutputStage {
+                output_stage: PmxOutputStage::registered(&request),
+                idempotency_token: request.idempotency_token,
+            }
+        }
+        Requested::UpdateOutputStage(request) => {
+            let output_stage = nested(
+                required(request.output_stage, "output_stage"),
+                "update_output_stage",
+            )?;
+            nested(
+                nested_name(&output_stage.name, "output_stage"),
+                "update_output_stage",
+            )?;
+            Mutation::UpdateOutputStage {
+                output_stage,
+                expected_revision: request.expected_revision,
+            }
+        }
+        Requested::UnregisterOutputStage(request) => {
+            Mutation::UnregisterOutputStage { id: request.id }
+        }
+    })
+}
+
+/// The `name` of an entity or scene.
+pub fn name(name: &str) -> Result<(), Vec<FieldViolation>> {
+    if name.trim().is_empty() {
+        Err(vec![violation("name", "must not be empty")])
+    } else {
+        Ok(())
+    }
+}
+
+/// The `name` of the entity at `field` of the request.
+pub fn nested_name(name: &str, field: &str) -> Result<(), Vec<FieldViolation>> {
+    nested(self::name(name), field)
+}
+
+pub fn input_group(request: &UpdateInputGroupRequest) -> Result<(), Vec<FieldViolation>> {
+    if request.group_channel_strip_name.trim().is_empty() {
+        Err(vec![violation(
+            "group_channel_strip_name",
+            "must not be empty",
+        )])
+    } else {
+        Ok(())
+    }
+}
+
+pub fn rename_group(request: &RenameGroupRequest) -> Result<(), Vec<FieldViolation>> {
+    let violations: Vec<FieldViolation> =
+        [("name", &request.name), ("new_name", &request.new_name)]
+            .into_iter()
+            .filter(|(_, name)| name.trim().is_empty())
+            .map(|(field, _)| violation(field, "must not be empty"))
+            .collect();
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+pub fn move_inputs_to_group(request: &MoveInputsToGroupRequest) -> Result<(), Vec<FieldViolation>> {
+    let mut violations = Vec::new();
+    if request.input_ids.is_empty() {
+        violations.push(violation("input_ids", "must not be empty"));
+    }
+    if request.group.trim().is_empty() {
+        violations.push(violation("group", "must not be empty"));
+    }
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+pub fn resume_token(token: Option<String>) -> Result<Option<ResumeToken>, Vec<FieldViolation>> {
+    token
+        .map(|token| {
+            token.parse().map_err(|_| {
+                vec![violation(
+                    "resume_token",
+                    format!("{token:?} isn't a resume token"),
+                )]
+            })
+        })
+        .transpose()
+}
+
+/// Ordering and paging shared by the list requests.
+fn page(
+    order_by: i32,
+    descending: bool,
+    page_size: u32,
+    page_token: &str,
+    violations: &mut Vec<FieldViolation>,
+) -> Page {
+    let order = match PmxListOrder::try_from(order_by) {
+        Ok(PmxListOrder::ById) => Order::Id,
+        Ok(PmxListOrder::ByName) => Order::Name,
+        Err(_) => {
+            violations.push(violation(
+                "order_by",
+                format!("{order_by} isn't a known order"),
+            ));
+            Order::Id
+        }
+    };
+    let after = match page_token {
+        "" => None,
+        token => match token.parse() {
+            Ok(token) => Some(token),
+            Err(_) => {
+                violations.push(violation(
+                    "page_token",
+                    format!("{token:?} isn't a page token"),
+                ));
+                None
+            }
+        },
+    };
+    Page {
+        order,
+        descending,
+        size: page_size as usize,
+        after,
+    }
+}
+
+fn query<F>(
+    filter: F,
+    page: Page,
+    violations: Vec<FieldViolation>,
+) -> Result<(F, Page), Vec<FieldViolation>> {
+    if violations.is_empty() {
+        Ok((filter, page))
+    } else {
+        Err(violations)
+    }
+}
+
+pub fn input_query(request: ListInputsRequest) -> Result<(InputFilter, Page), Vec<FieldViolation>> {
+    let mut violations = Vec::new();
+    let port_state = request
+        .port_state
+        .and_then(|value| match PmxInputType::try_from(value) {
+            Ok(PmxInputType::None) => Some(PortState::None),
+            Ok(PmxInputType::MonoInput) => Some(PortState::Mono),
+            Ok(PmxInputType::StereoInput) => Some(PortState::Stereo),
+            Err(_) => {
+                violations.push(violation(
+                    "port_state",
+                    format!("{value} isn't a known input type"),
+                ));
+                None
+            }
+        });
+    let page = page(
+        request.order_by,
+        request.descending,
+        request.page_size,
+        &request.page_token,
+        &mut violations,
+    );
+    let filter = InputFilter {
+        group_channel_strip_name: request.group_channel_strip_name,
+        port_state,
+        name_contains: request.name_contains,
+    };
+    query(filter, page, violations)
+}
+
+pub fn output_query(
+    request: ListOutputsRequest,
+) -> Result<(OutputFilter, Page), Vec<FieldViolation>> {
+    let mut violations = Vec::new();
+    let output_type =
+        request
+            .output_type
+            .and_then(|value| match self::output_type(value, "output_type") {
+                Ok(output_type) => Some(MixerOutputType::from(output_type)),
+                Err(mut violation) => {
+                    violations.append(&mut violation);
+                    None
+                }
+            });
+    let page = page(
+        request.order_by,
+        request.descending,
+        request.page_size,
+        &request.page_token,
+        &mut violations,
+    );
+    query(OutputFilter { output_type }, page, violations)
+}
+
+/// Ordering and paging of the list requests that don't filter.
+pub fn page_query(
+    order_by: i32,
+    descending: bool,
+    page_size: u32,
+    page_token: &str,
+) -> Result<Page, Vec<FieldViolation>> {
+    let mut violations = Vec::new();
+    let page = page(order_by, descending, page_size, page_token, &mut violations);
+    query((), page, violations).map(|((), page)| page)
+}
+
+pub fn plugin_query(
+    request: ListPluginsRequest,
+) -> Result<(PluginFilter, Page), Vec<FieldViolation>> {
+    let mut violations = Vec::new();
+    let page = page(
+        request.order_by,
+        request.descending,
+        request.page_size,
+        &request.page_token,
+        &mut violations,
+    );
+    let filter = PluginFilter {
+        plugin_uri: request.plugin_uri,
+    };
+    query(filter, page, violations)
+}